@@ -1,7 +1,9 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
 use std::path::PathBuf;
-use std::process::{Child, Command, Output};
+use std::process::Output;
 
 use libcnb::layer_lifecycle::execute_layer_lifecycle;
 use libcnb::{
@@ -9,33 +11,56 @@ use libcnb::{
     TestResults, TestStatus,
 };
 
-use crate::util::config::{read_package_directories, SFPackageBuildpackConfig, TestResultsFormat};
-use crate::{BuildLogger, Logger};
+use crate::client::{CliBackend, CliCommand, ProcessSfdxClient, SfdxClient};
+use crate::error::{find_buildpack_error, sfdx_command_error};
+use crate::util::changes::affected_test_classes;
+use crate::util::config::{
+    read_package_directories, CoverageThresholds, FlakyTests, SFPackageAppConfig,
+    SFPackageBuildpackConfig, TestLevel, TestSelection,
+};
+use crate::{BuildLogger, BuildpackError, Logger};
 
-use crate::layers::sfdx::SFDXLayerLifecycle;
+use crate::layers::sfdx::{plugin_env, runtime_config, SFDXLayerLifecycle};
+use crate::util::cache::has_layers;
 use crate::util::config;
+use crate::util::dependencies::{DependencyGraph, PlannedInstall};
 use crate::util::enc_file::{decrypt, EncFile};
+use crate::util::scratch_org::ScratchOrg;
+use crate::util::state::SfdxState;
+use crate::util::version::{version_key, version_matches};
 use anyhow::anyhow;
-use std::io::{BufRead, BufReader};
 use std::str::FromStr;
 
 pub(crate) fn require_sfdx(
     context: &BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
 ) -> anyhow::Result<()> {
-    if let Ok(output) = Command::new("sfdx").arg("--version").output() {
-        let str = String::from_utf8(output.stdout).unwrap();
-        assert!(str.contains("sfdx-cli/"));
-        return Ok(());
+    // A pinned runtime, or one with plugins, is installed even when some other CLI is on the PATH.
+    let runtime = runtime_config(context);
+    if !runtime.is_pinned() && runtime.plugins.is_empty() {
+        let backend = runtime.cli.unwrap_or_else(CliBackend::detect);
+        match backend.installed_version() {
+            Some(version) if backend == CliBackend::Sfdx || version.starts_with("2.") => {
+                return Ok(())
+            }
+            _ => {}
+        }
     }
 
-    execute_layer_lifecycle("sfdx", SFDXLayerLifecycle, context)?;
-    Ok(())
+    match execute_layer_lifecycle("sfdx", SFDXLayerLifecycle, context) {
+        Ok(_) => Ok(()),
+        // A runtime that fails verification must be reported as such, not as a missing sfdx.
+        Err(libcnb::Error::BuildpackError(e)) if find_buildpack_error(&e).is_some() => Err(e),
+        Err(e) => Err(BuildpackError::SfdxNotFound {
+            reason: e.to_string(),
+        }
+        .into()),
+    }
 }
 
 pub(crate) fn find_one_apex_test(app_dir: &PathBuf) -> bool {
-    if let Some(vec) = read_package_directories(&app_dir, true, true) {
+    if let Ok(vec) = read_package_directories(&app_dir, true, true) {
         for p in vec.iter() {
-            if find_one_file(p.as_path(), "IsTest") {
+            if find_one_file(app_dir.join(p).as_path(), "IsTest") {
                 return true;
             }
         }
@@ -43,17 +68,53 @@ pub(crate) fn find_one_apex_test(app_dir: &PathBuf) -> bool {
     false
 }
 
-pub(crate) fn reset_environment(
-    layers_dir: &PathBuf,
+/// Resolve the package versions that must be installed before the `roots` packages can be
+/// deployed, looking up versions on the Dev Hub when `packageAliases` does not name them.
+pub(crate) fn resolve_install_plan<C: SfdxClient>(
+    client: &C,
+    app_dir: &PathBuf,
+    hub_user: &str,
+    roots: &[String],
+    include_local: bool,
+) -> Result<Vec<PlannedInstall>, anyhow::Error> {
+    let graph = DependencyGraph::from_dir(app_dir)?;
+    graph.install_plan(roots, include_local, |package_id, version_number| {
+        let versions = client.list_package_versions(hub_user, package_id, false)?;
+        Ok(versions
+            .into_iter()
+            .filter(|v| version_matches(version_number, &v.version))
+            .max_by_key(|v| version_key(&v.version))
+            .map(|v| (v.subscriber_package_version_id, v.version)))
+    })
+}
+
+/// Install the dependencies of every package in the project into a scratch org, ahead of a
+/// source push, with the installation keys configured for them.
+pub(crate) fn install_dependencies<C: SfdxClient>(
+    client: &C,
     app_dir: &PathBuf,
     hub_user: &str,
     scratch_org_alias: &str,
+    wait_seconds: i32,
+    logger: &mut BuildLogger,
 ) -> Result<(), anyhow::Error> {
-    println!("---> Resetting environment");
-    match sfdx_delete_org(layers_dir, app_dir, hub_user, scratch_org_alias) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(e),
+    let config = SFPackageAppConfig::from_dir(app_dir);
+    let roots = DependencyGraph::from_dir(app_dir)?.package_names();
+    let plan = resolve_install_plan(client, app_dir, hub_user, &roots, false)?;
+    for install in plan {
+        logger.info(format!(
+            "---> installing dependency {} ({})",
+            install.package, install.version_id
+        ))?;
+        let output = client.install_package(
+            scratch_org_alias,
+            &install.version_id,
+            &config.installation_key(&install),
+            wait_seconds,
+        )?;
+        logger.output(format!("installing {}", install.package), output)?;
     }
+    Ok(())
 }
 
 //{
@@ -70,13 +131,6 @@ pub(crate) fn reset_environment(
 //     "This command will expose sensitive information that allows for subsequent activity using your current authenticated session.\nSharing this information is equivalent to logging someone in under the current credential, resulting in unintended access and escalation of privilege.\nFor additional information, please review the authorization section of the https://developer.salesforce.com/docs/atlas.en-us.234.0.sfdx_dev.meta/sfdx_dev/sfdx_dev_auth_web_flow.htm"
 //   ]
 // }
-#[derive(Serialize, Deserialize, Debug)]
-pub struct OrgDisplay {
-    status: i32,
-    result: Option<OrgDisplayResult>,
-    warnings: Vec<String>,
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct OrgDisplayResult {
@@ -107,12 +161,6 @@ pub struct OrgDisplayResult {
   }
 }
  */
-#[derive(Serialize, Deserialize, Debug)]
-pub struct PackageVersionCreate {
-    status: i32,
-    result: PackageVersionCreateResult,
-}
-
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct PackageVersionCreateResult {
@@ -163,19 +211,52 @@ pub struct PackageVersionCreateResult {
 }
  */
 #[derive(Serialize, Deserialize, Debug)]
-pub struct PackageVersion {
-    status: i32,
-    result: PackageVersionResult,
+#[serde(rename_all = "PascalCase")]
+pub struct PackageVersionResult {
+    pub package2_id: String,
+    pub subscriber_package_version_id: String,
+    pub name: String,
+    pub version: String,
+    pub ancestor_version: String,
+    pub is_released: bool,
+    pub code_coverage: Option<PackageVersionCodeCoverage>,
+    #[serde(default)]
+    pub has_passed_code_coverage_check: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageVersionCodeCoverage {
+    pub apex_code_coverage_percentage: f64,
 }
 
+/*
+{
+  "status": 0,
+  "result": [
+    {
+      "Package2Id": "0Ho3t000000XZNrCAO",
+      "Id": "05i3t000000XZeMAAW",
+      "SubscriberPackageVersionId": "04t3t000002zQrEAAU",
+      "Name": "Version One",
+      "Package2Name": "sf-package-test",
+      "Version": "1.0.0.6",
+      "MajorVersion": 1,
+      "MinorVersion": 0,
+      "PatchVersion": 0,
+      "BuildNumber": 6,
+      "IsReleased": true
+    }
+  ]
+}
+ */
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
-pub struct PackageVersionResult {
+pub struct PackageVersionListItem {
     pub package2_id: String,
     pub subscriber_package_version_id: String,
     pub name: String,
     pub version: String,
-    pub ancestor_version: String,
     pub is_released: bool,
 }
 
@@ -203,29 +284,31 @@ impl FromStr for OrgStatus {
 pub fn sfdx_display_org(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
+    backend: CliBackend,
     user: &str,
 ) -> Option<OrgDisplayResult> {
-    let mut cmd = sfdx(layers_dir);
-    match cmd
-        .args(vec!["force:org:display", "-u", user, "--json"])
-        .output()
-    {
-        Ok(output) => {
-            let s = std::str::from_utf8(output.stdout.as_slice()).unwrap();
-            let res: OrgDisplay = serde_json::from_str(s).unwrap();
-            res.result
-        }
-        Err(e) => {
-            panic!(
-                "failed to execute {:?} from {:?} due to {}",
-                cmd, app_dir, e
-            );
-        }
-    }
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .args(vec!["force:org:display", "-u", user, "--json"]);
+    // An org that cannot be displayed, for whatever reason, is not there to be used.  Its
+    // warnings are not logged, as sfdx warns that the access token is shown on every call.
+    let output = run(&mut cmd).ok()?;
+    parse_response(
+        backend,
+        "force:org:display",
+        &String::from_utf8_lossy(&output.stdout),
+        "",
+    )
+    .result
 }
 
+/// The status of the org of `user`, as a `ProcessSfdxClient` for the app checks it.
 pub fn sfdx_check_org(layers_dir: &PathBuf, app_dir: &PathBuf, user: &str) -> Option<OrgStatus> {
-    if let Some(org_info) = sfdx_display_org(layers_dir, app_dir, user) {
+    ProcessSfdxClient::new(layers_dir, app_dir, &BuildLogger::new(false, true)).check_org(user)
+}
+
+pub(crate) fn org_status(org_info: Option<OrgDisplayResult>) -> Option<OrgStatus> {
+    if let Some(org_info) = org_info {
         if let Some(status) = org_info.connected_status {
             Some(status)
         } else if let Some(status) = org_info.status {
@@ -238,29 +321,237 @@ pub fn sfdx_check_org(layers_dir: &PathBuf, app_dir: &PathBuf, user: &str) -> Op
     }
 }
 
-fn sfdx(layers_dir: &PathBuf) -> Command {
-    let sfdx_bin = layers_dir.join("sfdx").join("bin");
-    config::append_local_env_path(sfdx_bin);
+/// # sfdx Response
+/// The envelope every sfdx `--json` command answers with.  A command that succeeded has a
+/// `status` of 0 and a `result`, one that failed a `name`, a `message` and, for some errors,
+/// `data` on what went wrong.  Either may come with `warnings`.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SfdxResponse<R> {
+    pub status: i32,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub message: String,
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    #[serde(default)]
+    pub data: serde_json::Value,
+    pub result: Option<R>,
+}
+
+impl<R: DeserializeOwned> SfdxResponse<R> {
+    /// Read the response a command printed.  Output that is not a response, such as that of a
+    /// crashed sfdx, is read as a failure with stderr as its message.
+    pub fn parse(stdout: &str, stderr: &str) -> SfdxResponse<R> {
+        match serde_json::from_str(stdout) {
+            Ok(response) => response,
+            Err(e) => SfdxResponse {
+                status: 1,
+                name: "Error".to_string(),
+                message: match stderr.trim() {
+                    "" => format!("unexpected response: {}", e),
+                    stderr => stderr.to_string(),
+                },
+                exit_code: None,
+                warnings: vec![],
+                data: serde_json::Value::Null,
+                result: None,
+            },
+        }
+    }
+}
+
+impl<R> SfdxResponse<R> {
+    pub fn log_warnings(&self, command: &str, logger: &mut impl Logger) -> anyhow::Result<()> {
+        for warning in self.warnings.iter() {
+            logger.warning(format!("sfdx {}", command), warning)?;
+        }
+        Ok(())
+    }
+
+    /// The result of a command that succeeded, or the error of one that failed, see
+    /// `sfdx_command_error`.
+    pub fn into_result(self, command: &str, target: &str) -> Result<R, BuildpackError> {
+        let SfdxResponse {
+            status,
+            name,
+            message,
+            data,
+            result,
+            ..
+        } = self;
+        match result {
+            Some(result) if status == 0 => Ok(result),
+            None if status == 0 => Err(BuildpackError::SfdxCommandFailed {
+                command: command.to_string(),
+                name: "Error".to_string(),
+                message: "no result in response".to_string(),
+            }),
+            _ => Err(sfdx_command_error(command, target, name, message, data)),
+        }
+    }
+}
+
+/// The response of the sfdx `command` run by `backend`, with its result in the shape `sfdx`
+/// answers with, see `CliBackend::sfdx_result`.
+pub(crate) fn parse_response<R: DeserializeOwned>(
+    backend: CliBackend,
+    command: &str,
+    stdout: &str,
+    stderr: &str,
+) -> SfdxResponse<R> {
+    let response = SfdxResponse::<serde_json::Value>::parse(stdout, stderr);
+    let result = match response.result {
+        Some(result) => match serde_json::from_value(backend.sfdx_result(command, result)) {
+            Ok(result) => Some(result),
+            Err(e) => return SfdxResponse::parse("", &format!("unexpected result: {}", e)),
+        },
+        None => None,
+    };
+    SfdxResponse {
+        status: response.status,
+        name: response.name,
+        message: response.message,
+        exit_code: response.exit_code,
+        warnings: response.warnings,
+        data: response.data,
+        result,
+    }
+}
+
+/// The typed result of an sfdx `--json` command, logging any warnings it came with.
+pub(crate) fn read_response<R: DeserializeOwned>(
+    command: &str,
+    target: &str,
+    stdout: &str,
+    stderr: &str,
+    logger: &mut BuildLogger,
+) -> Result<R, anyhow::Error> {
+    read_result(SfdxResponse::parse(stdout, stderr), command, target, logger)
+}
+
+/// The result of a response, see `read_response`.
+fn read_result<R>(
+    response: SfdxResponse<R>,
+    command: &str,
+    target: &str,
+    logger: &mut BuildLogger,
+) -> Result<R, anyhow::Error> {
+    response.log_warnings(command, logger)?;
+    Ok(response.into_result(command, target)?)
+}
+
+/// Check that an sfdx `--json` command whose result is not needed succeeded.
+pub(crate) fn check_response(
+    command: &str,
+    target: &str,
+    stdout: &str,
+    stderr: &str,
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    read_response::<serde_json::Value>(command, target, stdout, stderr, logger)?;
+    Ok(())
+}
+
+/// The id of the named package among those in a `force:package:list` result.
+pub(crate) fn find_package_result(
+    packages: Vec<PackageListItem>,
+    package_name: &str,
+) -> FindPackageResult {
+    FindPackageResult {
+        package_id: packages
+            .into_iter()
+            .find(|p| p.name == package_name)
+            .map(|p| p.id)
+            .unwrap_or_default(),
+    }
+}
+
+/// An sfdx command, run by `sfdx` or `sf` as `backend` says.
+fn sfdx(layers_dir: &PathBuf, backend: CliBackend) -> CliCommand {
+    let sfdx_layer = layers_dir.join("sfdx");
+    config::prepend_local_env_path(sfdx_layer.join("bin"));
+
+    let mut cmd = CliCommand::new(backend);
+    if sfdx_layer.join("plugins").is_dir() {
+        for (key, value) in plugin_env(&sfdx_layer) {
+            cmd.env(key, value);
+        }
+    }
+    // Auth files and aliases are kept in the `sfdx-state` layer, once it is restored.
+    if has_layers(layers_dir) {
+        let home = SfdxState::home(layers_dir);
+        if home.is_dir() {
+            cmd.env("HOME", home);
+        }
+    }
+    cmd
+}
 
-    Command::new("sfdx")
+/// Run an sfdx command to completion, failing with `SfdxNotFound` if it cannot be started.
+fn run(cmd: &mut CliCommand) -> Result<Output, BuildpackError> {
+    cmd.output().map_err(|e| BuildpackError::SfdxNotFound {
+        reason: e.to_string(),
+    })
+}
+
+/// Run an sfdx `--json` command, see `read_response`.  Its errors are reported under the name
+/// the backend ran it by.
+fn run_json<R: DeserializeOwned>(
+    cmd: &mut CliCommand,
+    command: &str,
+    target: &str,
+    logger: &mut BuildLogger,
+) -> Result<R, anyhow::Error> {
+    let output = run(cmd)?;
+    let backend = cmd.backend();
+    let response = parse_response(
+        backend,
+        command,
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+    );
+    read_result(response, &backend.command_name(command), target, logger)
+}
+
+/// Run an sfdx `--json` command, see `check_response`.
+fn run_checked(
+    cmd: &mut CliCommand,
+    command: &str,
+    target: &str,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let output = run(cmd)?;
+    check_response(
+        &cmd.backend().command_name(command),
+        target,
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+        logger,
+    )?;
+    Ok(output)
 }
 
 pub fn sfdx_auth(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
+    backend: CliBackend,
     client_id: &str,
     key_path: &str,
     instance_url: &str,
     user_name: &str,
     alias: Option<String>,
     env: &PlatformEnv,
+    logger: &mut BuildLogger,
 ) -> Result<(), anyhow::Error> {
-    let mut logger = BuildLogger::new(true, true);
-
     // Exit early if we are already authenticated.
     if env.var("SFDX_AUTH_FORCE").is_ok() {
         logger.info("---> re-authenticating hub")?;
-    } else if let Some(OrgStatus::Connected) = sfdx_check_org(layers_dir, app_dir, user_name) {
+    } else if let Some(OrgStatus::Connected) =
+        org_status(sfdx_display_org(layers_dir, app_dir, backend, user_name))
+    {
         logger.info("---> hub already authenticated")?;
         return Ok(());
     }
@@ -279,7 +570,10 @@ pub fn sfdx_auth(
                 ))?;
                 Ok(Some(p))
             } else {
-                Err(anyhow!("Location given but no such file exists"))
+                Err(BuildpackError::AuthFailed {
+                    user: user_name.to_string(),
+                    reason: format!("SFDX_AUTH_KEYFILE {} does not exist", p.to_string_lossy()),
+                })
             }
         }
         Err(_) => match env.var("SFDX_AUTH_ENC_KEYFILE") {
@@ -292,7 +586,7 @@ pub fn sfdx_auth(
                     "---> found SFDX_AUTH_ENC_KEYFILE {}",
                     p.to_str().unwrap()
                 ))?;
-                let decrypted_file = decrypt_key(layers_dir, &mut logger, &p, env)?;
+                let decrypted_file = decrypt_key(layers_dir, logger, &p, env)?;
                 Ok(Some(decrypted_file))
             }
             Err(_) => {
@@ -306,7 +600,7 @@ pub fn sfdx_auth(
                         "---> found file with key_path app config value {}",
                         p.to_str().unwrap()
                     ))?;
-                    let decrypted_file = decrypt_key(layers_dir, &mut logger, &p, env)?;
+                    let decrypted_file = decrypt_key(layers_dir, logger, &p, env)?;
                     Ok(Some(decrypted_file))
                 } else {
                     Ok(None)
@@ -328,8 +622,10 @@ pub fn sfdx_auth(
                 ))?;
                 Ok(Some(p))
             } else {
-                // Location given but no such file exists
-                Err(anyhow!("Location given but no such file exists"))
+                Err(BuildpackError::AuthFailed {
+                    user: user_name.to_string(),
+                    reason: format!("SFDX_AUTH_URLFILE {} does not exist", p.to_string_lossy()),
+                })
             }
         }
         Err(_) => match env.var("SFDX_AUTH_URL") {
@@ -345,68 +641,55 @@ pub fn sfdx_auth(
 
     if let Some(key_file) = key_file {
         logger.info("---> authenticating hub with key")?;
-        let mut cmd = sfdx(layers_dir);
+        let mut cmd = sfdx(layers_dir, backend);
         cmd.current_dir(app_dir)
             .arg("auth:jwt:grant")
             .arg("--clientid")
             .arg(client_id)
             .arg("--jwtkeyfile")
-            .arg(key_file.canonicalize().unwrap())
+            .arg(key_file.canonicalize()?)
             .arg("--username")
             .arg(user_name)
             .arg("--instanceurl")
             .arg(instance_url)
-            .arg("--setdefaultdevhubusername");
+            .arg("--setdefaultdevhubusername")
+            .arg("--json");
         if let Some(s) = alias {
             logger.info(format!("---> using alias {}", &s))?;
             cmd.arg("--setalias").arg(s);
         }
-        match cmd.output() {
-            Ok(output) => {
-                logger.output("authenticated hub", output)?;
-                Ok(())
-            }
-            Err(e) => Err(anyhow::Error::new(e)),
-        }
+        let output = run_checked(&mut cmd, "auth", user_name, logger)?;
+        logger.output("authenticated hub", output)
     } else if let Some(url_file) = url_file {
         logger.info("---> authenticating hub with url")?;
-        let mut cmd = sfdx(layers_dir);
-        match cmd
-            .current_dir(app_dir)
+        let mut cmd = sfdx(layers_dir, backend);
+        cmd.current_dir(app_dir)
             .arg("auth:sfdxurl:store")
             .arg("-f")
-            .arg(url_file.canonicalize().unwrap())
+            .arg(url_file.canonicalize()?)
             .arg("--setdefaultdevhubusername")
-            .output()
-        {
-            Ok(output) => {
-                logger.output("authenticated hub", output)?;
-                Ok(())
-            }
-            Err(e) => Err(anyhow::Error::new(e)),
-        }
+            .arg("--json");
+        let output = run_checked(&mut cmd, "auth", user_name, logger)?;
+        logger.output("authenticated hub", output)
     } else if let Ok(access_token) = env.var("SFDX_ACCESS_TOKEN") {
         logger.info("---> authenticating hub with SFDX_ACCESS_TOKEN")?;
-        let mut cmd = sfdx(layers_dir);
-        match cmd
-            .current_dir(app_dir)
+        let mut cmd = sfdx(layers_dir, backend);
+        cmd.current_dir(app_dir)
             .env("SFDX_ACCESS_TOKEN", access_token)
             .arg("auth:accesstoken:store")
             .arg("--instanceurl")
             .arg(instance_url)
             .arg("--setdefaultdevhubusername")
             .arg("--noprompt")
-            .output()
-        {
-            Ok(output) => {
-                logger.output("authenticated hub", output)?;
-                Ok(())
-            }
-            Err(e) => Err(anyhow::Error::new(e)),
-        }
+            .arg("--json");
+        let output = run_checked(&mut cmd, "auth", user_name, logger)?;
+        logger.output("authenticated hub", output)
     } else {
-        Err(anyhow!("Unable to authenticate hub.  Hub should be pre-authenticated, \
-        or one of SFDX_AUTH_KEYFILE, SFDX_AUTH_ENC_KEYFILE, SFDX_AUTH_URL, SFDX_AUTH_URLFILE, or SFDX_ACCESS_TOKEN must be provided."))
+        Err(BuildpackError::AuthFailed {
+            user: user_name.to_string(),
+            reason: "the hub is not authenticated, and no credentials were provided".to_string(),
+        }
+        .into())
     }
 }
 
@@ -436,27 +719,28 @@ fn decrypt_key(
     }
 }
 
-pub fn sfdx_create_org_if_needed(
-    layers_dir: &PathBuf,
-    app_dir: &PathBuf,
+pub fn sfdx_create_org_if_needed<C: SfdxClient>(
+    client: &C,
     hub_user: &str,
     scratch_org_def_path: &str,
     scratch_org_duration: i32,
     scratch_org_alias: &str,
     logger: &mut BuildLogger,
 ) -> Result<bool, anyhow::Error> {
-    let created = match sfdx_check_org(layers_dir, app_dir, scratch_org_alias) {
+    let created = match client.check_org(scratch_org_alias) {
         Some(OrgStatus::Active) => false,
         _ => {
             logger.info("---> creating scratch org")?;
-            let output = sfdx_create_org(
-                layers_dir,
-                app_dir,
+            let (org, output) = ScratchOrg::create(
+                client,
                 hub_user,
                 scratch_org_def_path,
                 scratch_org_duration,
                 scratch_org_alias,
+                false,
             )?;
+            // Dev mode orgs outlive the build, to be reused by later builds.
+            org.keep();
             logger.output("creating environment", output)?;
             true
         }
@@ -464,6 +748,7 @@ pub fn sfdx_create_org_if_needed(
     Ok(created)
 }
 
+/// Create a scratch org with a `ProcessSfdxClient` for the app, see `SfdxClient::create_org`.
 pub fn sfdx_create_org(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
@@ -472,9 +757,30 @@ pub fn sfdx_create_org(
     scratch_org_duration: i32,
     scratch_org_alias: &str,
 ) -> Result<Output, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
+    ProcessSfdxClient::new(layers_dir, app_dir, &BuildLogger::new(false, true)).create_org(
+        hub_user,
+        scratch_org_def_path,
+        scratch_org_duration,
+        scratch_org_alias,
+        false,
+    )
+}
+
+pub(crate) fn sfdx_create_scratch_org(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    hub_user: &str,
+    scratch_org_def_path: &str,
+    scratch_org_duration: i32,
+    scratch_org_alias: &str,
+    no_namespace: bool,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
     cmd.current_dir(app_dir)
         .arg("force:org:create")
+        .arg("--json")
         .arg("-v")
         .arg(hub_user)
         .arg("-f")
@@ -483,103 +789,239 @@ pub fn sfdx_create_org(
         .arg(scratch_org_duration.to_string())
         .arg("-a")
         .arg(scratch_org_alias);
-    match cmd.output() {
-        Ok(output) => {
-            let status = output.status.code().unwrap();
-            if status != 0 {
-                let stderr = String::from_utf8(output.stderr.to_owned()).unwrap();
-                Err(anyhow::anyhow!(
-                    "failed to execute {:?} from {:?}:\n{}",
-                    cmd,
-                    app_dir,
-                    stderr
-                ))
-            } else {
-                Ok(output)
-            }
-        }
-        Err(e) => Err(anyhow::anyhow!(
-            "failed to execute {:?} from {:?} due to {}",
-            cmd,
-            app_dir,
-            e
-        )),
+    if no_namespace {
+        cmd.arg("-n");
     }
+    run_checked(&mut cmd, "force:org:create", hub_user, logger)
 }
 
+/// Delete a scratch org with a `ProcessSfdxClient` for the app, see `SfdxClient::delete_org`.
 pub fn sfdx_delete_org(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
     hub_user: &str,
     scratch_org_alias: &str,
 ) -> Result<Output, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
+    ProcessSfdxClient::new(layers_dir, app_dir, &BuildLogger::new(false, true))
+        .delete_org(hub_user, scratch_org_alias)
+}
+
+pub(crate) fn sfdx_delete_scratch_org(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    hub_user: &str,
+    scratch_org_alias: &str,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
     cmd.current_dir(app_dir)
         .arg("force:org:delete")
+        .arg("--json")
         .arg("-v")
         .arg(hub_user)
         .arg("-u")
         .arg(scratch_org_alias)
         .arg("-p");
-    match cmd.output() {
-        Ok(output) => {
-            let status = output.status.code().unwrap();
-            let stderr = String::from_utf8(output.stderr.to_owned()).unwrap();
-            if status != 0 {
-                return Err(anyhow::anyhow!(
-                    "failed to delete scratch org on {} named {}:\n {}",
-                    hub_user,
-                    scratch_org_alias,
-                    stderr
-                ));
-            }
-            Ok(output)
-        }
-        Err(e) => {
-            eprintln!(
-                "failed to delete scratch org on {} named {}",
-                hub_user, scratch_org_alias
-            );
-            Err(anyhow::anyhow!(e))
-        }
-    }
+    run_checked(&mut cmd, "force:org:delete", scratch_org_alias, logger)
 }
 
 pub fn sfdx_push_source(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
+    backend: CliBackend,
     scratch_org_alias: &str,
     wait_seconds: i32,
+    logger: &mut BuildLogger,
 ) -> Result<Output, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
-    let mut child = cmd
-        .current_dir(app_dir)
+    let mut cmd = sfdx(layers_dir, backend);
+    // Conflicts are only listed in the --json response.
+    cmd.current_dir(app_dir)
         .arg("force:source:push")
+        .arg("--json")
         .arg("-f")
         .arg("-u")
         .arg(scratch_org_alias)
         .arg("-w")
+        .arg(wait_seconds.to_string());
+    run_checked(&mut cmd, "force:source:push", scratch_org_alias, logger)
+}
+
+pub fn sfdx_install_package(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    scratch_org_alias: &str,
+    package_version_id: &str,
+    installation_key: &str,
+    wait_seconds: i32,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:package:install")
+        .arg("--json")
+        .arg("-u")
+        .arg(scratch_org_alias)
+        .arg("-p")
+        .arg(package_version_id)
+        .arg("-w")
+        .arg(wait_seconds.to_string())
+        .arg("-b")
         .arg(wait_seconds.to_string())
-        .spawn()
-        .expect("failed to execute command");
+        .arg("-r");
+    if !installation_key.is_empty() {
+        cmd.arg("-k").arg(installation_key);
+    }
+    run_checked(
+        &mut cmd,
+        "force:package:install",
+        package_version_id,
+        logger,
+    )
+}
 
-    output_stderr(&mut child);
+pub fn sfdx_assign_permset(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    scratch_org_alias: &str,
+    permset_name: &str,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:user:permset:assign")
+        .arg("--json")
+        .arg("-u")
+        .arg(scratch_org_alias)
+        .arg("-n")
+        .arg(permset_name);
+    run_checked(
+        &mut cmd,
+        "force:user:permset:assign",
+        scratch_org_alias,
+        logger,
+    )
+}
 
-    let output = child.wait_with_output().expect("failed to wait on child");
-    if output.status.success() {
-        Ok(output)
-    } else {
-        Err(anyhow::anyhow!(
-            "failed to push source to {}:\n Exited with {}",
-            scratch_org_alias,
-            output.status.code().unwrap()
-        ))
-    }
+pub fn sfdx_import_data(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    scratch_org_alias: &str,
+    plan_path: &str,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:data:tree:import")
+        .arg("--json")
+        .arg("-u")
+        .arg(scratch_org_alias)
+        .arg("-p")
+        .arg(plan_path);
+    run_checked(
+        &mut cmd,
+        "force:data:tree:import",
+        scratch_org_alias,
+        logger,
+    )
 }
 
-pub struct SfdxResponse<R> {
-    pub status: u8,
-    pub result: R,
+pub fn sfdx_execute_apex(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    scratch_org_alias: &str,
+    apex_path: &str,
+    logger: &mut BuildLogger,
+) -> Result<ExecuteAnonymousResult, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:apex:execute")
+        .arg("--json")
+        .arg("-u")
+        .arg(scratch_org_alias)
+        .arg("-f")
+        .arg(apex_path);
+    run_json(&mut cmd, "force:apex:execute", scratch_org_alias, logger)
+}
+
+pub fn sfdx_create_user(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    hub_user: &str,
+    scratch_org_alias: &str,
+    user_def_path: &str,
+    logger: &mut BuildLogger,
+) -> Result<Output, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:user:create")
+        .arg("--json")
+        .arg("-v")
+        .arg(hub_user)
+        .arg("-u")
+        .arg(scratch_org_alias)
+        .arg("-f")
+        .arg(user_def_path);
+    run_checked(&mut cmd, "force:user:create", scratch_org_alias, logger)
+}
+
+/// # Anonymous Apex Result
+/// The result of `force:apex:execute`.  sfdx reports Apex that fails to compile or throws as a
+/// result, so it must be checked.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecuteAnonymousResult {
+    pub compiled: bool,
+    pub success: bool,
+    #[serde(default)]
+    pub compile_problem: Option<String>,
+    #[serde(default)]
+    pub exception_message: Option<String>,
+    #[serde(default)]
+    pub exception_stack_trace: Option<String>,
+    #[serde(default)]
+    pub line: i32,
+    #[serde(default)]
+    pub logs: String,
+}
+
+impl ExecuteAnonymousResult {
+    /// Fail with `SfdxCommandFailed` if the Apex did not compile or threw.
+    pub fn check(&self, apex_path: &str) -> Result<(), BuildpackError> {
+        let (name, message) = if !self.compiled {
+            (
+                "CompileError",
+                format!(
+                    "{} line {}: {}",
+                    apex_path,
+                    self.line,
+                    self.compile_problem.as_deref().unwrap_or_default()
+                ),
+            )
+        } else if !self.success {
+            (
+                "ApexException",
+                format!(
+                    "{}: {}\n{}",
+                    apex_path,
+                    self.exception_message.as_deref().unwrap_or_default(),
+                    self.exception_stack_trace.as_deref().unwrap_or_default()
+                ),
+            )
+        } else {
+            return Ok(());
+        };
+        Err(BuildpackError::SfdxCommandFailed {
+            command: "force:apex:execute".to_string(),
+            name: name.to_string(),
+            message: message.trim_end().to_string(),
+        })
+    }
 }
 
 pub struct CreatePackageResult {
@@ -591,64 +1033,78 @@ pub struct FindPackageResult {
     pub package_id: String,
 }
 
+/*
+{
+  "status": 0,
+  "result": [
+    {
+      "Id": "0Ho3t000000XZNrCAO",
+      "SubscriberPackageId": "0333t000000Ve9BAAS",
+      "Name": "sf-package-test",
+      "Description": "SF Package Buildpack Package",
+      "NamespacePrefix": "mphtest",
+      "ContainerOptions": "Managed",
+      "ConvertedFromPackageId": "N/A",
+      "Alias": "sf-package-test",
+      "IsOrgDependent": "N/A",
+      "PackageErrorUsername": null,
+      "CreatedBy": "0053t000007ec1qAAA"
+    }
+  ]
+}
+ */
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PackageListItem {
+    pub id: String,
+    pub name: String,
+}
+
+/*
+{
+  "status": 0,
+  "result": {
+    "Id": "0Ho3t000000XZNrCAO"
+  }
+}
+ */
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct PackageCreateResult {
+    pub id: String,
+}
+
 pub fn sfdx_find_package(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
-    hub_user: &String,
-    package_name: &String,
-) -> Result<SfdxResponse<FindPackageResult>, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
-    let output = cmd
-        .current_dir(app_dir)
+    backend: CliBackend,
+    hub_user: &str,
+    package_name: &str,
+    logger: &mut BuildLogger,
+) -> Result<FindPackageResult, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
         .arg("force:package:list")
         .arg("--json")
         .arg("-v")
-        .arg(hub_user)
-        .output()
-        .expect("failed to execute command");
-
-    if output.status.success() {
-        let stdout = String::from_utf8(output.stdout)?;
-        let v: serde_json::Value = serde_json::from_str(stdout.as_str())?;
-        let package_values = v["result"].as_array().unwrap();
-        match package_values
-            .iter()
-            .find(|v| v["Name"].as_str().unwrap().eq(package_name))
-        {
-            Some(package) => Ok(SfdxResponse {
-                status: 0,
-                result: FindPackageResult {
-                    package_id: package["Id"].as_str().unwrap().to_string(),
-                },
-            }),
-            None => Ok(SfdxResponse {
-                status: 1,
-                result: FindPackageResult {
-                    package_id: "".to_string(),
-                },
-            }),
-        }
-    } else {
-        Err(anyhow::anyhow!(
-            "failed to create new package {}:\n Exited with {}",
-            package_name,
-            output.status.code().unwrap()
-        ))
-    }
+        .arg(hub_user);
+    let packages = run_json(&mut cmd, "force:package:list", hub_user, logger)?;
+    Ok(find_package_result(packages, package_name))
 }
 
 pub fn sfdx_create_package(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
-    hub_user: &String,
-    package_name: &String,
-    package_desc: &String,
-    package_type: &String,
-    package_root: &String,
-) -> Result<SfdxResponse<CreatePackageResult>, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
-    let mut child = cmd
-        .current_dir(app_dir)
+    backend: CliBackend,
+    hub_user: &str,
+    package_name: &str,
+    package_desc: &str,
+    package_type: &str,
+    package_root: &str,
+    logger: &mut BuildLogger,
+) -> Result<CreatePackageResult, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
         .arg("force:package:create")
         .arg("--json")
         .arg("-v")
@@ -660,54 +1116,31 @@ pub fn sfdx_create_package(
         .arg("-t")
         .arg(package_type)
         .arg("-r")
-        .arg(package_root)
-        .spawn()
-        .expect("failed to execute command");
-
-    output_stderr(&mut child);
-
-    let output = child.wait_with_output().expect("failed to wait on command");
-    if output.status.success() {
-        let stdout = String::from_utf8(output.stdout)?;
-        let v: serde_json::Value = serde_json::from_str(stdout.as_str())?;
-        let status = 0;
-        let result = CreatePackageResult {
-            created: true,
-            package_id: v["result"]["Id"].to_string(),
-        };
-        Ok(SfdxResponse { status, result })
-    } else {
-        Err(anyhow::anyhow!(
-            "failed to create new package {}:\n Exited with {}",
-            package_name,
-            output.status.code().unwrap()
-        ))
-    }
-}
-
-fn output_stderr(child: &mut Child) {
-    if let Some(stderr) = child.stderr.take() {
-        let reader = BufReader::new(stderr);
-        reader
-            .lines()
-            .filter_map(|line| line.ok())
-            .for_each(|line| eprintln!("{}", line));
-    }
+        .arg(package_root);
+    let created: PackageCreateResult =
+        run_json(&mut cmd, "force:package:create", package_name, logger)?;
+    Ok(CreatePackageResult {
+        created: true,
+        package_id: created.id,
+    })
 }
 
 pub fn sfdx_create_package_version(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
-    hub_user: &String,
-    package_id: &String,
-    org_def_path: &String,
-    version_name: &String,
-    version_number: &String,
-    installation_key: &String,
+    backend: CliBackend,
+    hub_user: &str,
+    package_id: &str,
+    org_def_path: &str,
+    version_name: &str,
+    version_number: &str,
+    installation_key: &str,
+    code_coverage: bool,
     wait_seconds: i32,
+    logger: &mut BuildLogger,
 ) -> Result<PackageVersionResult, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
-    cmd.current_dir(&app_dir)
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
         .arg("force:package:version:create")
         .arg("--json")
         .arg("-p")
@@ -722,81 +1155,312 @@ pub fn sfdx_create_package_version(
         .arg(version_number)
         .arg("-w")
         .arg(wait_seconds.to_string());
+    // Computing code coverage slows the build, and is only needed to promote the version.
+    if code_coverage {
+        cmd.arg("-c");
+    }
     if installation_key.is_empty() {
         cmd.arg("-x");
     } else {
         cmd.arg("-k").arg(installation_key);
     }
-    let output = cmd.output().expect("failed to execute command");
-
-    if output.status.success() {
-        let stdout = String::from_utf8(output.stdout)?;
-        let v: PackageVersionCreate = serde_json::from_str(stdout.as_str())?;
-        let id = v.result.subscriber_package_version_id; // 04t...
-        sfdx_fetch_package_version(layers_dir, app_dir, hub_user, &id)
-    } else {
-        let stdout = String::from_utf8(output.stdout)?;
-        let details: serde_json::Value = serde_json::from_str(stdout.as_str())?;
-        Err(anyhow::anyhow!(
-            "failed to create new package version of {}\n{}: {}",
-            package_id,
-            details["name"].as_str().unwrap(),
-            details["message"].as_str().unwrap(),
-        ))
-    }
+    let created: PackageVersionCreateResult =
+        run_json(&mut cmd, "force:package:version:create", package_id, logger)?;
+    // 04t...
+    sfdx_fetch_package_version(
+        layers_dir,
+        app_dir,
+        backend,
+        hub_user,
+        &created.subscriber_package_version_id,
+        logger,
+    )
 }
 
 pub fn sfdx_fetch_package_version(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
-    hub_user: &String,
-    id: &String,
+    backend: CliBackend,
+    hub_user: &str,
+    id: &str,
+    logger: &mut BuildLogger,
 ) -> Result<PackageVersionResult, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
-    cmd.current_dir(&app_dir)
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
         .arg("force:package:version:report")
         .arg("--json")
         .arg("-p")
         .arg(id)
         .arg("-v")
         .arg(hub_user);
-    let output = cmd.output().expect("failed to execute command");
+    run_json(&mut cmd, "force:package:version:report", id, logger)
+}
 
-    if output.status.success() {
-        let stdout = String::from_utf8(output.stdout)?;
-        let v: PackageVersion = serde_json::from_str(stdout.as_str())?;
-        Ok(v.result)
-    } else {
-        let stdout = String::from_utf8(output.stdout)?;
-        let details: serde_json::Value = serde_json::from_str(stdout.as_str())?;
-        Err(anyhow::anyhow!(
-            "failed to fetch package version {}\n{}: {}",
-            id,
-            details["name"].as_str().unwrap(),
-            details["message"].as_str().unwrap(),
-        ))
-    }
+/*
+{
+  "status": 0,
+  "result": {
+    "id": "04t3t000002zQrEAAU",
+    "success": true,
+    "errors": []
+  }
+}
+ */
+pub fn sfdx_promote_package_version(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    hub_user: &str,
+    id: &str,
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:package:version:promote")
+        .arg("--json")
+        .arg("-p")
+        .arg(id)
+        .arg("-v")
+        .arg(hub_user)
+        .arg("-n");
+    run_checked(&mut cmd, "force:package:version:promote", id, logger)?;
+    Ok(())
 }
 
-/* {
-    "status": 0,
-    "result": ApexTestRunResult
-} */
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ApexTestRun {
-    pub status: i32,
-    pub result: ApexTestRunResult,
+pub fn sfdx_list_package_versions(
+    layers_dir: &PathBuf,
+    app_dir: &PathBuf,
+    backend: CliBackend,
+    hub_user: &str,
+    package_id: &str,
+    released_only: bool,
+    logger: &mut BuildLogger,
+) -> Result<Vec<PackageVersionListItem>, anyhow::Error> {
+    let mut cmd = sfdx(layers_dir, backend);
+    cmd.current_dir(app_dir)
+        .arg("force:package:version:list")
+        .arg("--json")
+        .arg("-p")
+        .arg(package_id)
+        .arg("-v")
+        .arg(hub_user);
+    if released_only {
+        cmd.arg("--released");
+    }
+    run_json(&mut cmd, "force:package:version:list", package_id, logger)
 }
 
 /* {
     "summary": ApexTestSummary,
     "tests": [ ApexTestResult ],
+    "coverage": ApexCodeCoverage,
 } */
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ApexTestRunResult {
     pub summary: ApexTestSummary,
     pub tests: Vec<ApexTestResult>,
+    #[serde(default)]
+    pub coverage: Option<ApexCodeCoverage>,
+    /// Tests that failed, then passed when rerun.
+    #[serde(default)]
+    pub flaky: Vec<String>,
+    /// Tests that failed, and were ignored as quarantined.
+    #[serde(default)]
+    pub quarantined: Vec<String>,
+}
+
+impl ApexTestRunResult {
+    /// Take the results of `rerun`, a rerun of some of the tests, in place of their earlier
+    /// results.  Tests that now pass are flaky.
+    pub fn merge_rerun(&mut self, rerun: ApexTestRunResult) {
+        for test in rerun.tests {
+            if let Some(earlier) = self
+                .tests
+                .iter_mut()
+                .find(|t| t.full_name == test.full_name)
+            {
+                if matches!(earlier.outcome, ApexTestOutcome::Fail)
+                    && matches!(test.outcome, ApexTestOutcome::Pass)
+                {
+                    self.flaky.push(test.full_name.clone());
+                }
+                *earlier = test;
+            }
+        }
+        self.summarize();
+    }
+
+    /// Ignore the failures of quarantined tests.
+    pub fn quarantine(&mut self, flaky: &FlakyTests) {
+        for test in self.tests.iter_mut() {
+            if matches!(test.outcome, ApexTestOutcome::Fail)
+                && flaky.is_quarantined(&test.full_name)
+            {
+                test.outcome = ApexTestOutcome::Ignore;
+                self.quarantined.push(test.full_name.clone());
+            }
+        }
+        self.summarize();
+    }
+
+    /// Count the outcomes of the tests into the summary.
+    fn summarize(&mut self) {
+        let count = |outcome: fn(&ApexTestOutcome) -> bool| {
+            self.tests.iter().filter(|t| outcome(&t.outcome)).count() as i32
+        };
+        let passing = count(|o| matches!(o, ApexTestOutcome::Pass));
+        let failing = count(|o| matches!(o, ApexTestOutcome::Fail));
+        let skipped = count(|o| matches!(o, ApexTestOutcome::Ignore));
+        let summary = &mut self.summary;
+        summary.passing = passing;
+        summary.failing = failing;
+        summary.skipped = skipped;
+        summary.tests_ran = passing + failing + skipped;
+        if summary.tests_ran > 0 {
+            summary.pass_rate = format!("{}%", passing * 100 / summary.tests_ran);
+            summary.fail_rate = format!("{}%", failing * 100 / summary.tests_ran);
+        }
+        summary.outcome = if failing > 0 {
+            ApexTestSummaryOutcome::Failed
+        } else {
+            ApexTestSummaryOutcome::Passed
+        };
+    }
+
+    /// The failed tests, each with the message it failed with.
+    pub fn failures(&self) -> Vec<String> {
+        self.tests
+            .iter()
+            .filter(|test| matches!(test.outcome, ApexTestOutcome::Fail))
+            .map(|test| match &test.message {
+                Some(message) => format!("{}: {}", test.full_name, message),
+                None => test.full_name.clone(),
+            })
+            .collect()
+    }
+
+    /// Fail with `TestFailures` unless the run passed.
+    pub fn check(&self, org: &str) -> Result<(), BuildpackError> {
+        match self.summary.outcome {
+            ApexTestSummaryOutcome::Passed => Ok(()),
+            ApexTestSummaryOutcome::Failed => Err(BuildpackError::TestFailures {
+                org: org.to_string(),
+                failures: self.failures(),
+            }),
+        }
+    }
+
+    /// The percentage of lines covered by the run: counted from the classes covered when sfdx
+    /// reports them, otherwise as summarised by sfdx.
+    pub fn coverage_percent(&self) -> Option<f64> {
+        match &self.coverage {
+            Some(coverage) if !coverage.coverage.is_empty() => {
+                let total: u32 = coverage.coverage.iter().map(|c| c.total_lines).sum();
+                let covered: u32 = coverage.coverage.iter().map(|c| c.covered_lines()).sum();
+                if total == 0 {
+                    None
+                } else {
+                    Some(f64::from(covered) * 100.0 / f64::from(total))
+                }
+            }
+            _ => self
+                .summary
+                .test_run_coverage
+                .trim_end_matches('%')
+                .trim()
+                .parse()
+                .ok(),
+        }
+    }
+
+    /// Fail with `InsufficientCoverage` if the run, or any class, is covered less than the
+    /// `thresholds` require.
+    pub fn check_coverage(
+        &self,
+        org: &str,
+        thresholds: &CoverageThresholds,
+    ) -> Result<(), BuildpackError> {
+        let mut shortfalls = vec![];
+        if let Some(minimum) = thresholds.min_coverage {
+            match self.coverage_percent() {
+                Some(percent) if percent >= minimum => {}
+                Some(percent) => shortfalls.push(format!(
+                    "test run covers {:.1}% of lines, below {}%",
+                    percent, minimum
+                )),
+                None => shortfalls.push(format!(
+                    "no coverage was reported, {}% is required",
+                    minimum
+                )),
+            }
+        }
+        if let Some(minimum) = thresholds.min_class_coverage {
+            for class in self.coverage.iter().flat_map(|c| c.coverage.iter()) {
+                let percent = class.percent();
+                if percent < minimum {
+                    shortfalls.push(format!(
+                        "{} covers {:.1}% of lines, below {}%",
+                        class.name, percent, minimum
+                    ));
+                }
+            }
+        }
+        if shortfalls.is_empty() {
+            Ok(())
+        } else {
+            Err(BuildpackError::InsufficientCoverage {
+                org: org.to_string(),
+                shortfalls,
+            })
+        }
+    }
+}
+
+/* {
+    "coverage": [ ApexClassCoverage ],
+    "records": [ ... ],
+    "summary": { ... }
+} */
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ApexCodeCoverage {
+    #[serde(default)]
+    pub coverage: Vec<ApexClassCoverage>,
+}
+
+/* {
+    "id": "01p0t00000FKeSsAAL",
+    "name": "Test",
+    "totalLines": 4,
+    "lines": { "3": 1, "4": 1, "7": 1, "8": 0 },
+    "totalCovered": 3,
+    "coveredPercent": 75
+} */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ApexClassCoverage {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub total_lines: u32,
+    /// Hits by line number, for each line that can be covered.
+    #[serde(default)]
+    pub lines: BTreeMap<u32, u32>,
+}
+
+impl ApexClassCoverage {
+    pub fn covered_lines(&self) -> u32 {
+        self.lines.values().filter(|hits| **hits > 0).count() as u32
+    }
+
+    pub fn percent(&self) -> f64 {
+        if self.total_lines == 0 {
+            100.0
+        } else {
+            f64::from(self.covered_lines()) * 100.0 / f64::from(self.total_lines)
+        }
+    }
 }
 
 impl Into<TestOutcome> for ApexTestRunResult {
@@ -903,7 +1567,7 @@ pub enum ApexTestSummaryOutcome {
     Failed,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum ApexTestOutcome {
     Pass,
     Fail,
@@ -918,71 +1582,185 @@ pub enum ApexTestOutcome {
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "PascalCase")]
 pub struct ApexTestClass {
-    id: String,
-    name: String,
-    namespace_prefix: Option<String>,
+    pub id: String,
+    pub name: String,
+    pub namespace_prefix: Option<String>,
+}
+
+/// Run `tests`, rerunning those that fail up to `flaky.test_retries` times, then ignoring the
+/// failures of quarantined tests.
+pub(crate) fn test_apex_with_retries<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    org_alias: &str,
+    tests: &TestSelection,
+    wait_seconds: i32,
+    flaky: &FlakyTests,
+) -> Result<ApexTestRunResult, anyhow::Error> {
+    let mut result = client.test_apex(org_alias, tests, wait_seconds)?;
+    for attempt in 1..=flaky.test_retries {
+        let failed: Vec<String> = result
+            .tests
+            .iter()
+            .filter(|t| matches!(t.outcome, ApexTestOutcome::Fail))
+            .filter(|t| !flaky.is_quarantined(&t.full_name))
+            .map(|t| t.full_name.clone())
+            .collect();
+        if failed.is_empty() {
+            break;
+        }
+        logger.info(format!(
+            "---> rerunning {} failed apex tests, attempt {} of {}",
+            failed.len(),
+            attempt,
+            flaky.test_retries
+        ))?;
+        let rerun = TestSelection {
+            level: Some(TestLevel::RunSpecifiedTests),
+            methods: failed,
+            ..TestSelection::default()
+        };
+        result.merge_rerun(client.test_apex(org_alias, &rerun, wait_seconds)?);
+    }
+    result.quarantine(flaky);
+    for name in result.flaky.iter() {
+        logger.warning("Flaky test", format!("{} passed only when rerun", name))?;
+    }
+    for name in result.quarantined.iter() {
+        logger.warning(
+            "Quarantined test",
+            format!("{} failed and was ignored", name),
+        )?;
+    }
+    Ok(result)
+}
+
+/// The tests to run for a `selection`, with overrides from the environment applied and its level
+/// set, `default_level` if not otherwise chosen.  Tests affected by changes are resolved into the
+/// classes to run.  None when there are no tests to run: no tests are affected by the changes.
+pub(crate) fn resolve_tests(
+    app_dir: &PathBuf,
+    selection: &TestSelection,
+    default_level: TestLevel,
+) -> Result<Option<TestSelection>, anyhow::Error> {
+    let mut selection = selection.clone().with_env()?;
+    if let Some(git_ref) = selection.changed_since.take() {
+        let affected = affected_test_classes(app_dir, &git_ref)?;
+        if affected.is_empty() && !selection.is_specified() {
+            return Ok(None);
+        }
+        for class in affected {
+            if !selection.classes.contains(&class) {
+                selection.classes.push(class);
+            }
+        }
+        selection.level = Some(TestLevel::RunSpecifiedTests);
+    }
+    let level = selection.level_or(default_level);
+    if level == TestLevel::RunSpecifiedTests {
+        if !selection.is_specified() {
+            return Err(anyhow!(
+                "RunSpecifiedTests needs test classes, suites or methods to run"
+            ));
+        }
+        if !selection.suites.is_empty()
+            && (!selection.classes.is_empty() || !selection.methods.is_empty())
+        {
+            return Err(anyhow!(
+                "test suites cannot be run together with test classes or methods, including the classes selected by changed_since"
+            ));
+        }
+    }
+    selection.level = Some(level);
+    Ok(Some(selection))
+}
+
+/// The arguments selecting the tests of `force:apex:test:run`.
+pub(crate) fn test_selection_args(tests: &TestSelection) -> Vec<String> {
+    let level = tests.level_or(TestLevel::RunLocalTests);
+    let mut args = vec!["-l".to_string(), level.to_string()];
+    if level == TestLevel::RunSpecifiedTests {
+        // sfdx takes one of -n, -s and -t.  Whole classes may be named among the methods of -t.
+        if !tests.methods.is_empty() {
+            let names: Vec<&str> = tests
+                .classes
+                .iter()
+                .chain(tests.methods.iter())
+                .map(String::as_str)
+                .collect();
+            args.push("-t".to_string());
+            args.push(names.join(","));
+        } else if !tests.classes.is_empty() {
+            args.push("-n".to_string());
+            args.push(tests.classes.join(","));
+        } else if !tests.suites.is_empty() {
+            args.push("-s".to_string());
+            args.push(tests.suites.join(","));
+        }
+    }
+    args
 }
 
 pub fn sfdx_test_apex(
     layers_dir: &PathBuf,
     app_dir: &PathBuf,
+    backend: CliBackend,
     scratch_org_alias: &str,
-    results_path: Option<String>,
-    results_format: TestResultsFormat,
+    tests: &TestSelection,
     wait_seconds: i32,
+    logger: &mut BuildLogger,
 ) -> Result<ApexTestRunResult, anyhow::Error> {
-    let mut cmd = sfdx(layers_dir);
+    let mut cmd = sfdx(layers_dir, backend);
     cmd.current_dir(app_dir)
         .arg("force:apex:test:run")
         .arg("-u")
         .arg(scratch_org_alias)
-        .arg("-l")
-        .arg("RunLocalTests")
+        .args(test_selection_args(tests))
         .arg("-w")
         .arg(wait_seconds.to_string())
         .arg("--json")
-        .arg("-r")
-        .arg(results_format.to_string())
         .arg("-c")
         .arg("-v");
-    if let Some(path) = results_path {
-        cmd.arg("-d").arg(app_dir.join(path));
-    }
-
-    match cmd.output() {
-        Ok(output) => {
-            let status = output.status.code().unwrap();
-            let stdout = String::from_utf8(output.stdout)?;
-            let stderr = String::from_utf8(output.stderr)?;
-            // This is a Hack, to work around the platform bug that throws an error when no apex tests exist.
-            if status != 0
-                && !stderr
-                    .contains("Always provide a classes, suites, tests, or testLevel property")
-            {
-                return Err(anyhow::anyhow!(
-                    "failed to run apex tests on {}:\n {}",
-                    scratch_org_alias,
-                    stderr
-                ));
-            }
-            let result: ApexTestRun = serde_json::from_str(stdout.as_str())?;
-            Ok(result.result)
-        }
-        Err(e) => {
-            eprintln!("failed to run apex tests on {}", scratch_org_alias);
-            Err(anyhow::anyhow!(e))
-        }
+
+    let output = run(&mut cmd)?;
+    let response = parse_response(
+        backend,
+        "force:apex:test:run",
+        &String::from_utf8_lossy(&output.stdout),
+        &String::from_utf8_lossy(&output.stderr),
+    );
+    apex_test_run_result(
+        response,
+        &backend.command_name("force:apex:test:run"),
+        scratch_org_alias,
+        logger,
+    )
+}
+
+/// The run reported by `force:apex:test:run`, named `command` as run.  sfdx fails the command
+/// when tests fail, but still reports the run, which has failed rather than the command.
+pub(crate) fn apex_test_run_result(
+    mut response: SfdxResponse<ApexTestRunResult>,
+    command: &str,
+    scratch_org_alias: &str,
+    logger: &mut BuildLogger,
+) -> Result<ApexTestRunResult, anyhow::Error> {
+    if response.result.is_some() {
+        response.status = 0;
     }
+    read_result(response, command, scratch_org_alias, logger)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     use libcnb::data::buildpack::BuildpackToml;
     use libcnb::data::buildpack_plan::BuildpackPlan;
     use libcnb::{BuildContext, GenericPlatform, Platform};
     use std::path::PathBuf;
+    use std::process::Command;
     use std::{env, fs};
-    use tempfile::TempDir;
+    use tempfile::{tempdir, TempDir};
 
     fn _setup_context(tmp_dir: &TempDir) -> BuildContext<GenericPlatform, toml::value::Table> {
         let app_dir = tmp_dir.path().join("app");
@@ -1014,4 +1792,165 @@ mod tests {
             buildpack_descriptor,
         }
     }
+
+    fn recorded(name: &str) -> String {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/sfdx")
+            .join(name);
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn it_reads_sfdx_responses() {
+        let response: SfdxResponse<PackageVersionResult> =
+            SfdxResponse::parse(&recorded("package_version_report.json"), "");
+        let result = response
+            .into_result("force:package:version:report", "04t3t000002zQrEAAU")
+            .unwrap();
+        assert_eq!(result.version, "1.0.0.6");
+
+        let response: SfdxResponse<PackageVersionResult> =
+            SfdxResponse::parse(&recorded("error.json"), "");
+        assert!(matches!(
+            response.into_result("force:package:version:report", "04t3t000002zQrEAAU"),
+            Err(BuildpackError::SfdxCommandFailed { .. })
+        ));
+    }
+
+    /// An app whose `Greeter` class changed since its only commit, tested by `GreeterTest`.
+    fn changed_app() -> TempDir {
+        let tmp_dir = tempdir().unwrap();
+        let app_dir = tmp_dir.path();
+        let classes = app_dir.join("force-app/main/default/classes");
+        fs::create_dir_all(&classes).unwrap();
+        fs::write(
+            app_dir.join("sfdx-project.json"),
+            r#"{ "packageDirectories": [ { "path": "force-app", "default": true } ] }"#,
+        )
+        .unwrap();
+        fs::write(classes.join("Greeter.cls"), "public class Greeter {}").unwrap();
+        fs::write(
+            classes.join("GreeterTest.cls"),
+            "@IsTest class GreeterTest { @IsTest static void greets() { new Greeter(); } }",
+        )
+        .unwrap();
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(app_dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        git(&["init", "-q"]);
+        git(&["add", "."]);
+        git(&[
+            "-c",
+            "user.name=test",
+            "-c",
+            "user.email=test@example.com",
+            "commit",
+            "-q",
+            "-m",
+            "init",
+        ]);
+        fs::write(classes.join("Greeter.cls"), "public class Greeter { }").unwrap();
+        tmp_dir
+    }
+
+    #[test]
+    fn it_runs_changed_tests_with_named_tests() {
+        let app = changed_app();
+        let app_dir = app.path().to_path_buf();
+        let selection = TestSelection {
+            methods: vec!["OtherTest.works".to_string()],
+            changed_since: Some("HEAD".to_string()),
+            ..TestSelection::default()
+        };
+        let tests = resolve_tests(&app_dir, &selection, TestLevel::RunLocalTests)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            test_selection_args(&tests),
+            vec![
+                "-l",
+                "RunSpecifiedTests",
+                "-t",
+                "GreeterTest,OtherTest.works"
+            ]
+        );
+
+        let selection = TestSelection {
+            suites: vec!["Smoke".to_string()],
+            changed_since: Some("HEAD".to_string()),
+            ..TestSelection::default()
+        };
+        assert!(resolve_tests(&app_dir, &selection, TestLevel::RunLocalTests).is_err());
+    }
+
+    #[test]
+    fn it_reads_failed_test_runs() {
+        let response = SfdxResponse::parse(&recorded("apex_test_run_failed.json"), "");
+        assert_eq!(response.status, 100);
+        assert_eq!(response.exit_code, Some(100));
+        assert_eq!(response.warnings.len(), 1);
+
+        let result = apex_test_run_result(
+            response,
+            "force:apex:test:run",
+            "ci",
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap();
+        assert_eq!(result.summary.failing, 1);
+        assert!(matches!(
+            result.check("ci"),
+            Err(BuildpackError::TestFailures { .. })
+        ));
+    }
+
+    fn failed_test_run() -> ApexTestRunResult {
+        apex_test_run_result(
+            SfdxResponse::parse(&recorded("apex_test_run_failed.json"), ""),
+            "force:apex:test:run",
+            "ci",
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_merges_reruns() {
+        let mut result = failed_test_run();
+        let rerun = apex_test_run_result(
+            SfdxResponse::parse(&recorded("apex_test_run_rerun.json"), ""),
+            "force:apex:test:run",
+            "ci",
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap();
+        result.merge_rerun(rerun);
+
+        assert_eq!(result.flaky, vec!["TestTests.testGreeting"]);
+        assert_eq!(result.summary.passing, 2);
+        assert_eq!(result.summary.failing, 0);
+        assert!(result.check("ci").is_ok());
+    }
+
+    #[test]
+    fn it_ignores_quarantined_failures() {
+        let mut result = failed_test_run();
+        result.quarantine(&FlakyTests {
+            test_retries: 0,
+            quarantined_tests: vec!["TestTests.testGreeting".to_string()],
+        });
+
+        assert_eq!(result.quarantined, vec!["TestTests.testGreeting"]);
+        assert_eq!(result.summary.skipped, 1);
+        assert!(result.check("ci").is_ok());
+        match result.into() {
+            TestOutcome::Pass(results) => assert_eq!(results.ignored.len(), 1),
+            TestOutcome::Fail(_) => panic!("quarantined failures should not fail the run"),
+        }
+    }
 }
@@ -1,17 +1,28 @@
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use std::path::PathBuf;
 use std::process::Output;
 
 use libcnb::Error::BuildpackError;
 use libcnb::{get_lifecycle_mode, BuildContext, GenericPlatform, LifecycleMode, Platform};
 
-use crate::util::config::{SFPackageAppConfig, SFPackageBuildpackConfig};
+use crate::util::cache::has_layers;
+use crate::util::config::{
+    PackageConfig, SFPackageAppConfig, SFPackageBuildpackConfig, TestLevel, TestResultsFormat,
+    TestSelection,
+};
 use crate::util::logger::{BuildLogger, Logger};
-use crate::util::meta::{write_package_meta, write_package_version_meta};
+use crate::util::meta::{
+    write_package_meta, write_package_version_meta, PackageVersionStatus, SFPackageAppMeta,
+};
+use crate::util::pool::ci_scratch_org;
+use crate::util::project::SfdxProject;
+use crate::util::report::{write_report, TestReport};
+use crate::util::setup::run_setup;
+use crate::util::state::SfdxState;
+use crate::util::version::VersionNumber;
 use crate::{
-    find_one_apex_test, require_sfdx, reset_environment, sfdx_auth, sfdx_create_org,
-    sfdx_create_org_if_needed, sfdx_create_package, sfdx_create_package_version, sfdx_push_source,
-    sfdx_test_apex,
+    find_one_apex_test, install_dependencies, require_sfdx, resolve_tests,
+    sfdx_create_org_if_needed, ApexTestRunResult, ProcessSfdxClient, SfdxClient,
 };
 
 pub fn build(
@@ -20,29 +31,60 @@ pub fn build(
     let mut logger = BuildLogger::new(true, true);
 
     require_sfdx(&context)?;
+    let client = ProcessSfdxClient::new(&context.layers_dir, &context.app_dir, &logger);
 
     let mode = get_lifecycle_mode().unwrap_or(LifecycleMode::Dev);
 
+    // Without a layers directory the CLI keeps its state in the user's home, as it would anyway.
+    let state = if has_layers(&context.layers_dir) {
+        let hub = SFPackageAppConfig::from_dir(&context.app_dir).default;
+        let state = SfdxState::new(&context.layers_dir, &context.app_dir);
+        let reset = context.platform.env().var("SFDX_STATE_RESET").is_ok();
+        if state
+            .restore(&hub.hub_user, &hub.hub_instance_url, reset)
+            .map_err(BuildpackError)?
+        {
+            logger
+                .info("---> restored sfdx state")
+                .map_err(BuildpackError)?;
+        }
+        Some(state)
+    } else {
+        None
+    };
+
     // Lifecycle Mode => Dev, CI, Test, or Package
-    // Dev => namespaced scratch org created if needed, source push, test run if desired, setup automation if desired.  Use file watcher to trigger if desired.
+    // Dev => namespaced scratch org created if needed, source push, test run if desired, setup automation if desired.  `dev watch` pushes changes as they are made.
     // CI => namespaced scratch org created, source push, test run, scratch org deleted
     // Test (Install) => beta package version built, non-namespaced extended scratch org created, dependent packages installed, beta package version installed, setup automation if desired
     // Test (Upgrade) => beta package version built, non-namespaced extended scratch org created, dependent packages installed, ancestor released package version installed, setup automation if desired, beta package version installed
     // Package => beta package version promoted, published
     match mode {
-        LifecycleMode::Dev => dev_build(context, &mut logger).map_err(BuildpackError),
-        LifecycleMode::CI => ci_build(context, &mut logger).map_err(BuildpackError),
-        LifecycleMode::Package => package_build(context, &mut logger).map_err(BuildpackError),
+        LifecycleMode::Dev => {
+            if let Some(state) = &state {
+                state.restore_tracking().map_err(BuildpackError)?;
+            }
+            let result = dev_build(context, &client, &mut logger);
+            // Source tracking is kept even when the build fails, as it is what sfdx pushed.
+            if let Some(state) = &state {
+                state.save().map_err(BuildpackError)?;
+            }
+            result.map_err(BuildpackError)
+        }
+        LifecycleMode::CI => ci_build(context, &client, &mut logger).map_err(BuildpackError),
+        LifecycleMode::Package => {
+            package_build(context, &client, &mut logger).map_err(BuildpackError)
+        }
         _ => Ok(()),
     }
 }
 
-pub fn dev_build(
+pub fn dev_build<C: SfdxClient>(
     context: BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
     logger: &mut BuildLogger,
 ) -> Result<(), anyhow::Error> {
     let app_dir = &context.app_dir;
-    let layers_dir = &context.layers_dir;
 
     let config = SFPackageAppConfig::from_dir(app_dir).dev;
 
@@ -50,9 +92,7 @@ pub fn dev_build(
 
     logger.header("---> Creating environment")?;
 
-    sfdx_auth(
-        layers_dir,
-        &context.app_dir,
+    client.auth(
         &config.hub_client_id,
         &config.hub_key_path,
         &config.hub_instance_url,
@@ -61,11 +101,8 @@ pub fn dev_build(
         &context.platform.env(),
     )?;
 
-    let mut abort = false;
-
-    match sfdx_create_org_if_needed(
-        layers_dir,
-        app_dir,
+    let created = match sfdx_create_org_if_needed(
+        client,
         &config.hub_user,
         &config.org_def_path,
         config.org_duration_days,
@@ -75,72 +112,75 @@ pub fn dev_build(
         Ok(created) => {
             if created {
                 logger.info("---> created scratch org")?;
+                if let Err(e) = install_dependencies(
+                    client,
+                    app_dir,
+                    &config.hub_user,
+                    &config.org_alias,
+                    config.op_wait_seconds,
+                    logger,
+                ) {
+                    return Err(logger.fail("---> Failed installing dependencies", e));
+                }
             }
+            created
         }
-        Err(e) => {
-            logger.error("---> Failed creating environment", e)?;
-            abort = true;
+        Err(e) => return Err(logger.fail("---> Failed creating environment", e)),
+    };
+
+    logger.header("---> Preparing artifacts")?;
+    match push_source(client, logger, &config.org_alias, config.op_wait_seconds) {
+        Ok(output) => {
+            logger.output("---> Preparing artifacts", output)?;
         }
+        Err(e) => return Err(logger.fail("---> Preparing artifacts", e)),
     }
 
-    if !abort {
-        logger.header("---> Preparing artifacts")?;
-        match push_source(
-            layers_dir,
+    // Setup steps are not repeatable, so they only run on the org the build created.
+    if created && !config.setup.is_empty() {
+        logger.header("---> Setting up environment")?;
+        run_setup(
+            client,
             logger,
-            app_dir,
+            &config.hub_user,
             &config.org_alias,
-            config.op_wait_seconds,
-        ) {
-            Ok(output) => {
-                logger.output("---> Preparing artifacts", output)?;
-            }
-            Err(e) => {
-                logger.error("---> Preparing artifacts", e)?;
-                abort = true;
-            }
-        }
+            &config.setup,
+        )?;
     }
 
-    if !abort && config.run_tests {
+    if config.run_tests {
         logger.header("---> Running tests")?;
 
         if find_one_apex_test(app_dir) {
             logger.info("---> running apex tests")?;
-            match sfdx_test_apex(
-                layers_dir,
+            run_apex_tests(
+                client,
+                logger,
                 app_dir,
                 &config.org_alias,
-                config.test_results_path,
-                config.test_results_format,
-                240,
-            ) {
-                Ok(result) => {
-                    logger.info(format!("{:?}", result))?;
-                }
-                Err(e) => {
-                    logger.error("---> Running tests", e)?;
-                }
-            }
+                &config.tests,
+                &config.test_results_path,
+                &config.test_results_format,
+            )?;
         }
     }
 
     Ok(())
 }
 
-pub fn push_source(
-    layers_dir: &PathBuf,
+pub fn push_source<C: SfdxClient>(
+    client: &C,
     logger: &mut BuildLogger,
-    app_dir: &PathBuf,
     org_alias: &str,
     dev_op_wait_seconds: i32,
 ) -> Result<Output, Error> {
     logger.info("---> pushing source code")?;
-    sfdx_push_source(layers_dir, app_dir, org_alias, dev_op_wait_seconds)
+    client.push_source(org_alias, dev_op_wait_seconds)
 }
 
-pub fn ci_build(
+pub fn ci_build<C: SfdxClient>(
     context: BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
     logger: &mut BuildLogger,
 ) -> Result<(), anyhow::Error> {
     logger.header("---> CI Build")?;
@@ -148,152 +188,202 @@ pub fn ci_build(
     let config = SFPackageAppConfig::from_dir(app_dir).ci;
 
     logger.header("---> Creating environment")?;
-    sfdx_auth(
-        &context.layers_dir,
-        &context.app_dir,
+    client.auth(
         &config.hub_client_id,
         &config.hub_key_path,
         &config.hub_instance_url,
         &config.hub_user,
-        config.hub_alias,
+        config.hub_alias.clone(),
         &context.platform.env(),
     )?;
 
-    let mut abort = false;
-
-    logger.info("---> creating scratch org")?;
-    match sfdx_create_org(
-        &context.layers_dir,
+    // Deleted when dropped, should the build fail before it is deleted below.
+    let org = match ci_scratch_org(client, logger, &context.layers_dir, &config) {
+        Ok((org, output)) => {
+            if let Some(output) = output {
+                logger.output("---> Creating environment", output)?;
+            }
+            org
+        }
+        Err(e) => return Err(logger.fail("---> Failed creating environment", e)),
+    };
+    let org_alias = org.alias().to_string();
+    if let Err(e) = install_dependencies(
+        client,
         app_dir,
         &config.hub_user,
-        &config.org_def_path,
-        config.org_duration_days,
-        &config.org_alias,
+        &org_alias,
+        config.op_wait_seconds,
+        logger,
     ) {
+        return Err(logger.fail("---> Failed installing dependencies", e));
+    }
+
+    logger.header("---> Preparing artifacts")?;
+    match client.push_source(&org_alias, config.op_wait_seconds) {
         Ok(output) => {
-            logger.output("---> Creating environment", output)?;
-        }
-        Err(e) => {
-            abort = true;
-            logger.error("---> Failed creating environment", e)?;
+            logger.output("---> Preparing artifacts", output)?;
         }
+        Err(e) => return Err(logger.fail("---> Preparing artifacts", e)),
     }
 
-    if !abort {
-        logger.header("---> Preparing artifacts")?;
+    if !config.setup.is_empty() {
+        logger.header("---> Setting up environment")?;
+        run_setup(client, logger, &config.hub_user, &org_alias, &config.setup)?;
+    }
 
-        match sfdx_push_source(
-            &context.layers_dir,
+    logger.header("---> Running tests")?;
+    if find_one_apex_test(app_dir) {
+        logger.info("---> running apex tests")?;
+        let result = run_apex_tests(
+            client,
+            logger,
             app_dir,
-            &config.org_alias,
-            config.op_wait_seconds,
-        ) {
-            Ok(output) => {
-                logger.output("---> Preparing artifacts", output)?;
-            }
-            Err(e) => {
-                logger.error("---> Preparing artifacts", e)?;
-                abort = true;
+            &org_alias,
+            &config.tests,
+            &config.test_results_path,
+            &config.test_results_format,
+        )?;
+        if let Some(result) = result {
+            if let Err(e) = result.check_coverage(&org_alias, &config.coverage) {
+                return Err(logger.fail("---> Checking coverage", e.into()));
             }
         }
     }
 
-    if !abort {
-        logger.header("---> Running tests")?;
+    logger.header("---> Resetting environment")?;
+    logger.info("---> deleting scratch org")?;
+    org.delete()?;
+    Ok(())
+}
 
-        if find_one_apex_test(app_dir) {
-            logger.info("---> running apex tests")?;
-            match sfdx_test_apex(
-                &context.layers_dir,
-                app_dir,
-                &config.org_alias,
-                config.test_results_path,
-                config.test_results_format,
-                240,
-            ) {
-                Ok(result) => {
-                    logger.info(format!("{:?}", result))?;
-                }
-                Err(e) => {
-                    logger.error("---> Running tests", e)?;
-                }
+/// Run the selected apex tests, failing with `TestFailures` if any fail.  The report of the run
+/// is written to `results_path` whether or not they pass.  None if no tests were selected to run.
+fn run_apex_tests<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    app_dir: &PathBuf,
+    org_alias: &str,
+    tests: &TestSelection,
+    results_path: &Option<String>,
+    results_format: &TestResultsFormat,
+) -> Result<Option<ApexTestRunResult>, anyhow::Error> {
+    let tests = match resolve_tests(app_dir, tests, TestLevel::RunLocalTests) {
+        Ok(Some(tests)) => tests,
+        Ok(None) => {
+            logger.info("---> no apex tests affected by changes")?;
+            return Ok(None);
+        }
+        Err(e) => return Err(logger.fail("---> Running tests", e)),
+    };
+    match client.test_apex(org_alias, &tests, 240) {
+        Ok(result) => {
+            let report = TestReport::new(&result);
+            logger.info(report.human())?;
+            write_report(&report, app_dir, results_path, results_format, logger)?;
+            match result.check(org_alias) {
+                Ok(()) => Ok(Some(result)),
+                Err(e) => Err(logger.fail("---> Running tests", e.into())),
             }
         }
+        Err(e) => Err(logger.fail("---> Running tests", e)),
     }
-
-    logger.header("---> Resetting environment")?;
-
-    logger.info("---> deleting scratch org")?;
-    reset_environment(
-        &context.layers_dir,
-        app_dir,
-        &config.hub_user,
-        &config.org_alias,
-    )?;
-    Ok(())
 }
 
-pub fn package_build(
+pub fn package_build<C: SfdxClient>(
     context: BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
     logger: &mut BuildLogger,
 ) -> Result<(), anyhow::Error> {
-    let layers_dir = &context.layers_dir;
     let app_dir = &context.app_dir;
 
     logger.header("---> Package Build")?;
 
-    let config = SFPackageAppConfig::from_dir(app_dir).package;
+    let project = SfdxProject::from_dir(app_dir)?;
+    let configs = SFPackageAppConfig::from_dir(app_dir).package_configs(&project)?;
+    if configs.is_empty() {
+        return Err(anyhow!(
+            "no packages to build.  Configure [package] or [[packages]] in app.toml."
+        ));
+    }
 
-    sfdx_auth(
-        &context.layers_dir,
-        &context.app_dir,
+    for config in configs {
+        build_package_version(&context, client, &project, config, logger)?;
+    }
+    Ok(())
+}
+
+fn build_package_version<C: SfdxClient>(
+    context: &BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
+    project: &SfdxProject,
+    config: PackageConfig,
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    let app_dir = &context.app_dir;
+
+    client.auth(
         &config.hub_client_id,
         &config.hub_key_path,
         &config.hub_instance_url,
         &config.hub_user,
-        config.hub_alias,
+        config.hub_alias.clone(),
         &context.platform.env(),
     )?;
 
-    logger.header("---> Preparing artifacts")?;
-    let mut package_id = config.id;
+    logger.header(format!("---> Preparing artifacts for {}", config.name))?;
+    let mut package_id = config.id.clone();
+    if package_id.is_empty() {
+        if let Some(id) = project.package_aliases.get(&config.name) {
+            if id.starts_with("0Ho") {
+                package_id = id.clone();
+            }
+        }
+    }
     if package_id.is_empty() && config.create_if_needed {
-        let found_response =
-            crate::sfdx_find_package(layers_dir, app_dir, &config.hub_user, &config.name)?;
-        if found_response.result.package_id.is_empty() {
+        let found = client.find_package(&config.hub_user, &config.name)?;
+        if found.package_id.is_empty() {
             logger.info("---> creating package")?;
-            let response = sfdx_create_package(
-                layers_dir,
-                app_dir,
+            let created = client.create_package(
                 &config.hub_user,
                 &config.name,
                 &config.description,
                 &config.package_type,
                 &config.root,
             )?;
-            package_id = response.result.package_id;
+            package_id = created.package_id;
         } else {
-            package_id = found_response.result.package_id;
+            package_id = found.package_id;
         }
-        write_package_meta(
-            app_dir,
-            &package_id,
-            &config.name,
-            &config.hub_user,
-            &config.hub_instance_url,
-        )?;
     }
-
-    logger.info("---> building package version")?;
-    match sfdx_create_package_version(
-        layers_dir,
+    if package_id.is_empty() {
+        return Err(anyhow!(
+            "package {} has no id.  Set its id, add it to packageAliases or set create_if_needed.",
+            config.name
+        ));
+    }
+    write_package_meta(
         app_dir,
+        &package_id,
+        &config.name,
+        &config.hub_user,
+        &config.hub_instance_url,
+    )?;
+
+    let version_number = next_version_number(app_dir, &package_id, &config, logger)?;
+
+    logger.info(format!(
+        "---> building package version {} of {}",
+        version_number, config.name
+    ))?;
+    match client.create_package_version(
         &config.hub_user,
         &package_id,
         &config.org_def_path,
         &config.version_name,
-        &config.version_number,
+        &version_number.to_string(),
         &config.installation_key,
+        config.code_coverage,
         config.op_wait_seconds,
     ) {
         Ok(result) => {
@@ -306,9 +396,57 @@ pub fn package_build(
             )?;
             logger.info("---> new package version created")?;
         }
-        Err(e) => {
-            logger.error("---> Preparing artifacts", e)?;
-        }
+        Err(e) => return Err(logger.fail("---> Preparing artifacts", e)),
     }
     Ok(())
 }
+
+/// Work out the version number to build from the configured one and the versions recorded in
+/// `app-meta.toml`, applying the bump policy once the configured version has been released.  A
+/// bumped version number is written back to `sfdx-project.json`.
+fn next_version_number(
+    app_dir: &PathBuf,
+    package_id: &str,
+    config: &PackageConfig,
+    logger: &mut BuildLogger,
+) -> Result<VersionNumber, anyhow::Error> {
+    if config.version_number.is_empty() {
+        return Err(anyhow!(
+            "package {} has no version number.  Set versionNumber in sfdx-project.json.",
+            config.name
+        ));
+    }
+    let configured: VersionNumber = config.version_number.parse()?;
+
+    let app_meta = SFPackageAppMeta::from_dir(app_dir);
+    let mut built = vec![];
+    let mut released = vec![];
+    for package_version in app_meta.package_versions(package_id) {
+        if let Ok(number) = package_version.number.parse::<VersionNumber>() {
+            if let PackageVersionStatus::Published = package_version.status {
+                released.push(number.clone());
+            }
+            built.push(number);
+        }
+    }
+
+    let next = configured.next(config.bump, &built, &released)?;
+    if next != configured {
+        logger.info(format!(
+            "---> {} is released, bumping version number to {}",
+            configured, next
+        ))?;
+        let mut project = SfdxProject::from_dir(app_dir)?;
+        match project.package_directory_mut(&config.name) {
+            Some(dir) => {
+                dir.version_number = Some(next.to_string());
+                project.to_dir(app_dir)?;
+            }
+            None => logger.warning(
+                "---> Updating sfdx-project.json",
+                format!("no package directory for {}", config.name),
+            )?,
+        }
+    }
+    Ok(next)
+}
@@ -1,10 +1,13 @@
 use anyhow::{anyhow, Error};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{env, process};
 
+use crate::util::config::SFPackageAppConfig;
 use crate::util::enc_file;
 use crate::util::enc_file::EncFile;
-use crate::{BuildLogger, Logger};
+use crate::util::pool::OrgPool;
+use crate::{describe_error, BuildLogger, Logger, ProcessSfdxClient, SfdxClient};
 use clap::{App, AppSettings, Arg, ArgMatches, ArgSettings};
 use libcnb::data::buildpack_plan::{BuildpackPlan, Entry};
 use libcnb::{
@@ -64,7 +67,7 @@ pub fn execute(args: Vec<String>) -> Result<(), anyhow::Error> {
                 )
                 )
                 .subcommand(
-                    App::new("build")
+                    with_test_selection(App::new("build"))
                         .about("build the application")
                         .setting(AppSettings::ArgRequiredElseHelp)
                         .arg(
@@ -94,7 +97,7 @@ pub fn execute(args: Vec<String>) -> Result<(), anyhow::Error> {
                         )
                 )
                 .subcommand(
-                    App::new("test")
+                    with_test_selection(App::new("test"))
                         .about("test the application")
                         .setting(AppSettings::ArgRequiredElseHelp)
                         .arg(
@@ -153,6 +156,46 @@ pub fn execute(args: Vec<String>) -> Result<(), anyhow::Error> {
                     )
                 ),
         )
+        .subcommand(
+            App::new("dev")
+                .about("Dev mode commands")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("watch")
+                        .about("push source to the dev scratch org as it changes")
+                        .arg(
+                            Arg::new("source")
+                                .help("path to the application source directory, containing the app.toml file")
+                        )
+                        .arg(
+                            Arg::new("layers")
+                                .help("path to the directory the buildpack caches its layers in")
+                                .takes_value(true)
+                                .long("layers")
+                                .short('l')
+                        )
+                        .arg(
+                            Arg::new("run-tests")
+                                .help("run the Apex tests affected by each change, as run_tests in the dev section of app.toml does")
+                                .long("run-tests")
+                        )
+                        .arg(
+                            Arg::new("debounce")
+                                .help("milliseconds to wait for further changes before pushing")
+                                .takes_value(true)
+                                .default_value("500")
+                                .long("debounce")
+                        ),
+                ),
+        )
+        .subcommand(
+            App::new("pool")
+                .about("Scratch org pool commands, for the pool configured in the ci section of app.toml")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(pool_command("fill", "recycle the pool, then create scratch orgs until it is full"))
+                .subcommand(pool_command("recycle", "delete expiring scratch orgs and drop inactive ones from the pool"))
+                .subcommand(pool_command("list", "list the scratch orgs in the pool")),
+        )
         .subcommand(
             App::new("file")
                 .about("File-related utility commands")
@@ -219,6 +262,14 @@ pub fn execute(args: Vec<String>) -> Result<(), anyhow::Error> {
             Some(("decrypt", matches)) => decrypt(matches),
             _ => Ok(()),
         },
+        Some(("dev", matches)) => match matches.subcommand() {
+            Some(("watch", matches)) => watch(matches),
+            _ => Err(anyhow!("dev subcommand missing")),
+        },
+        Some(("pool", matches)) => match matches.subcommand() {
+            Some((action, matches)) => pool(action, matches),
+            _ => Err(anyhow!("pool subcommand missing")),
+        },
         Some(("pack", matches)) => {
             if let Some(mode) = matches.value_of("mode") {
                 match set_lifecycle_mode(mode) {
@@ -246,6 +297,93 @@ pub fn execute(args: Vec<String>) -> Result<(), anyhow::Error> {
     }
 }
 
+/// A pool subcommand, run against the app's layers so that it sees the pool the builds use.
+fn pool_command<'a>(name: &str, about: &'a str) -> App<'a> {
+    App::new(name)
+        .about(about)
+        .arg(
+            Arg::new("source")
+                .help("path to the application source directory, containing the app.toml file")
+        )
+        .arg(
+            Arg::new("platform")
+                .help("path to a directory containing platform provided configuration, for cloud native buildpacks.  Files containing environment variables should reside within an env subdirectory here.")
+                .takes_value(true)
+                .long("platform")
+                .short('p')
+        )
+        .arg(
+            Arg::new("env")
+                .help("path to a directory with files containing environment variable values")
+                .takes_value(true)
+                .long("env")
+                .short('e')
+        )
+        .arg(
+            Arg::new("layers")
+                .help("path to the directory the buildpack caches its layers in")
+                .takes_value(true)
+                .long("layers")
+                .short('l')
+        )
+}
+
+/// Options selecting the Apex tests to run, overriding the `tests` table of app.toml.
+fn with_test_selection(app: App) -> App {
+    app.arg(
+        Arg::new("test-level")
+            .help("test level to run: RunLocalTests, RunAllTestsInOrg or RunSpecifiedTests")
+            .takes_value(true)
+            .long("test-level"),
+    )
+    .arg(
+        Arg::new("test-class")
+            .help("name of an Apex test class to run, may be repeated")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .long("test-class"),
+    )
+    .arg(
+        Arg::new("test-suite")
+            .help("name of an Apex test suite to run, may be repeated")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .long("test-suite"),
+    )
+    .arg(
+        Arg::new("test-method")
+            .help("Apex test method to run as Class.method, may be repeated")
+            .takes_value(true)
+            .multiple_occurrences(true)
+            .long("test-method"),
+    )
+    .arg(
+        Arg::new("changed-since")
+            .help("run the Apex tests affected by files changed since this git ref")
+            .takes_value(true)
+            .long("changed-since"),
+    )
+}
+
+/// Pass the test selection options on to the buildpack through the environment.
+fn select_tests(args: &ArgMatches) {
+    if let Some(level) = args.value_of("test-level") {
+        env::set_var("SF_PACKAGE_TEST_LEVEL", level);
+    }
+    for (arg, var) in [
+        ("test-class", "SF_PACKAGE_TEST_CLASSES"),
+        ("test-suite", "SF_PACKAGE_TEST_SUITES"),
+        ("test-method", "SF_PACKAGE_TEST_METHODS"),
+    ] {
+        if let Some(values) = args.values_of(arg) {
+            env::set_var(var, values.collect::<Vec<&str>>().join(","));
+        }
+    }
+    if let Some(git_ref) = args.value_of("changed-since") {
+        env::set_var("SF_PACKAGE_TEST_CHANGED_SINCE", git_ref);
+    }
+}
+
 fn init(
     args: &ArgMatches,
     logger: &mut BuildLogger,
@@ -340,6 +478,7 @@ fn build(args: &ArgMatches) -> Result<(), anyhow::Error> {
     let mut logger = BuildLogger::new(true, false);
     logger.header("Pack Build")?;
 
+    select_tests(args);
     let (buildpack_dir, bp_toml, app_dir, platform_dir, layers_dir) = init(args, &mut logger);
 
     let context = BuildContext {
@@ -359,7 +498,7 @@ fn build(args: &ArgMatches) -> Result<(), anyhow::Error> {
 
     match crate::build(context) {
         Ok(()) => logger.info(format!("Built app in {}", &app_dir.to_str().unwrap())),
-        Err(e) => logger.error("Unexpected error during build", e),
+        Err(e) => logger.error("Unexpected error during build", describe_error(&e)),
     }
 }
 
@@ -367,6 +506,7 @@ fn test(args: &ArgMatches) -> Result<(), anyhow::Error> {
     let mut logger = BuildLogger::new(true, false);
     logger.header("Pack Test")?;
 
+    select_tests(args);
     let (buildpack_dir, bp_toml, app_dir, platform_dir, layers_dir) = init(args, &mut logger);
 
     let context = TestContext {
@@ -397,7 +537,7 @@ fn test(args: &ArgMatches) -> Result<(), anyhow::Error> {
                 ),
             ),
         },
-        Err(e) => logger.error("Unexpected error during test", e),
+        Err(e) => logger.error("Unexpected error during test", describe_error(&e)),
     }
 }
 
@@ -417,7 +557,86 @@ fn publish(args: &ArgMatches) -> Result<(), anyhow::Error> {
 
     match crate::publish(context) {
         Ok(_) => logger.info(format!("App in {} published", &app_dir.to_str().unwrap())),
-        Err(e) => logger.error("Unexpected error during publish", e),
+        Err(e) => logger.error("Unexpected error during publish", describe_error(&e)),
+    }
+}
+
+fn watch(args: &ArgMatches) -> Result<(), anyhow::Error> {
+    let mut logger = BuildLogger::new(true, false);
+
+    let current_dir = env::current_dir()?;
+    let app_dir = match args.value_of("source") {
+        None => current_dir
+            .ancestors()
+            .find(|a| a.join("app.toml").is_file())
+            .map(Path::to_path_buf)
+            .unwrap_or(current_dir),
+        Some(s) => PathBuf::from(s),
+    };
+    let layers_dir = args
+        .value_of("layers")
+        .map(PathBuf::from)
+        .unwrap_or_default();
+    let debounce = match args.value_of("debounce").unwrap_or("500").parse() {
+        Ok(ms) => Duration::from_millis(ms),
+        Err(e) => return logger.error("Invalid debounce", e),
+    };
+    let run_tests =
+        args.is_present("run-tests") || SFPackageAppConfig::from_dir(&app_dir).dev.run_tests;
+
+    let client = ProcessSfdxClient::new(&layers_dir, &app_dir, &logger);
+    match crate::dev_watch(&client, &mut logger, &app_dir, run_tests, debounce) {
+        Ok(()) => Ok(()),
+        Err(e) => logger.error("Unexpected error during watch", e),
+    }
+}
+
+fn pool(action: &str, args: &ArgMatches) -> Result<(), anyhow::Error> {
+    let mut logger = BuildLogger::new(true, false);
+    logger.header("Scratch Org Pool")?;
+
+    let (_buildpack_dir, _bp_toml, app_dir, platform_dir, layers_dir) = init(args, &mut logger);
+    let layers_dir = layers_dir.unwrap_or_default();
+    let config = SFPackageAppConfig::from_dir(&app_dir).ci;
+    let client = ProcessSfdxClient::new(&layers_dir, &app_dir, &logger);
+    let pool = OrgPool::new(&client, &config.hub_user, &config.pool, &layers_dir);
+
+    if action == "list" {
+        let orgs = match pool.orgs() {
+            Ok(orgs) => orgs,
+            Err(e) => return logger.error("Unexpected error reading the scratch org pool", e),
+        };
+        for org in orgs {
+            logger.info(format!(
+                "{} {} tag={} definition={} expires={}",
+                org.alias, org.username, org.tag, org.org_def_path, org.expires
+            ))?;
+        }
+        return Ok(());
+    }
+
+    let platform = GenericPlatform::from_path(platform_dir).unwrap();
+    let result = client
+        .auth(
+            &config.hub_client_id,
+            &config.hub_key_path,
+            &config.hub_instance_url,
+            &config.hub_user,
+            config.hub_alias.clone(),
+            &platform.env(),
+        )
+        .and_then(|()| match action {
+            "fill" => pool
+                .fill(&mut logger)
+                .map(|n| format!("{} scratch orgs created", n)),
+            "recycle" => pool
+                .recycle(&mut logger)
+                .map(|n| format!("{} scratch orgs removed", n)),
+            _ => Err(anyhow!("pool subcommand {} not supported", action)),
+        });
+    match result {
+        Ok(message) => logger.info(message),
+        Err(e) => logger.error("Unexpected error managing the scratch org pool", e),
     }
 }
 
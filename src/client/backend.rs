@@ -0,0 +1,765 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::util::config::SFPackageAppConfig;
+
+lazy_static! {
+    /// The backend of the CLI on the PATH, detected once, when the first client is created.
+    static ref INSTALLED: CliBackend = CliBackend::detect();
+}
+
+/// # CLI Backend
+/// The Salesforce CLI that runs the buildpack's commands: the legacy `sfdx`, or `sf` v2.
+/// Commands are written with the `force:*` topics and flags of `sfdx`, and translated for `sf`
+/// v2 when they run.  The `--json` results of `sf` v2 are mapped back to the shapes `sfdx`
+/// answers with, see `sfdx_result`, and errors are reported under the name of the command that
+/// ran.  The backend is set with `cli` in the `[runtime]` table of `app.toml`, otherwise `sf` is
+/// used when the installed CLI is v2.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CliBackend {
+    Sfdx,
+    Sf,
+}
+
+impl CliBackend {
+    /// The backend set for the app, or else that of the installed CLI.
+    pub fn for_app(app_dir: &Path) -> Self {
+        SFPackageAppConfig::from_dir(&app_dir.to_path_buf())
+            .runtime
+            .cli
+            .unwrap_or(*INSTALLED)
+    }
+
+    /// `sf` when `sf --version` reports v2, otherwise `sfdx`.  The `sf` bundled with `sfdx` v7
+    /// is v1, which does not have the v2 commands.
+    pub fn detect() -> Self {
+        match CliBackend::Sf.installed_version() {
+            Some(version) if version.starts_with("2.") => CliBackend::Sf,
+            _ => CliBackend::Sfdx,
+        }
+    }
+
+    pub fn program(&self) -> &'static str {
+        match self {
+            CliBackend::Sfdx => "sfdx",
+            CliBackend::Sf => "sf",
+        }
+    }
+
+    /// The backend of the CLI installed in `bin_dir`, detected as `detect` does for the PATH.
+    pub fn detect_in(bin_dir: &Path) -> Self {
+        match CliBackend::Sf.version_of(&bin_dir.join(CliBackend::Sf.program())) {
+            Some(version) if version.starts_with("2.") => CliBackend::Sf,
+            _ => CliBackend::Sfdx,
+        }
+    }
+
+    /// The version of the CLI on the PATH, from `--version`, None when it is not installed.
+    pub fn installed_version(&self) -> Option<String> {
+        self.version_of(Path::new(self.program()))
+    }
+
+    /// The version `program`, a CLI of this backend, reports with `--version`, None when it
+    /// cannot be run.
+    pub fn version_of(&self, program: &Path) -> Option<String> {
+        let prefix = match self {
+            CliBackend::Sfdx => "sfdx-cli/",
+            CliBackend::Sf => "@salesforce/cli/",
+        };
+        let output = Command::new(program).arg("--version").output().ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let version = stdout
+            .split_whitespace()
+            .find_map(|w| w.strip_prefix(prefix))?;
+        Some(version.to_string())
+    }
+
+    /// The arguments of an `sfdx` command, translated for this backend.  Commands `sf` has no
+    /// translation for are passed on as they are.
+    pub fn args(&self, args: &[OsString]) -> Vec<OsString> {
+        let (command, flags) = match (self, args.first().and_then(|a| a.to_str())) {
+            (CliBackend::Sf, Some(command)) => match sf_command(command) {
+                Some(translation) => translation,
+                None => return args.to_vec(),
+            },
+            _ => return args.to_vec(),
+        };
+
+        let mut translated: Vec<OsString> = command.iter().map(OsString::from).collect();
+        let mut rest = args[1..].iter();
+        while let Some(arg) = rest.next() {
+            match flags.iter().find(|(flag, _)| arg.to_str() == Some(*flag)) {
+                Some((_, Some(sf_flag))) => translated.push(OsString::from(sf_flag)),
+                // Flags `sf` does without are dropped, with their value.
+                Some((_, None)) => {
+                    rest.next();
+                }
+                None => translated.push(arg.clone()),
+            }
+        }
+        translated
+    }
+
+    /// The name of an `sfdx` command as this backend runs it: `org create scratch` for
+    /// `force:org:create` with `sf`.  Names without a translation, such as `auth`, are kept.
+    pub fn command_name(&self, command: &str) -> String {
+        match (self, sf_command(command)) {
+            (CliBackend::Sf, Some((sf, _))) => sf.join(" "),
+            _ => command.to_string(),
+        }
+    }
+
+    /// The `--json` result of an `sfdx` command run by this backend, in the shape `sfdx`
+    /// answers with.
+    pub fn sfdx_result(&self, command: &str, result: Value) -> Value {
+        match self {
+            CliBackend::Sfdx => result,
+            CliBackend::Sf => match command {
+                "force:org:display" => sf_org_display(result),
+                "force:package:version:create" => sf_package_version_create(result),
+                "force:package:version:report" => sf_package_version_report(result),
+                "force:package:version:list" => match result {
+                    Value::Array(versions) => {
+                        Value::Array(versions.into_iter().map(sf_package_version).collect())
+                    }
+                    result => result,
+                },
+                "force:apex:test:run" => sf_apex_test_run(result),
+                _ => result,
+            },
+        }
+    }
+}
+
+/// The `sfdx` command for a command name of either backend: `force:org:create` for
+/// `org create scratch`.
+pub(crate) fn sfdx_command_name(command: &str) -> &str {
+    SF_COMMANDS
+        .iter()
+        .find(|sfdx| sf_command(sfdx).map(|(sf, _)| sf.join(" ")).as_deref() == Some(command))
+        .copied()
+        .unwrap_or(command)
+}
+
+/// Set each of `keys` missing from an object, or null, to `default`.
+fn default_fields(object: &mut Map<String, Value>, keys: &[&str], default: Value) {
+    for key in keys {
+        match object.get(*key) {
+            Some(value) if !value.is_null() => {}
+            _ => {
+                object.insert(key.to_string(), default.clone());
+            }
+        }
+    }
+}
+
+/// `org display` leaves out the client id of orgs authorized by access token, and reports
+/// statuses `sfdx` does not have, such as `Unknown`, which are dropped.
+fn sf_org_display(mut result: Value) -> Value {
+    if let Some(org) = result.as_object_mut() {
+        default_fields(
+            org,
+            &["id", "accessToken", "instanceUrl", "username", "clientId"],
+            json!(""),
+        );
+        for key in ["connectedStatus", "status"] {
+            let known = matches!(
+                org.get(key).and_then(Value::as_str),
+                Some("Active" | "Deleted" | "Connected" | "Disconnected")
+            );
+            if !known {
+                org.remove(key);
+            }
+        }
+    }
+    result
+}
+
+/// `package version create` answers with the create request, which leaves out the ids of a
+/// version that has not been created.
+fn sf_package_version_create(mut result: Value) -> Value {
+    if let Some(request) = result.as_object_mut() {
+        default_fields(
+            request,
+            &[
+                "Id",
+                "Status",
+                "Package2Id",
+                "Package2VersionId",
+                "SubscriberPackageVersionId",
+            ],
+            json!(""),
+        );
+    }
+    result
+}
+
+/// `package version report` reports `CodeCoverage` as a bare percentage, and leaves out the
+/// version number and ancestor of some versions.
+fn sf_package_version_report(result: Value) -> Value {
+    let mut result = sf_package_version(result);
+    if let Some(version) = result.as_object_mut() {
+        if let Some(percent) = version.get("CodeCoverage").and_then(Value::as_f64) {
+            version.insert(
+                "CodeCoverage".to_string(),
+                json!({ "apexCodeCoveragePercentage": percent }),
+            );
+        }
+        default_fields(version, &["AncestorVersion"], json!("N/A"));
+        default_fields(version, &["HasPassedCodeCoverageCheck"], json!(false));
+    }
+    result
+}
+
+/// A package version, with its `Version` number put together from its parts when left out.
+fn sf_package_version(mut result: Value) -> Value {
+    if let Some(version) = result.as_object_mut() {
+        if version.get("Version").map_or(true, Value::is_null) {
+            let parts: Vec<String> = [
+                "MajorVersion",
+                "MinorVersion",
+                "PatchVersion",
+                "BuildNumber",
+            ]
+            .iter()
+            .map(|part| version.get(*part).and_then(Value::as_i64).unwrap_or(0))
+            .map(|part| part.to_string())
+            .collect();
+            version.insert("Version".to_string(), json!(parts.join(".")));
+        }
+        default_fields(version, &["Name"], json!(""));
+        default_fields(version, &["IsReleased"], json!(false));
+    }
+    result
+}
+
+/// `apex run test` reports times in milliseconds, leaves out the coverage summary when no
+/// coverage was collected and the job ids and full name of some tests, and has the `Skip` and
+/// `CompileFail` outcomes, read as `Ignore` and `Fail`.
+fn sf_apex_test_run(mut result: Value) -> Value {
+    if let Some(summary) = result.get_mut("summary").and_then(Value::as_object_mut) {
+        for key in ["testExecutionTime", "testTotalTime", "commandTime"] {
+            if let Some(ms) = summary.get(key).and_then(Value::as_i64) {
+                summary.insert(key.to_string(), json!(format!("{} ms", ms)));
+            }
+        }
+        default_fields(
+            summary,
+            &[
+                "passRate",
+                "failRate",
+                "testStartTime",
+                "testExecutionTime",
+                "testTotalTime",
+                "commandTime",
+                "hostname",
+                "orgId",
+                "username",
+                "testRunId",
+                "userId",
+                "testRunCoverage",
+                "orgWideCoverage",
+            ],
+            json!(""),
+        );
+        default_fields(
+            summary,
+            &["testsRan", "passing", "failing", "skipped"],
+            json!(0),
+        );
+    }
+    if let Some(tests) = result.get_mut("tests").and_then(Value::as_array_mut) {
+        for test in tests.iter_mut().filter_map(Value::as_object_mut) {
+            let outcome = match test.get("Outcome").and_then(Value::as_str) {
+                Some("Skip") => Some("Ignore"),
+                Some("CompileFail") => Some("Fail"),
+                _ => None,
+            };
+            if let Some(outcome) = outcome {
+                test.insert("Outcome".to_string(), json!(outcome));
+            }
+            if test.get("FullName").map_or(true, Value::is_null) {
+                let class = test
+                    .get("ApexClass")
+                    .and_then(|c| c.get("Name"))
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let method = test
+                    .get("MethodName")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default();
+                let full_name = format!("{}.{}", class, method);
+                test.insert("FullName".to_string(), json!(full_name));
+            }
+            default_fields(
+                test,
+                &["Id", "QueueItemId", "AsyncApexJobId", "MethodName"],
+                json!(""),
+            );
+            default_fields(test, &["RunTime"], json!(0));
+        }
+    }
+    result
+}
+
+impl fmt::Display for CliBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program())
+    }
+}
+
+impl FromStr for CliBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sfdx" => Ok(CliBackend::Sfdx),
+            "sf" => Ok(CliBackend::Sf),
+            _ => Err(anyhow!("unknown cli {}, expected sfdx or sf", s)),
+        }
+    }
+}
+
+type SfFlags = &'static [(&'static str, Option<&'static str>)];
+
+/// The `sfdx` commands `sf_command` translates.
+const SF_COMMANDS: &[&str] = &[
+    "force:org:display",
+    "auth:jwt:grant",
+    "auth:sfdxurl:store",
+    "auth:accesstoken:store",
+    "force:org:create",
+    "force:org:delete",
+    "force:source:push",
+    "force:package:install",
+    "force:user:permset:assign",
+    "force:data:tree:import",
+    "force:apex:execute",
+    "force:user:create",
+    "force:package:list",
+    "force:package:create",
+    "force:package:version:create",
+    "force:package:version:report",
+    "force:package:version:promote",
+    "force:package:version:list",
+    "force:apex:test:run",
+];
+
+/// The `sf` v2 command for an `sfdx` command, with the `sf` flag for each of its flags.
+fn sf_command(command: &str) -> Option<(&'static [&'static str], SfFlags)> {
+    let translation: (&'static [&'static str], SfFlags) = match command {
+        "force:org:display" => (&["org", "display"], &[("-u", Some("--target-org"))]),
+        "auth:jwt:grant" => (
+            &["org", "login", "jwt"],
+            &[
+                ("--clientid", Some("--client-id")),
+                ("--jwtkeyfile", Some("--jwt-key-file")),
+                ("--instanceurl", Some("--instance-url")),
+                ("--setdefaultdevhubusername", Some("--set-default-dev-hub")),
+                ("--setalias", Some("--alias")),
+            ],
+        ),
+        "auth:sfdxurl:store" => (
+            &["org", "login", "sfdx-url"],
+            &[
+                ("-f", Some("--sfdx-url-file")),
+                ("--setdefaultdevhubusername", Some("--set-default-dev-hub")),
+            ],
+        ),
+        "auth:accesstoken:store" => (
+            &["org", "login", "access-token"],
+            &[
+                ("--instanceurl", Some("--instance-url")),
+                ("--setdefaultdevhubusername", Some("--set-default-dev-hub")),
+                ("--noprompt", Some("--no-prompt")),
+            ],
+        ),
+        "force:org:create" => (
+            &["org", "create", "scratch"],
+            &[
+                ("-v", Some("--target-dev-hub")),
+                ("-f", Some("--definition-file")),
+                ("-d", Some("--duration-days")),
+                ("-a", Some("--alias")),
+                ("-n", Some("--no-namespace")),
+            ],
+        ),
+        "force:org:delete" => (
+            &["org", "delete", "scratch"],
+            &[
+                ("-v", None),
+                ("-u", Some("--target-org")),
+                ("-p", Some("--no-prompt")),
+            ],
+        ),
+        "force:source:push" => (
+            &["project", "deploy", "start"],
+            &[
+                ("-f", Some("--ignore-conflicts")),
+                ("-u", Some("--target-org")),
+                ("-w", Some("--wait")),
+            ],
+        ),
+        "force:package:install" => (
+            &["package", "install"],
+            &[
+                ("-u", Some("--target-org")),
+                ("-p", Some("--package")),
+                ("-w", Some("--wait")),
+                ("-b", Some("--publish-wait")),
+                ("-r", Some("--no-prompt")),
+                ("-k", Some("--installation-key")),
+            ],
+        ),
+        "force:user:permset:assign" => (
+            &["org", "assign", "permset"],
+            &[("-u", Some("--target-org")), ("-n", Some("--name"))],
+        ),
+        "force:data:tree:import" => (
+            &["data", "import", "tree"],
+            &[("-u", Some("--target-org")), ("-p", Some("--plan"))],
+        ),
+        "force:apex:execute" => (
+            &["apex", "run"],
+            &[("-u", Some("--target-org")), ("-f", Some("--file"))],
+        ),
+        "force:user:create" => (
+            &["org", "create", "user"],
+            &[
+                ("-v", Some("--target-dev-hub")),
+                ("-u", Some("--target-org")),
+                ("-f", Some("--definition-file")),
+            ],
+        ),
+        "force:package:list" => (&["package", "list"], &[("-v", Some("--target-dev-hub"))]),
+        "force:package:create" => (
+            &["package", "create"],
+            &[
+                ("-v", Some("--target-dev-hub")),
+                ("-n", Some("--name")),
+                ("-d", Some("--description")),
+                ("-t", Some("--package-type")),
+                ("-r", Some("--path")),
+            ],
+        ),
+        "force:package:version:create" => (
+            &["package", "version", "create"],
+            &[
+                ("-p", Some("--package")),
+                ("-v", Some("--target-dev-hub")),
+                ("-f", Some("--definition-file")),
+                ("-a", Some("--version-name")),
+                ("-n", Some("--version-number")),
+                ("-w", Some("--wait")),
+                ("-c", Some("--code-coverage")),
+                ("-x", Some("--installation-key-bypass")),
+                ("-k", Some("--installation-key")),
+            ],
+        ),
+        "force:package:version:report" => (
+            &["package", "version", "report"],
+            &[("-p", Some("--package")), ("-v", Some("--target-dev-hub"))],
+        ),
+        "force:package:version:promote" => (
+            &["package", "version", "promote"],
+            &[
+                ("-p", Some("--package")),
+                ("-v", Some("--target-dev-hub")),
+                ("-n", Some("--no-prompt")),
+            ],
+        ),
+        "force:package:version:list" => (
+            &["package", "version", "list"],
+            &[("-p", Some("--packages")), ("-v", Some("--target-dev-hub"))],
+        ),
+        "force:apex:test:run" => (
+            &["apex", "run", "test"],
+            &[
+                ("-u", Some("--target-org")),
+                ("-l", Some("--test-level")),
+                ("-n", Some("--class-names")),
+                ("-s", Some("--suite-names")),
+                ("-t", Some("--tests")),
+                ("-w", Some("--wait")),
+                ("-c", Some("--code-coverage")),
+                ("-v", Some("--detailed-coverage")),
+            ],
+        ),
+        _ => return None,
+    };
+    Some(translation)
+}
+
+/// An `sfdx` command, run by a `CliBackend`.  Built like a `Command`.
+#[derive(Debug)]
+pub struct CliCommand {
+    backend: CliBackend,
+    dir: Option<PathBuf>,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+}
+
+impl CliCommand {
+    pub fn new(backend: CliBackend) -> Self {
+        CliCommand {
+            backend,
+            dir: None,
+            args: vec![],
+            envs: vec![],
+        }
+    }
+
+    pub fn backend(&self) -> CliBackend {
+        self.backend
+    }
+
+    pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        self.dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Self {
+        self.envs
+            .push((key.as_ref().to_os_string(), val.as_ref().to_os_string()));
+        self
+    }
+
+    /// Run the command to completion with its backend.
+    pub fn output(&self) -> io::Result<Output> {
+        let dir = self.dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        Command::new(self.backend.program())
+            .current_dir(&dir)
+            .args(self.backend.args(&self.args))
+            .envs(self.envs.iter().map(|(k, v)| (k, v)))
+            .output()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn it_passes_sfdx_commands_through() {
+        let push = args(&["force:source:push", "--json", "-f", "-u", "dev"]);
+        assert_eq!(CliBackend::Sfdx.args(&push), push);
+        let unknown = args(&["force:mdapi:deploy", "-u", "dev"]);
+        assert_eq!(CliBackend::Sf.args(&unknown), unknown);
+    }
+
+    #[test]
+    fn it_translates_commands_for_sf() {
+        assert_eq!(
+            CliBackend::Sf.args(&args(&[
+                "force:org:create",
+                "--json",
+                "-v",
+                "hub",
+                "-f",
+                "config/project-scratch-def.json",
+                "-d",
+                "1",
+                "-a",
+                "ci",
+                "-n"
+            ])),
+            args(&[
+                "org",
+                "create",
+                "scratch",
+                "--json",
+                "--target-dev-hub",
+                "hub",
+                "--definition-file",
+                "config/project-scratch-def.json",
+                "--duration-days",
+                "1",
+                "--alias",
+                "ci",
+                "--no-namespace"
+            ])
+        );
+        assert_eq!(
+            CliBackend::Sf.args(&args(&[
+                "force:apex:test:run",
+                "-u",
+                "ci",
+                "-l",
+                "RunSpecifiedTests",
+                "-n",
+                "TestTests",
+                "--json",
+                "-c",
+                "-v"
+            ])),
+            args(&[
+                "apex",
+                "run",
+                "test",
+                "--target-org",
+                "ci",
+                "--test-level",
+                "RunSpecifiedTests",
+                "--class-names",
+                "TestTests",
+                "--json",
+                "--code-coverage",
+                "--detailed-coverage"
+            ])
+        );
+    }
+
+    #[test]
+    fn it_drops_flags_sf_does_without() {
+        assert_eq!(
+            CliBackend::Sf.args(&args(&[
+                "force:org:delete",
+                "--json",
+                "-v",
+                "hub",
+                "-u",
+                "ci",
+                "-p"
+            ])),
+            args(&[
+                "org",
+                "delete",
+                "scratch",
+                "--json",
+                "--target-org",
+                "ci",
+                "--no-prompt"
+            ])
+        );
+    }
+
+    #[test]
+    fn it_names_commands_as_run() {
+        assert_eq!(
+            CliBackend::Sfdx.command_name("force:org:create"),
+            "force:org:create"
+        );
+        assert_eq!(
+            CliBackend::Sf.command_name("force:org:create"),
+            "org create scratch"
+        );
+        assert_eq!(CliBackend::Sf.command_name("auth"), "auth");
+        assert_eq!(sfdx_command_name("org create scratch"), "force:org:create");
+        assert_eq!(sfdx_command_name("force:org:create"), "force:org:create");
+    }
+
+    #[test]
+    fn it_maps_sf_test_runs() {
+        let result = json!({
+            "summary": {
+                "outcome": "Failed",
+                "testsRan": 2,
+                "passing": 1,
+                "failing": 1,
+                "skipped": 0,
+                "testExecutionTime": 24,
+                "testRunId": "7070t00001vpgqx"
+            },
+            "tests": [
+                {
+                    "Id": "07M0t00000FfffwEAB",
+                    "MethodName": "testBehavior",
+                    "Outcome": "Pass",
+                    "ApexClass": {"Id": "01p0t00000FKeStAAL", "Name": "TestTests", "NamespacePrefix": null}
+                },
+                {
+                    "Id": "07M0t00000FfffxEAB",
+                    "MethodName": "testCompile",
+                    "Outcome": "CompileFail",
+                    "ApexClass": {"Id": "01p0t00000FKeStAAL", "Name": "TestTests", "NamespacePrefix": null},
+                    "FullName": "TestTests.testCompile"
+                }
+            ]
+        });
+        let run: crate::ApexTestRunResult = serde_json::from_value(
+            CliBackend::Sf.sfdx_result("force:apex:test:run", result.clone()),
+        )
+        .unwrap();
+        assert_eq!(run.summary.test_execution_time, "24 ms");
+        assert_eq!(run.tests[0].full_name, "TestTests.testBehavior");
+        assert_eq!(run.tests[1].outcome, crate::ApexTestOutcome::Fail);
+        assert_eq!(
+            CliBackend::Sfdx.sfdx_result("force:apex:test:run", result.clone()),
+            result
+        );
+    }
+
+    #[test]
+    fn it_maps_sf_package_version_reports() {
+        let report: crate::PackageVersionResult =
+            serde_json::from_value(CliBackend::Sf.sfdx_result(
+                "force:package:version:report",
+                json!({
+                    "Package2Id": "0Ho3t000000XZNrCAO",
+                    "SubscriberPackageVersionId": "04t3t000002zQqpAAE",
+                    "Name": "Version One",
+                    "MajorVersion": 1,
+                    "MinorVersion": 0,
+                    "PatchVersion": 0,
+                    "BuildNumber": 2,
+                    "IsReleased": false,
+                    "CodeCoverage": 82,
+                    "HasPassedCodeCoverageCheck": true
+                }),
+            ))
+            .unwrap();
+        assert_eq!(report.version, "1.0.0.2");
+        assert_eq!(report.ancestor_version, "N/A");
+        assert_eq!(
+            report.code_coverage.unwrap().apex_code_coverage_percentage,
+            82.0
+        );
+    }
+
+    #[test]
+    fn it_drops_sf_org_statuses_sfdx_does_not_have() {
+        let org: crate::OrgDisplayResult = serde_json::from_value(CliBackend::Sf.sfdx_result(
+            "force:org:display",
+            json!({
+                "id": "00D3t000004SKHiEAO",
+                "accessToken": "00D3t000004SKHi!AR",
+                "instanceUrl": "https://mphhub-dev-ed.my.salesforce.com",
+                "username": "mhoefer@mphhub.org",
+                "connectedStatus": "Unknown"
+            }),
+        ))
+        .unwrap();
+        assert!(org.connected_status.is_none());
+        assert_eq!(org.client_id, "");
+    }
+}
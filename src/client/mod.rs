@@ -0,0 +1,141 @@
+use std::process::Output;
+
+use libcnb::PlatformEnv;
+
+use crate::util::config::TestSelection;
+use crate::{
+    org_status, ApexTestRunResult, CreatePackageResult, ExecuteAnonymousResult, FindPackageResult,
+    OrgDisplayResult, OrgStatus, PackageVersionListItem, PackageVersionResult,
+};
+
+pub(crate) use backend::sfdx_command_name;
+pub use backend::{CliBackend, CliCommand};
+pub use process::ProcessSfdxClient;
+#[cfg(all(unix, any(test, feature = "scripted-sfdx")))]
+pub use scripted::{ScriptedSfdxClient, SfdxCall};
+
+mod backend;
+mod process;
+#[cfg(all(unix, any(test, feature = "scripted-sfdx")))]
+mod scripted;
+
+/// # sfdx Client
+/// Every sfdx command the buildpack runs.  `ProcessSfdxClient` runs the sfdx CLI, while
+/// `ScriptedSfdxClient` replays recorded `--json` responses so that builds can be exercised
+/// without a Dev Hub.
+pub trait SfdxClient: Clone + Send + 'static {
+    fn auth(
+        &self,
+        client_id: &str,
+        key_path: &str,
+        instance_url: &str,
+        user_name: &str,
+        alias: Option<String>,
+        env: &PlatformEnv,
+    ) -> Result<(), anyhow::Error>;
+
+    fn display_org(&self, user: &str) -> Option<OrgDisplayResult>;
+
+    fn check_org(&self, user: &str) -> Option<OrgStatus> {
+        org_status(self.display_org(user))
+    }
+
+    fn create_org(
+        &self,
+        hub_user: &str,
+        scratch_org_def_path: &str,
+        scratch_org_duration: i32,
+        scratch_org_alias: &str,
+        no_namespace: bool,
+    ) -> Result<Output, anyhow::Error>;
+
+    fn delete_org(&self, hub_user: &str, scratch_org_alias: &str) -> Result<Output, anyhow::Error>;
+
+    fn push_source(
+        &self,
+        scratch_org_alias: &str,
+        wait_seconds: i32,
+    ) -> Result<Output, anyhow::Error>;
+
+    fn install_package(
+        &self,
+        scratch_org_alias: &str,
+        package_version_id: &str,
+        installation_key: &str,
+        wait_seconds: i32,
+    ) -> Result<Output, anyhow::Error>;
+
+    fn assign_permset(
+        &self,
+        scratch_org_alias: &str,
+        permset_name: &str,
+    ) -> Result<Output, anyhow::Error>;
+
+    fn import_data(
+        &self,
+        scratch_org_alias: &str,
+        plan_path: &str,
+    ) -> Result<Output, anyhow::Error>;
+
+    fn execute_apex(
+        &self,
+        scratch_org_alias: &str,
+        apex_path: &str,
+    ) -> Result<ExecuteAnonymousResult, anyhow::Error>;
+
+    fn create_user(
+        &self,
+        hub_user: &str,
+        scratch_org_alias: &str,
+        user_def_path: &str,
+    ) -> Result<Output, anyhow::Error>;
+
+    fn find_package(
+        &self,
+        hub_user: &str,
+        package_name: &str,
+    ) -> Result<FindPackageResult, anyhow::Error>;
+
+    fn create_package(
+        &self,
+        hub_user: &str,
+        package_name: &str,
+        package_desc: &str,
+        package_type: &str,
+        package_root: &str,
+    ) -> Result<CreatePackageResult, anyhow::Error>;
+
+    fn create_package_version(
+        &self,
+        hub_user: &str,
+        package_id: &str,
+        org_def_path: &str,
+        version_name: &str,
+        version_number: &str,
+        installation_key: &str,
+        code_coverage: bool,
+        wait_seconds: i32,
+    ) -> Result<PackageVersionResult, anyhow::Error>;
+
+    fn fetch_package_version(
+        &self,
+        hub_user: &str,
+        id: &str,
+    ) -> Result<PackageVersionResult, anyhow::Error>;
+
+    fn promote_package_version(&self, hub_user: &str, id: &str) -> Result<(), anyhow::Error>;
+
+    fn list_package_versions(
+        &self,
+        hub_user: &str,
+        package_id: &str,
+        released_only: bool,
+    ) -> Result<Vec<PackageVersionListItem>, anyhow::Error>;
+
+    fn test_apex(
+        &self,
+        scratch_org_alias: &str,
+        tests: &TestSelection,
+        wait_seconds: i32,
+    ) -> Result<ApexTestRunResult, anyhow::Error>;
+}
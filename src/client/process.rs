@@ -0,0 +1,321 @@
+use std::path::PathBuf;
+use std::process::Output;
+use std::sync::{Arc, Mutex};
+
+use libcnb::PlatformEnv;
+
+use crate::client::{CliBackend, SfdxClient};
+use crate::util::config::{self, TestSelection};
+use crate::util::logger::BuildLogger;
+use crate::{
+    sfdx_assign_permset, sfdx_auth, sfdx_create_package, sfdx_create_package_version,
+    sfdx_create_scratch_org, sfdx_create_user, sfdx_delete_scratch_org, sfdx_display_org,
+    sfdx_execute_apex, sfdx_fetch_package_version, sfdx_find_package, sfdx_import_data,
+    sfdx_install_package, sfdx_list_package_versions, sfdx_promote_package_version,
+    sfdx_push_source, sfdx_test_apex, ApexTestRunResult, CreatePackageResult,
+    ExecuteAnonymousResult, FindPackageResult, OrgDisplayResult, PackageVersionListItem,
+    PackageVersionResult,
+};
+
+/// Runs the sfdx CLI, from the `sfdx` layer when the CLI is not already installed, with the
+/// app's `CliBackend`.  Warnings the CLI answers with are logged like the caller's `logger`.
+#[derive(Debug, Clone)]
+pub struct ProcessSfdxClient {
+    layers_dir: PathBuf,
+    app_dir: PathBuf,
+    backend: CliBackend,
+    logger: Arc<Mutex<BuildLogger>>,
+}
+
+impl ProcessSfdxClient {
+    pub fn new(layers_dir: &PathBuf, app_dir: &PathBuf, logger: &BuildLogger) -> Self {
+        // The CLI installed into the `sfdx` layer is the one to detect the backend of.
+        config::prepend_local_env_path(layers_dir.join("sfdx").join("bin"));
+        ProcessSfdxClient {
+            layers_dir: layers_dir.clone(),
+            app_dir: app_dir.clone(),
+            backend: CliBackend::for_app(app_dir),
+            logger: Arc::new(Mutex::new(logger.clone())),
+        }
+    }
+}
+
+impl SfdxClient for ProcessSfdxClient {
+    fn auth(
+        &self,
+        client_id: &str,
+        key_path: &str,
+        instance_url: &str,
+        user_name: &str,
+        alias: Option<String>,
+        env: &PlatformEnv,
+    ) -> Result<(), anyhow::Error> {
+        sfdx_auth(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            client_id,
+            key_path,
+            instance_url,
+            user_name,
+            alias,
+            env,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn display_org(&self, user: &str) -> Option<OrgDisplayResult> {
+        sfdx_display_org(&self.layers_dir, &self.app_dir, self.backend, user)
+    }
+
+    fn create_org(
+        &self,
+        hub_user: &str,
+        scratch_org_def_path: &str,
+        scratch_org_duration: i32,
+        scratch_org_alias: &str,
+        no_namespace: bool,
+    ) -> Result<Output, anyhow::Error> {
+        sfdx_create_scratch_org(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            scratch_org_def_path,
+            scratch_org_duration,
+            scratch_org_alias,
+            no_namespace,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn delete_org(&self, hub_user: &str, scratch_org_alias: &str) -> Result<Output, anyhow::Error> {
+        sfdx_delete_scratch_org(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            scratch_org_alias,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn push_source(
+        &self,
+        scratch_org_alias: &str,
+        wait_seconds: i32,
+    ) -> Result<Output, anyhow::Error> {
+        sfdx_push_source(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            scratch_org_alias,
+            wait_seconds,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn install_package(
+        &self,
+        scratch_org_alias: &str,
+        package_version_id: &str,
+        installation_key: &str,
+        wait_seconds: i32,
+    ) -> Result<Output, anyhow::Error> {
+        sfdx_install_package(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            scratch_org_alias,
+            package_version_id,
+            installation_key,
+            wait_seconds,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn assign_permset(
+        &self,
+        scratch_org_alias: &str,
+        permset_name: &str,
+    ) -> Result<Output, anyhow::Error> {
+        sfdx_assign_permset(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            scratch_org_alias,
+            permset_name,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn import_data(
+        &self,
+        scratch_org_alias: &str,
+        plan_path: &str,
+    ) -> Result<Output, anyhow::Error> {
+        sfdx_import_data(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            scratch_org_alias,
+            plan_path,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn execute_apex(
+        &self,
+        scratch_org_alias: &str,
+        apex_path: &str,
+    ) -> Result<ExecuteAnonymousResult, anyhow::Error> {
+        sfdx_execute_apex(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            scratch_org_alias,
+            apex_path,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn create_user(
+        &self,
+        hub_user: &str,
+        scratch_org_alias: &str,
+        user_def_path: &str,
+    ) -> Result<Output, anyhow::Error> {
+        sfdx_create_user(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            scratch_org_alias,
+            user_def_path,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn find_package(
+        &self,
+        hub_user: &str,
+        package_name: &str,
+    ) -> Result<FindPackageResult, anyhow::Error> {
+        sfdx_find_package(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            package_name,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn create_package(
+        &self,
+        hub_user: &str,
+        package_name: &str,
+        package_desc: &str,
+        package_type: &str,
+        package_root: &str,
+    ) -> Result<CreatePackageResult, anyhow::Error> {
+        sfdx_create_package(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            package_name,
+            package_desc,
+            package_type,
+            package_root,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn create_package_version(
+        &self,
+        hub_user: &str,
+        package_id: &str,
+        org_def_path: &str,
+        version_name: &str,
+        version_number: &str,
+        installation_key: &str,
+        code_coverage: bool,
+        wait_seconds: i32,
+    ) -> Result<PackageVersionResult, anyhow::Error> {
+        sfdx_create_package_version(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            package_id,
+            org_def_path,
+            version_name,
+            version_number,
+            installation_key,
+            code_coverage,
+            wait_seconds,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn fetch_package_version(
+        &self,
+        hub_user: &str,
+        id: &str,
+    ) -> Result<PackageVersionResult, anyhow::Error> {
+        sfdx_fetch_package_version(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            id,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn promote_package_version(&self, hub_user: &str, id: &str) -> Result<(), anyhow::Error> {
+        sfdx_promote_package_version(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            id,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn list_package_versions(
+        &self,
+        hub_user: &str,
+        package_id: &str,
+        released_only: bool,
+    ) -> Result<Vec<PackageVersionListItem>, anyhow::Error> {
+        sfdx_list_package_versions(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            hub_user,
+            package_id,
+            released_only,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+
+    fn test_apex(
+        &self,
+        scratch_org_alias: &str,
+        tests: &TestSelection,
+        wait_seconds: i32,
+    ) -> Result<ApexTestRunResult, anyhow::Error> {
+        sfdx_test_apex(
+            &self.layers_dir,
+            &self.app_dir,
+            self.backend,
+            scratch_org_alias,
+            tests,
+            wait_seconds,
+            &mut self.logger.lock().unwrap(),
+        )
+    }
+}
@@ -0,0 +1,372 @@
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context};
+use libcnb::PlatformEnv;
+use serde::de::DeserializeOwned;
+
+use crate::client::SfdxClient;
+use crate::util::config::TestSelection;
+use crate::util::logger::BuildLogger;
+use crate::{
+    apex_test_run_result, check_response, find_package_result, read_response, test_selection_args,
+    ApexTestRunResult, CreatePackageResult, ExecuteAnonymousResult, FindPackageResult,
+    OrgDisplayResult, PackageCreateResult, PackageVersionCreateResult, PackageVersionListItem,
+    PackageVersionResult, SfdxResponse,
+};
+
+/// A command run through a `ScriptedSfdxClient`, with the values it was given.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SfdxCall {
+    pub command: String,
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Script {
+    responses: HashMap<String, VecDeque<String>>,
+    calls: Vec<SfdxCall>,
+}
+
+/// # Scripted sfdx Client
+/// Replays recorded `--json` responses, queued per sfdx command such as `force:package:list`,
+/// and records every call made.  Commands whose result the buildpack reads fail when no response
+/// is queued; the others succeed with empty output unless a failed response is queued.  Hub
+/// authentication is recorded as the `auth` command.  Clones share their script, so a clone can
+/// be handed to a build and inspected afterwards.
+#[derive(Debug, Clone)]
+pub struct ScriptedSfdxClient {
+    script: Arc<Mutex<Script>>,
+    logger: Arc<Mutex<BuildLogger>>,
+}
+
+impl Default for ScriptedSfdxClient {
+    fn default() -> Self {
+        ScriptedSfdxClient {
+            script: Arc::default(),
+            logger: Arc::new(Mutex::new(BuildLogger::new(false, false))),
+        }
+    }
+}
+
+impl ScriptedSfdxClient {
+    pub fn new() -> Self {
+        ScriptedSfdxClient::default()
+    }
+
+    /// Queue a response for the next call of the command.
+    pub fn respond(&self, command: &str, response: impl Into<String>) -> &Self {
+        self.script
+            .lock()
+            .unwrap()
+            .responses
+            .entry(command.to_string())
+            .or_default()
+            .push_back(response.into());
+        self
+    }
+
+    /// Queue a response recorded in a file for the next call of the command.
+    pub fn respond_with_file(
+        &self,
+        command: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<&Self, anyhow::Error> {
+        let path = path.as_ref();
+        let response = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.to_string_lossy()))?;
+        Ok(self.respond(command, response))
+    }
+
+    pub fn calls(&self) -> Vec<SfdxCall> {
+        self.script.lock().unwrap().calls.clone()
+    }
+
+    /// The commands called so far, in order.
+    pub fn commands(&self) -> Vec<String> {
+        self.calls().into_iter().map(|c| c.command).collect()
+    }
+
+    fn call(&self, command: &str, args: &[&str]) -> Option<String> {
+        let mut script = self.script.lock().unwrap();
+        script.calls.push(SfdxCall {
+            command: command.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        });
+        script
+            .responses
+            .get_mut(command)
+            .and_then(|responses| responses.pop_front())
+    }
+
+    /// The result in the queued response of a command whose result is read, or its error.
+    /// `target` is as for `sfdx_command_error`.
+    fn expect<R: DeserializeOwned>(
+        &self,
+        command: &str,
+        target: &str,
+        args: &[&str],
+    ) -> Result<R, anyhow::Error> {
+        match self.call(command, args) {
+            Some(response) => read_response(
+                command,
+                target,
+                &response,
+                "",
+                &mut self.logger.lock().unwrap(),
+            ),
+            None => Err(anyhow!("no response recorded for {}", command)),
+        }
+    }
+
+    fn output(&self, command: &str, target: &str, args: &[&str]) -> Result<Output, anyhow::Error> {
+        let stdout = match self.call(command, args) {
+            Some(response) => {
+                check_response(
+                    command,
+                    target,
+                    &response,
+                    "",
+                    &mut self.logger.lock().unwrap(),
+                )?;
+                response
+            }
+            None => String::new(),
+        };
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.into_bytes(),
+            stderr: vec![],
+        })
+    }
+}
+
+impl SfdxClient for ScriptedSfdxClient {
+    fn auth(
+        &self,
+        _client_id: &str,
+        _key_path: &str,
+        instance_url: &str,
+        user_name: &str,
+        _alias: Option<String>,
+        _env: &PlatformEnv,
+    ) -> Result<(), anyhow::Error> {
+        self.output("auth", user_name, &[user_name, instance_url])?;
+        Ok(())
+    }
+
+    fn display_org(&self, user: &str) -> Option<OrgDisplayResult> {
+        self.call("force:org:display", &[user])
+            .and_then(|response| SfdxResponse::parse(&response, "").result)
+    }
+
+    fn create_org(
+        &self,
+        hub_user: &str,
+        scratch_org_def_path: &str,
+        scratch_org_duration: i32,
+        scratch_org_alias: &str,
+        no_namespace: bool,
+    ) -> Result<Output, anyhow::Error> {
+        let duration = scratch_org_duration.to_string();
+        let mut args = vec![
+            hub_user,
+            scratch_org_def_path,
+            duration.as_str(),
+            scratch_org_alias,
+        ];
+        if no_namespace {
+            args.push("-n");
+        }
+        self.output("force:org:create", hub_user, &args)
+    }
+
+    fn delete_org(&self, hub_user: &str, scratch_org_alias: &str) -> Result<Output, anyhow::Error> {
+        self.output(
+            "force:org:delete",
+            scratch_org_alias,
+            &[hub_user, scratch_org_alias],
+        )
+    }
+
+    fn push_source(
+        &self,
+        scratch_org_alias: &str,
+        _wait_seconds: i32,
+    ) -> Result<Output, anyhow::Error> {
+        self.output("force:source:push", scratch_org_alias, &[scratch_org_alias])
+    }
+
+    fn install_package(
+        &self,
+        scratch_org_alias: &str,
+        package_version_id: &str,
+        installation_key: &str,
+        _wait_seconds: i32,
+    ) -> Result<Output, anyhow::Error> {
+        self.output(
+            "force:package:install",
+            package_version_id,
+            &[scratch_org_alias, package_version_id, installation_key],
+        )
+    }
+
+    fn assign_permset(
+        &self,
+        scratch_org_alias: &str,
+        permset_name: &str,
+    ) -> Result<Output, anyhow::Error> {
+        self.output(
+            "force:user:permset:assign",
+            scratch_org_alias,
+            &[scratch_org_alias, permset_name],
+        )
+    }
+
+    fn import_data(
+        &self,
+        scratch_org_alias: &str,
+        plan_path: &str,
+    ) -> Result<Output, anyhow::Error> {
+        self.output(
+            "force:data:tree:import",
+            scratch_org_alias,
+            &[scratch_org_alias, plan_path],
+        )
+    }
+
+    fn execute_apex(
+        &self,
+        scratch_org_alias: &str,
+        apex_path: &str,
+    ) -> Result<ExecuteAnonymousResult, anyhow::Error> {
+        self.expect(
+            "force:apex:execute",
+            scratch_org_alias,
+            &[scratch_org_alias, apex_path],
+        )
+    }
+
+    fn create_user(
+        &self,
+        hub_user: &str,
+        scratch_org_alias: &str,
+        user_def_path: &str,
+    ) -> Result<Output, anyhow::Error> {
+        self.output(
+            "force:user:create",
+            scratch_org_alias,
+            &[hub_user, scratch_org_alias, user_def_path],
+        )
+    }
+
+    fn find_package(
+        &self,
+        hub_user: &str,
+        package_name: &str,
+    ) -> Result<FindPackageResult, anyhow::Error> {
+        let packages = self.expect("force:package:list", hub_user, &[hub_user])?;
+        Ok(find_package_result(packages, package_name))
+    }
+
+    fn create_package(
+        &self,
+        hub_user: &str,
+        package_name: &str,
+        package_desc: &str,
+        package_type: &str,
+        package_root: &str,
+    ) -> Result<CreatePackageResult, anyhow::Error> {
+        let created: PackageCreateResult = self.expect(
+            "force:package:create",
+            package_name,
+            &[
+                hub_user,
+                package_name,
+                package_desc,
+                package_type,
+                package_root,
+            ],
+        )?;
+        Ok(CreatePackageResult {
+            created: true,
+            package_id: created.id,
+        })
+    }
+
+    fn create_package_version(
+        &self,
+        hub_user: &str,
+        package_id: &str,
+        org_def_path: &str,
+        version_name: &str,
+        version_number: &str,
+        installation_key: &str,
+        code_coverage: bool,
+        _wait_seconds: i32,
+    ) -> Result<PackageVersionResult, anyhow::Error> {
+        let mut args = vec![
+            hub_user,
+            package_id,
+            org_def_path,
+            version_name,
+            version_number,
+            installation_key,
+        ];
+        if code_coverage {
+            args.push("-c");
+        }
+        let created: PackageVersionCreateResult =
+            self.expect("force:package:version:create", package_id, &args)?;
+        self.fetch_package_version(hub_user, &created.subscriber_package_version_id)
+    }
+
+    fn fetch_package_version(
+        &self,
+        hub_user: &str,
+        id: &str,
+    ) -> Result<PackageVersionResult, anyhow::Error> {
+        self.expect("force:package:version:report", id, &[hub_user, id])
+    }
+
+    fn promote_package_version(&self, hub_user: &str, id: &str) -> Result<(), anyhow::Error> {
+        self.output("force:package:version:promote", id, &[hub_user, id])?;
+        Ok(())
+    }
+
+    fn list_package_versions(
+        &self,
+        hub_user: &str,
+        package_id: &str,
+        released_only: bool,
+    ) -> Result<Vec<PackageVersionListItem>, anyhow::Error> {
+        let mut args = vec![hub_user, package_id];
+        if released_only {
+            args.push("--released");
+        }
+        self.expect("force:package:version:list", package_id, &args)
+    }
+
+    fn test_apex(
+        &self,
+        scratch_org_alias: &str,
+        tests: &TestSelection,
+        _wait_seconds: i32,
+    ) -> Result<ApexTestRunResult, anyhow::Error> {
+        let mut args = vec![scratch_org_alias.to_string()];
+        args.extend(test_selection_args(tests));
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        match self.call("force:apex:test:run", &args) {
+            Some(response) => apex_test_run_result(
+                SfdxResponse::parse(&response, ""),
+                "force:apex:test:run",
+                scratch_org_alias,
+                &mut self.logger.lock().unwrap(),
+            ),
+            None => Err(anyhow!("no response recorded for force:apex:test:run")),
+        }
+    }
+}
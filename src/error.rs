@@ -0,0 +1,388 @@
+use std::fmt;
+
+use libcnb::ErrorHandler;
+use serde::Deserialize;
+
+use crate::client::sfdx_command_name;
+use crate::{BuildLogger, Logger};
+
+/// # Buildpack Error
+/// The failures a user can do something about.  Each carries what went wrong, a stable `code`
+/// to search for and a `hint` on how to fix it.  Errors that are not a `BuildpackError` are
+/// reported as they are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildpackError {
+    /// The sfdx CLI could not be found or installed.
+    SfdxNotFound { reason: String },
+    /// The Dev Hub could not be authenticated.
+    AuthFailed { user: String, reason: String },
+    /// The Dev Hub has no scratch orgs left to create.
+    OrgLimitExceeded { hub_user: String, message: String },
+    /// Source changed both locally and in the scratch org.
+    PushConflict {
+        org: String,
+        conflicts: Vec<SourceConflict>,
+    },
+    /// The Dev Hub could not create a new version of the package.
+    PackageVersionCreateFailed { package_id: String, message: String },
+    /// Apex tests ran, and some failed.
+    TestFailures { org: String, failures: Vec<String> },
+    /// Any other failed sfdx command, with the error the CLI reported.
+    SfdxCommandFailed {
+        command: String,
+        name: String,
+        message: String,
+    },
+    /// Apex tests passed, but covered less code than `min_coverage` or `min_class_coverage`.
+    InsufficientCoverage {
+        org: String,
+        shortfalls: Vec<String>,
+    },
+    /// The sfdx runtime tarball does not have the checksum it was published or pinned with.
+    RuntimeChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    /// The installed sfdx runtime is not the `version` it was pinned with.
+    RuntimeVersionMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+    /// The runtime has a `version` but no `sha256` to pin it with.
+    RuntimeNotPinned { version: String },
+    /// A CLI plugin in `[runtime] plugins` has no version to install.
+    PluginNotPinned { plugin: String },
+    /// A CLI plugin in `[runtime] plugins` could not be installed into the runtime layer.
+    PluginInstallFailed { plugin: String, message: String },
+}
+
+impl BuildpackError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            BuildpackError::SfdxNotFound { .. } => "SFPB001",
+            BuildpackError::AuthFailed { .. } => "SFPB002",
+            BuildpackError::OrgLimitExceeded { .. } => "SFPB003",
+            BuildpackError::PushConflict { .. } => "SFPB004",
+            BuildpackError::PackageVersionCreateFailed { .. } => "SFPB005",
+            BuildpackError::TestFailures { .. } => "SFPB006",
+            BuildpackError::SfdxCommandFailed { .. } => "SFPB007",
+            BuildpackError::InsufficientCoverage { .. } => "SFPB008",
+            BuildpackError::RuntimeChecksumMismatch { .. } => "SFPB009",
+            BuildpackError::PluginNotPinned { .. } => "SFPB010",
+            BuildpackError::PluginInstallFailed { .. } => "SFPB011",
+            BuildpackError::RuntimeVersionMismatch { .. } => "SFPB012",
+            BuildpackError::RuntimeNotPinned { .. } => "SFPB013",
+        }
+    }
+
+    pub fn hint(&self) -> &'static str {
+        match self {
+            BuildpackError::SfdxNotFound { .. } => {
+                "Install the sfdx CLI and put it on PATH, or let the runtime layer install it by allowing network access to the tarball on developer.salesforce.com, or to the mirror set as the url in the [runtime] table of app.toml."
+            }
+            BuildpackError::AuthFailed { .. } => {
+                "Check hub_user, hub_client_id and hub_instance_url in app.toml, and provide one of SFDX_AUTH_KEYFILE, SFDX_AUTH_ENC_KEYFILE, SFDX_AUTH_URL, SFDX_AUTH_URLFILE or SFDX_ACCESS_TOKEN."
+            }
+            BuildpackError::OrgLimitExceeded { .. } => {
+                "Delete unused scratch orgs with `sfdx force:org:list --clean`, or wait for expired orgs to be removed."
+            }
+            BuildpackError::PushConflict { .. } => {
+                "Retrieve the conflicting changes with `sfdx force:source:pull`, or recreate the scratch org to discard them."
+            }
+            BuildpackError::PackageVersionCreateFailed { .. } => {
+                "Check the package's versionNumber and dependencies in sfdx-project.json, and that the Dev Hub can still create package versions today."
+            }
+            BuildpackError::TestFailures { .. } => "Fix the failing Apex tests listed above.",
+            BuildpackError::SfdxCommandFailed { .. } => {
+                "Run the command with --json for the full error reported by the CLI."
+            }
+            BuildpackError::InsufficientCoverage { .. } => {
+                "Add Apex tests covering the lines listed in the coverage report, or lower min_coverage and min_class_coverage in app.toml."
+            }
+            BuildpackError::RuntimeChecksumMismatch { .. } => {
+                "Retry the build in case the download was corrupted.  If it persists, check the runtime url and any pinned sha256 in app.toml or buildpack.toml; do not run a runtime that fails verification."
+            }
+            BuildpackError::PluginNotPinned { .. } => {
+                "Give each of the runtime plugins in app.toml the version to install, as name@version."
+            }
+            BuildpackError::PluginInstallFailed { .. } => {
+                "Check that the plugin and version exist, and that the build can reach the npm registry the CLI installs plugins from.  A plugin that is not signed is only installed when its name is listed in the unsigned_plugins of the runtime."
+            }
+            BuildpackError::RuntimeVersionMismatch { .. } => {
+                "Set the runtime version in app.toml or buildpack.toml to the version of the tarball its url and sha256 point to."
+            }
+            BuildpackError::RuntimeNotPinned { .. } => {
+                "Set the sha256 of the runtime tarball along with its version in app.toml or buildpack.toml, or remove the version to install the latest runtime."
+            }
+        }
+    }
+}
+
+impl fmt::Display for BuildpackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildpackError::SfdxNotFound { reason } => {
+                write!(f, "sfdx is not available: {}", reason)
+            }
+            BuildpackError::AuthFailed { user, reason } => {
+                write!(f, "failed to authenticate hub {}: {}", user, reason)
+            }
+            BuildpackError::OrgLimitExceeded { hub_user, message } => write!(
+                f,
+                "hub {} has reached its scratch org limit: {}",
+                hub_user, message
+            ),
+            BuildpackError::PushConflict { org, conflicts } => {
+                write!(f, "source conflicts pushing to {}:", org)?;
+                for conflict in conflicts {
+                    write!(f, "\n  {}", conflict)?;
+                }
+                Ok(())
+            }
+            BuildpackError::PackageVersionCreateFailed {
+                package_id,
+                message,
+            } => write!(
+                f,
+                "failed to create new package version of {}: {}",
+                package_id, message
+            ),
+            BuildpackError::TestFailures { org, failures } => {
+                write!(f, "{} apex tests failed on {}:", failures.len(), org)?;
+                for failure in failures {
+                    write!(f, "\n  {}", failure)?;
+                }
+                Ok(())
+            }
+            BuildpackError::SfdxCommandFailed {
+                command,
+                name,
+                message,
+            } => write!(f, "{} failed: {}: {}", command, name, message),
+            BuildpackError::InsufficientCoverage { org, shortfalls } => {
+                write!(f, "insufficient apex code coverage on {}:", org)?;
+                for shortfall in shortfalls {
+                    write!(f, "\n  {}", shortfall)?;
+                }
+                Ok(())
+            }
+            BuildpackError::RuntimeChecksumMismatch {
+                url,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "sfdx runtime {} failed checksum verification: expected sha256 {}, got {}",
+                url, expected, actual
+            ),
+            BuildpackError::PluginNotPinned { plugin } => {
+                write!(f, "sfdx plugin {} is not pinned to a version", plugin)
+            }
+            BuildpackError::PluginInstallFailed { plugin, message } => {
+                write!(f, "failed to install sfdx plugin {}: {}", plugin, message)
+            }
+            BuildpackError::RuntimeVersionMismatch {
+                url,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "sfdx runtime {} is version {}, not the pinned {}",
+                url, actual, expected
+            ),
+            BuildpackError::RuntimeNotPinned { version } => write!(
+                f,
+                "sfdx runtime version {} is set without a sha256 to pin it",
+                version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BuildpackError {}
+
+/// A component changed both locally and in the org, as listed by `force:source:push`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceConflict {
+    #[serde(default)]
+    pub full_name: String,
+    #[serde(default, rename = "type")]
+    pub component_type: String,
+    #[serde(default)]
+    pub file_path: String,
+}
+
+impl fmt::Display for SourceConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} ({})",
+            self.component_type, self.full_name, self.file_path
+        )
+    }
+}
+
+/// The error for the `name`, `message` and `data` of a failed sfdx command.  `target` is what
+/// the command acted on: the hub user for `force:org:create` and authentication, the org for
+/// `force:source:push`, and the package for `force:package:version:create`.  `command` is named
+/// as either `sfdx` or `sf` runs it.
+pub(crate) fn sfdx_command_error(
+    command: &str,
+    target: &str,
+    name: String,
+    message: String,
+    data: serde_json::Value,
+) -> BuildpackError {
+    match sfdx_command_name(command) {
+        "auth" => BuildpackError::AuthFailed {
+            user: target.to_string(),
+            reason: message,
+        },
+        "force:org:create"
+            if name == "LIMIT_EXCEEDED" || message.to_lowercase().contains("org limit") =>
+        {
+            BuildpackError::OrgLimitExceeded {
+                hub_user: target.to_string(),
+                message,
+            }
+        }
+        "force:source:push" if name.to_lowercase().contains("conflict") => {
+            BuildpackError::PushConflict {
+                org: target.to_string(),
+                conflicts: serde_json::from_value(data).unwrap_or_default(),
+            }
+        }
+        "force:package:version:create" => BuildpackError::PackageVersionCreateFailed {
+            package_id: target.to_string(),
+            message,
+        },
+        _ => BuildpackError::SfdxCommandFailed {
+            command: command.to_string(),
+            name,
+            message,
+        },
+    }
+}
+
+/// # Error Handler
+/// Reports a failed buildpack phase with the code and hint of any `BuildpackError` behind it.
+pub struct SFPackageErrorHandler;
+
+impl ErrorHandler<anyhow::Error> for SFPackageErrorHandler {
+    fn handle_error(&self, error: libcnb::Error<anyhow::Error>) -> i32 {
+        let mut logger = BuildLogger::new(false, true);
+        // The logger reports the error by failing, which is all that is left to do here.
+        let _ = logger.error("Buildpack failed", describe_error(&error));
+        100
+    }
+}
+
+/// An error as reported to the user: with its code and hint if it is a `BuildpackError`.
+pub fn describe_error(error: &libcnb::Error<anyhow::Error>) -> String {
+    match error {
+        libcnb::Error::BuildpackError(e) => match find_buildpack_error(e) {
+            Some(e) => format!("[{}] {}\n{}", e.code(), e, e.hint()),
+            None => format!("{:#}", e),
+        },
+        other => other.to_string(),
+    }
+}
+
+/// The `BuildpackError` behind an error, if any, looking through the context added on the way.
+pub fn find_buildpack_error(error: &anyhow::Error) -> Option<&BuildpackError> {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<BuildpackError>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SfdxResponse;
+    use anyhow::Context;
+
+    fn error(command: &str, target: &str, stdout: &str, stderr: &str) -> BuildpackError {
+        SfdxResponse::<serde_json::Value>::parse(stdout, stderr)
+            .into_result(command, target)
+            .unwrap_err()
+    }
+
+    #[test]
+    fn it_classifies_sfdx_errors() {
+        let limit = error(
+            "force:org:create",
+            "hub",
+            r#"{"status": 1, "name": "LIMIT_EXCEEDED", "message": "The signup request failed because this organization has reached its active scratch org limit"}"#,
+            "",
+        );
+        assert_eq!(limit.code(), "SFPB003");
+        let limit = error(
+            "org create scratch",
+            "hub",
+            r#"{"status": 1, "name": "SignupFailedError", "message": "The request to create a scratch org failed with error code: LIMIT_EXCEEDED. This org has reached its active scratch org limit."}"#,
+            "",
+        );
+        assert_eq!(limit.code(), "SFPB003");
+
+        let conflict = error(
+            "force:source:push",
+            "dev",
+            r#"{"status": 1, "name": "sourceConflictDetected", "message": "Source conflict(s) detected.", "data": [{"state": "Conflict", "fullName": "Greeter", "type": "ApexClass", "filePath": "force-app/main/default/classes/Greeter.cls"}]}"#,
+            "",
+        );
+        match conflict {
+            BuildpackError::PushConflict { org, conflicts } => {
+                assert_eq!(org, "dev");
+                assert_eq!(conflicts[0].full_name, "Greeter");
+                assert_eq!(conflicts[0].component_type, "ApexClass");
+            }
+            e => panic!("unexpected {:?}", e),
+        }
+
+        let created = error(
+            "package version create",
+            "0Ho3t000000XZNrCAO",
+            r#"{"status": 1, "name": "PackageVersionCreateError", "message": "Version number 1.0.0 is already released"}"#,
+            "",
+        );
+        assert_eq!(created.code(), "SFPB005");
+    }
+
+    #[test]
+    fn it_tolerates_missing_error_fields() {
+        assert_eq!(
+            error("force:org:delete", "ci", r#"{"status": 1}"#, ""),
+            BuildpackError::SfdxCommandFailed {
+                command: "force:org:delete".to_string(),
+                name: "".to_string(),
+                message: "".to_string(),
+            }
+        );
+        assert_eq!(
+            error(
+                "force:org:delete",
+                "ci",
+                "ERROR running force:org:delete",
+                "no org\n"
+            ),
+            BuildpackError::SfdxCommandFailed {
+                command: "force:org:delete".to_string(),
+                name: "Error".to_string(),
+                message: "no org".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn it_finds_buildpack_errors_behind_context() {
+        let error = Err::<(), _>(BuildpackError::SfdxNotFound {
+            reason: "npm failed".to_string(),
+        })
+        .context("installing sfdx")
+        .unwrap_err();
+        assert_eq!(find_buildpack_error(&error).unwrap().code(), "SFPB001");
+    }
+}
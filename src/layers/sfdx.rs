@@ -1,17 +1,35 @@
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
 
-use anyhow::Error;
+use anyhow::{anyhow, Context, Error};
 
 use libcnb::data::layer_content_metadata::LayerContentMetadata;
 use libcnb::layer_lifecycle::{LayerLifecycle, ValidateResult};
-use libcnb::{get, get_and_extract, BuildContext, GenericPlatform};
+use libcnb::{BuildContext, GenericPlatform};
+use sha2::{Digest, Sha256};
 use std::env;
+use xz2::read::XzDecoder;
 
-use crate::util::config::{SFDXRuntimeConfig, SFPackageBuildpackConfig};
+use crate::util::config::{SFDXRuntimeConfig, SFPackageAppConfig, SFPackageBuildpackConfig};
+use crate::{BuildLogger, BuildpackError, CliBackend, Logger};
 
 pub(crate) struct SFDXLayerLifecycle;
 
+/// The runtime to install: that of `buildpack.toml`, with the `[runtime]` table of `app.toml`
+/// applied.
+pub(crate) fn runtime_config(
+    build_context: &BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
+) -> SFDXRuntimeConfig {
+    build_context
+        .buildpack_descriptor
+        .metadata
+        .runtime
+        .with_overrides(&SFPackageAppConfig::from_dir(&build_context.app_dir).runtime)
+}
+
 impl
     LayerLifecycle<
         GenericPlatform,
@@ -26,11 +44,9 @@ impl
         layer_path: &Path,
         build_context: &BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
     ) -> Result<LayerContentMetadata<SFPackageBuildpackConfig>, anyhow::Error> {
-        let runtime_sha256 = get_and_extract(
-            &build_context.buildpack_descriptor.metadata.runtime.url,
-            &layer_path,
-            Some("sfdx/"),
-        )?;
+        let runtime = runtime_config(build_context);
+        let runtime_sha256 = install(&runtime, &build_context.app_dir, layer_path)?;
+        install_plugins(&runtime, layer_path, &mut BuildLogger::new(false, true))?;
 
         Ok(LayerContentMetadata::default()
             .build(false)
@@ -38,19 +54,8 @@ impl
             .launch(true)
             .metadata(SFPackageBuildpackConfig {
                 runtime: SFDXRuntimeConfig {
-                    url: build_context
-                        .buildpack_descriptor
-                        .metadata
-                        .runtime
-                        .url
-                        .clone(),
-                    manifest: build_context
-                        .buildpack_descriptor
-                        .metadata
-                        .runtime
-                        .manifest
-                        .clone(),
-                    sha256: runtime_sha256.clone(),
+                    sha256: runtime_sha256,
+                    ..runtime
                 },
             }))
     }
@@ -61,17 +66,27 @@ impl
         layer_content_metadata: &LayerContentMetadata<SFPackageBuildpackConfig>,
         build_context: &BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
     ) -> ValidateResult {
-        // Get most recent sfdx.tar.xz manifest
-        let manifest_content =
-            get(&build_context.buildpack_descriptor.metadata.runtime.manifest).unwrap();
-        let manifest = json::parse(&manifest_content).unwrap();
-
-        let manifest_sha256 = match &manifest["sha256xz"] {
-            json::JsonValue::String(s) => s,
-            _ => "",
+        let runtime = runtime_config(build_context);
+        // The plugins are part of the layer, so changing them recreates it.
+        if layer_content_metadata.metadata.runtime.plugins != runtime.plugins {
+            return ValidateResult::RecreateLayer;
+        }
+        let expected_sha256 = if runtime.is_pinned() {
+            runtime.sha256
+        } else {
+            // Get most recent sfdx.tar.xz manifest.  Offline, the cached runtime is kept.
+            match latest_sha256(&runtime.manifest, &build_context.app_dir) {
+                Ok(sha256) => sha256,
+                Err(_) => return ValidateResult::KeepLayer,
+            }
         };
 
-        if layer_content_metadata.metadata.runtime.sha256 == manifest_sha256 {
+        if layer_content_metadata
+            .metadata
+            .runtime
+            .sha256
+            .eq_ignore_ascii_case(&expected_sha256)
+        {
             ValidateResult::KeepLayer
         } else {
             ValidateResult::RecreateLayer
@@ -95,7 +110,465 @@ impl
                 env::var("PATH").unwrap_or(String::new()),
             ),
         );
+        for (key, value) in plugin_env(layer_path) {
+            layer_env.insert(key.to_string(), value.to_string_lossy().to_string());
+        }
 
         Ok(layer_env)
     }
 }
+
+/// The checksum of the latest runtime tarball, as published in its build manifest.
+fn latest_sha256(manifest_url: &str, app_dir: &Path) -> Result<String, anyhow::Error> {
+    let manifest_content = fetch(manifest_url, app_dir)?;
+    let manifest = json::parse(&String::from_utf8_lossy(&manifest_content))?;
+    manifest["sha256xz"]
+        .as_str()
+        .map(String::from)
+        .ok_or_else(|| anyhow!("no sha256xz in manifest {}", manifest_url))
+}
+
+/// Install the runtime tarball into the layer, returning its checksum.  The tarball is only
+/// extracted once its checksum matches the pinned `sha256` or, for a runtime that is not pinned,
+/// the `sha256xz` of its build manifest.  A runtime that cannot be verified is not installed.  A
+/// pinned runtime must then report the pinned `version`, if one is given.
+pub(crate) fn install(
+    runtime: &SFDXRuntimeConfig,
+    app_dir: &Path,
+    layer_path: &Path,
+) -> Result<String, anyhow::Error> {
+    if !runtime.version.is_empty() && !runtime.is_pinned() {
+        return Err(BuildpackError::RuntimeNotPinned {
+            version: runtime.version.clone(),
+        }
+        .into());
+    }
+    let expected = if runtime.is_pinned() {
+        runtime.sha256.clone()
+    } else {
+        latest_sha256(&runtime.manifest, app_dir).context("failed to verify the sfdx runtime")?
+    };
+    let tarball = fetch(&runtime.url, app_dir)?;
+    let actual = format!("{:x}", Sha256::digest(&tarball));
+    if !actual.eq_ignore_ascii_case(&expected) {
+        return Err(BuildpackError::RuntimeChecksumMismatch {
+            url: runtime.url.clone(),
+            expected,
+            actual,
+        }
+        .into());
+    }
+    extract(&tarball, layer_path)?;
+    if !runtime.version.is_empty() {
+        let backend = layer_backend(runtime, layer_path);
+        let installed = backend
+            .version_of(&layer_path.join("bin").join(backend.program()))
+            .unwrap_or_else(|| "unknown".to_string());
+        if installed != runtime.version {
+            return Err(BuildpackError::RuntimeVersionMismatch {
+                url: runtime.url.clone(),
+                expected: runtime.version.clone(),
+                actual: installed,
+            }
+            .into());
+        }
+    }
+    Ok(actual)
+}
+
+/// The backend of the CLI installed into the layer: `cli` if set, otherwise that of the tarball.
+fn layer_backend(runtime: &SFDXRuntimeConfig, layer_path: &Path) -> CliBackend {
+    runtime
+        .cli
+        .unwrap_or_else(|| CliBackend::detect_in(&layer_path.join("bin")))
+}
+
+/// Install the runtime's plugins into the layer with the CLI of the runtime.  Each plugin must be
+/// pinned to a version.  The CLI asks before installing a plugin that is not signed, which is
+/// only trusted when listed in `unsigned_plugins`, and refused otherwise.
+pub(crate) fn install_plugins(
+    runtime: &SFDXRuntimeConfig,
+    layer_path: &Path,
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    if let Some(plugin) = runtime.plugins.iter().find(|p| !is_pinned_plugin(p)) {
+        return Err(BuildpackError::PluginNotPinned {
+            plugin: plugin.clone(),
+        }
+        .into());
+    }
+    if runtime.plugins.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(layer_path.join("plugins"))?;
+    let cli = layer_path
+        .join("bin")
+        .join(layer_backend(runtime, layer_path).program());
+    for plugin in runtime.plugins.iter() {
+        let trusted = runtime
+            .unsigned_plugins
+            .iter()
+            .any(|name| name == plugin_name(plugin));
+        if trusted {
+            logger.info(format!("---> installing {}, trusted if not signed", plugin))?;
+        }
+        let mut child = Command::new(&cli)
+            .arg("plugins:install")
+            .arg(plugin)
+            .envs(plugin_env(layer_path))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to install sfdx plugin {}", plugin))?;
+        // Answer the prompt to trust an unsigned plugin.
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(if trusted { b"y\n" } else { b"n\n" });
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(BuildpackError::PluginInstallFailed {
+                plugin: plugin.clone(),
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Whether a plugin is given as `name@version`.  Scoped names start with `@` too.
+fn is_pinned_plugin(plugin: &str) -> bool {
+    matches!(plugin.rfind('@'), Some(at) if at > 0 && at + 1 < plugin.len())
+}
+
+/// The name of a plugin given as `name@version`.
+fn plugin_name(plugin: &str) -> &str {
+    match plugin.rfind('@') {
+        Some(at) if at > 0 => &plugin[..at],
+        _ => plugin,
+    }
+}
+
+/// The environment that has `sfdx` and `sf` find the plugins installed into the layer.
+pub(crate) fn plugin_env(layer_path: &Path) -> [(&'static str, PathBuf); 2] {
+    let data_dir = layer_path.join("plugins");
+    [
+        ("SFDX_DATA_DIR", data_dir.clone()),
+        ("SF_DATA_DIR", data_dir),
+    ]
+}
+
+/// The tarball at `url`, which may be downloaded or, for a mirror on disk, a path relative to
+/// the app.
+fn fetch(url: &str, app_dir: &Path) -> Result<Vec<u8>, anyhow::Error> {
+    if url.starts_with("https://") || url.starts_with("http://") {
+        let response = reqwest::blocking::get(url)?.error_for_status()?;
+        Ok(response.bytes()?.to_vec())
+    } else {
+        let path = app_dir.join(url.strip_prefix("file://").unwrap_or(url));
+        fs::read(&path).with_context(|| format!("failed to read {}", path.to_string_lossy()))
+    }
+}
+
+/// Extract the top directory of an `.tar.xz` tarball into the layer: `sfdx/` for the `sfdx`
+/// CLI, `sf/` for `sf`.
+fn extract(tarball: &[u8], layer_path: &Path) -> Result<(), anyhow::Error> {
+    let mut archive = tar::Archive::new(XzDecoder::new(tarball));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let mut components = path.components();
+        if !matches!(components.next(), Some(Component::Normal(_))) {
+            continue;
+        }
+        let relative = components.as_path();
+        if relative.as_os_str().is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            continue;
+        }
+        let target = layer_path.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::find_buildpack_error;
+    use tempfile::tempdir;
+    use xz2::write::XzEncoder;
+
+    /// A runtime tarball holding `sfdx/bin/sfdx`, written to `dir`.
+    fn tarball(dir: &Path) -> (String, String) {
+        let script = b"#!/bin/sh\necho sfdx-cli/7.132.0\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(script.len() as u64);
+        header.set_mode(0o755);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(XzEncoder::new(vec![], 6));
+        builder
+            .append_data(&mut header, "sfdx/bin/sfdx", &script[..])
+            .unwrap();
+        let bytes = builder.into_inner().unwrap().finish().unwrap();
+        let mut file = fs::File::create(dir.join("sfdx-linux-x64.tar.xz")).unwrap();
+        file.write_all(&bytes).unwrap();
+        (
+            "sfdx-linux-x64.tar.xz".to_string(),
+            format!("{:x}", Sha256::digest(&bytes)),
+        )
+    }
+
+    #[test]
+    fn it_installs_pinned_runtimes_from_disk() {
+        let app_dir = tempdir().unwrap();
+        let layer_dir = tempdir().unwrap();
+        let (url, sha256) = tarball(app_dir.path());
+        let runtime = SFDXRuntimeConfig {
+            url,
+            sha256: sha256.clone(),
+            version: "7.132.0".to_string(),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            install(&runtime, app_dir.path(), layer_dir.path()).unwrap(),
+            sha256
+        );
+        assert!(fs::read_to_string(layer_dir.path().join("bin/sfdx"))
+            .unwrap()
+            .contains("sfdx-cli/7.132.0"));
+    }
+
+    #[test]
+    fn it_rejects_runtimes_not_matching_the_pinned_version() {
+        let app_dir = tempdir().unwrap();
+        let layer_dir = tempdir().unwrap();
+        let (url, sha256) = tarball(app_dir.path());
+        let runtime = SFDXRuntimeConfig {
+            url,
+            sha256,
+            version: "7.140.0".to_string(),
+            ..Default::default()
+        };
+
+        let error = install(&runtime, app_dir.path(), layer_dir.path()).unwrap_err();
+        assert_eq!(
+            find_buildpack_error(&error),
+            Some(&BuildpackError::RuntimeVersionMismatch {
+                url: "sfdx-linux-x64.tar.xz".to_string(),
+                expected: "7.140.0".to_string(),
+                actual: "7.132.0".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn it_rejects_versions_without_a_checksum() {
+        let app_dir = tempdir().unwrap();
+        let layer_dir = tempdir().unwrap();
+        let (url, _) = tarball(app_dir.path());
+        let runtime = SFDXRuntimeConfig {
+            url,
+            version: "7.132.0".to_string(),
+            ..Default::default()
+        };
+
+        let error = install(&runtime, app_dir.path(), layer_dir.path()).unwrap_err();
+        assert_eq!(find_buildpack_error(&error).unwrap().code(), "SFPB013");
+        assert!(!layer_dir.path().join("bin/sfdx").exists());
+    }
+
+    #[test]
+    fn it_rejects_runtimes_not_matching_the_pin() {
+        let app_dir = tempdir().unwrap();
+        let layer_dir = tempdir().unwrap();
+        let (url, actual) = tarball(app_dir.path());
+        let runtime = SFDXRuntimeConfig {
+            url,
+            sha256: "0".repeat(64),
+            ..Default::default()
+        };
+
+        let error = install(&runtime, app_dir.path(), layer_dir.path()).unwrap_err();
+        assert_eq!(
+            find_buildpack_error(&error),
+            Some(&BuildpackError::RuntimeChecksumMismatch {
+                url: "sfdx-linux-x64.tar.xz".to_string(),
+                expected: "0".repeat(64),
+                actual,
+            })
+        );
+        assert!(!layer_dir.path().join("bin/sfdx").exists());
+    }
+
+    #[test]
+    fn it_verifies_runtimes_against_the_manifest() {
+        let app_dir = tempdir().unwrap();
+        let layer_dir = tempdir().unwrap();
+        let (url, actual) = tarball(app_dir.path());
+        let runtime = SFDXRuntimeConfig {
+            url,
+            manifest: "sfdx-linux-x64-buildmanifest".to_string(),
+            ..Default::default()
+        };
+        let manifest = app_dir.path().join("sfdx-linux-x64-buildmanifest");
+
+        // Without a manifest, the runtime cannot be verified.
+        assert!(install(&runtime, app_dir.path(), layer_dir.path()).is_err());
+
+        fs::write(&manifest, r#"{"version": "7.132.0", "sha256xz": "bad"}"#).unwrap();
+        let error = install(&runtime, app_dir.path(), layer_dir.path()).unwrap_err();
+        assert_eq!(find_buildpack_error(&error).unwrap().code(), "SFPB009");
+        assert!(!layer_dir.path().join("bin/sfdx").exists());
+
+        fs::write(
+            &manifest,
+            format!(r#"{{"version": "7.132.0", "sha256xz": "{}"}}"#, actual),
+        )
+        .unwrap();
+        assert_eq!(
+            install(&runtime, app_dir.path(), layer_dir.path()).unwrap(),
+            actual
+        );
+        assert!(layer_dir.path().join("bin/sfdx").is_file());
+    }
+
+    #[test]
+    fn it_requires_pinned_plugins() {
+        assert!(is_pinned_plugin("sfpowerkit@4.2.13"));
+        assert!(is_pinned_plugin("@salesforce/sfdx-scanner@2.12.0"));
+        assert!(!is_pinned_plugin("@salesforce/sfdx-scanner"));
+        assert!(!is_pinned_plugin("sfpowerkit@"));
+
+        let runtime = SFDXRuntimeConfig {
+            plugins: vec!["sfpowerkit@4.2.13".to_string(), "sfdmu".to_string()],
+            ..Default::default()
+        };
+        let error = install_plugins(
+            &runtime,
+            tempdir().unwrap().path(),
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap_err();
+        assert_eq!(
+            find_buildpack_error(&error),
+            Some(&BuildpackError::PluginNotPinned {
+                plugin: "sfdmu".to_string()
+            })
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_installs_plugins_into_the_layer() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let layer_dir = tempdir().unwrap();
+        let bin = layer_dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        // Records each install in the data dir, as the CLI would.
+        fs::write(
+            bin.join("sfdx"),
+            "#!/bin/sh\nread answer\necho \"$2 $answer\" >> \"$SFDX_DATA_DIR/installed\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(bin.join("sfdx"), fs::Permissions::from_mode(0o755)).unwrap();
+        let runtime = SFDXRuntimeConfig {
+            plugins: vec![
+                "@salesforce/sfdx-scanner@2.12.0".to_string(),
+                "sfpowerkit@4.2.13".to_string(),
+            ],
+            unsigned_plugins: vec!["sfpowerkit".to_string()],
+            ..Default::default()
+        };
+
+        install_plugins(
+            &runtime,
+            layer_dir.path(),
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap();
+
+        // Only the plugin listed as unsigned is trusted.
+        assert_eq!(
+            fs::read_to_string(layer_dir.path().join("plugins/installed")).unwrap(),
+            "@salesforce/sfdx-scanner@2.12.0 n\nsfpowerkit@4.2.13 y\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_installs_plugins_with_the_cli_of_the_runtime() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let layer_dir = tempdir().unwrap();
+        let bin = layer_dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        // An `sf` v2 tarball, without `sfdx`.
+        fs::write(
+            bin.join("sf"),
+            "#!/bin/sh\n[ \"$1\" = --version ] && echo @salesforce/cli/2.0.1 && exit\necho \"$2\" >> \"$SF_DATA_DIR/installed\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(bin.join("sf"), fs::Permissions::from_mode(0o755)).unwrap();
+        let runtime = SFDXRuntimeConfig {
+            plugins: vec!["sfpowerkit@4.2.13".to_string()],
+            ..Default::default()
+        };
+
+        install_plugins(
+            &runtime,
+            layer_dir.path(),
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(layer_dir.path().join("plugins/installed")).unwrap(),
+            "sfpowerkit@4.2.13\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_reports_failed_plugin_installs() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let layer_dir = tempdir().unwrap();
+        let bin = layer_dir.path().join("bin");
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(
+            bin.join("sfdx"),
+            "#!/bin/sh\necho \"$2 is not a plugin\" >&2\nexit 1\n",
+        )
+        .unwrap();
+        fs::set_permissions(bin.join("sfdx"), fs::Permissions::from_mode(0o755)).unwrap();
+        let runtime = SFDXRuntimeConfig {
+            plugins: vec!["sfpowerkit@0.0.0".to_string()],
+            ..Default::default()
+        };
+
+        let error = install_plugins(
+            &runtime,
+            layer_dir.path(),
+            &mut BuildLogger::new(false, false),
+        )
+        .unwrap_err();
+        assert_eq!(
+            find_buildpack_error(&error),
+            Some(&BuildpackError::PluginInstallFailed {
+                plugin: "sfpowerkit@0.0.0".to_string(),
+                message: "sfpowerkit@0.0.0 is not a plugin".to_string(),
+            })
+        );
+        assert_eq!(find_buildpack_error(&error).unwrap().code(), "SFPB011");
+    }
+}
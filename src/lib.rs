@@ -1,17 +1,23 @@
 pub use base::*;
 pub use build::*;
 pub use cli::*;
+pub use client::*;
 pub use detect::*;
+pub use error::*;
 pub use publish::*;
 pub use test::*;
 pub use util::config::SFPackageBuildpackConfig;
 pub use util::logger::*;
+pub use watch::*;
 
 mod base;
 mod build;
 mod cli;
+mod client;
 mod detect;
+mod error;
 mod layers;
 mod publish;
 mod test;
 mod util;
+mod watch;
@@ -1,9 +1,9 @@
-use libcnb::{cnb_runtime_all, GenericErrorHandler};
+use libcnb::cnb_runtime_all;
 use std::env;
 use std::ffi::OsStr;
 use std::path::Path;
 
-use sf_package_buildpack::{build, cli, detect, publish, test};
+use sf_package_buildpack::{build, cli, detect, publish, test, SFPackageErrorHandler};
 
 fn main() {
     // Using `std::env::args()` instead of `std::env::current_exe()` since the latter resolves
@@ -17,7 +17,7 @@ fn main() {
 
     match current_exe_file_name {
         Some("build") | Some("test") | Some("detect") | Some("publish") => {
-            cnb_runtime_all(detect, build, test, publish, GenericErrorHandler)
+            cnb_runtime_all(detect, build, test, publish, SFPackageErrorHandler)
         }
         _ => cli(),
     }
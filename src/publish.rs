@@ -1,8 +1,128 @@
-use crate::util::config::SFPackageBuildpackConfig;
-use libcnb::{GenericPlatform, PublishContext};
+use crate::util::config::{PackageConfig, SFPackageAppConfig, SFPackageBuildpackConfig};
+use crate::util::logger::{BuildLogger, Logger};
+use crate::util::meta::{publish_package_version, PackageVersionStatus, SFPackageAppMeta};
+use crate::util::project::SfdxProject;
+use crate::{PackageVersionResult, ProcessSfdxClient, SfdxClient};
+use anyhow::anyhow;
+use libcnb::Error::BuildpackError;
+use libcnb::{GenericPlatform, Platform, PlatformEnv, PublishContext};
+use std::path::PathBuf;
+use tempfile::tempdir;
 
+/// # Publish Command
+/// Promote the most recently built beta version of each package to released, once it meets the
+/// promotion requirements, and record it as published in `app-meta.toml`.
 pub fn publish(
-    _context: PublishContext<GenericPlatform, SFPackageBuildpackConfig>,
+    context: PublishContext<GenericPlatform, SFPackageBuildpackConfig>,
 ) -> libcnb::Result<(), anyhow::Error> {
+    let mut logger = BuildLogger::new(true, true);
+    // Publish runs without a layers directory, so sfdx must already be on the PATH.  The client
+    // is given an empty one, deleted afterwards, to keep the hub's key files in.
+    let layers_dir = tempdir().map_err(|e| BuildpackError(e.into()))?;
+    let client =
+        ProcessSfdxClient::new(&layers_dir.path().to_path_buf(), &context.app_dir, &logger);
+    package_publish(context, &client, &mut logger)
+}
+
+/// # Package Publish
+/// Publish each package of the project with the given client.
+pub fn package_publish<C: SfdxClient>(
+    context: PublishContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
+    logger: &mut BuildLogger,
+) -> libcnb::Result<(), anyhow::Error> {
+    let app_dir = &context.app_dir;
+    logger.header("---> Package Publish")?;
+
+    let project = SfdxProject::from_dir(app_dir).map_err(BuildpackError)?;
+    let configs = SFPackageAppConfig::from_dir(app_dir)
+        .package_configs(&project)
+        .map_err(BuildpackError)?;
+
+    for config in configs {
+        publish_package(client, app_dir, config, &context.platform.env(), logger)
+            .map_err(BuildpackError)?;
+    }
+    Ok(())
+}
+
+fn publish_package<C: SfdxClient>(
+    client: &C,
+    app_dir: &PathBuf,
+    config: PackageConfig,
+    env: &PlatformEnv,
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    let app_meta = SFPackageAppMeta::from_dir(app_dir);
+    let package_version = match app_meta
+        .package(&config.name)
+        .and_then(|p| app_meta.latest_package_version(&p.id))
+    {
+        Some(package_version) => package_version,
+        None => {
+            return Err(anyhow!(
+                "no version of package {} found in app-meta.toml.  Run a package build first.",
+                config.name
+            ))
+        }
+    };
+    if let PackageVersionStatus::Published = package_version.status {
+        logger.info(format!(
+            "---> {} version {} is already published",
+            config.name, package_version.number
+        ))?;
+        return Ok(());
+    }
+
+    client.auth(
+        &config.hub_client_id,
+        &config.hub_key_path,
+        &config.hub_instance_url,
+        &config.hub_user,
+        config.hub_alias,
+        env,
+    )?;
+
+    logger.header(format!(
+        "---> Checking promotion requirements of {}",
+        config.name
+    ))?;
+    let report = client.fetch_package_version(&config.hub_user, &package_version.id)?;
+    if report.is_released {
+        logger.info(format!(
+            "---> {} version {} was already promoted",
+            config.name, report.version
+        ))?;
+    } else {
+        check_promotable(&report)?;
+
+        logger.header(format!("---> Promoting {}", config.name))?;
+        client.promote_package_version(&config.hub_user, &package_version.id)?;
+    }
+
+    publish_package_version(app_dir, &package_version.id)?;
+    logger.info(format!(
+        "---> {} version {} ({}) published",
+        config.name, report.version, package_version.id
+    ))?;
     Ok(())
 }
+
+/// The platform promotes only versions that passed its code coverage check, which needs the
+/// version to be built with `code_coverage` set in the package config.
+fn check_promotable(report: &PackageVersionResult) -> Result<(), anyhow::Error> {
+    if report.has_passed_code_coverage_check {
+        return Ok(());
+    }
+    match &report.code_coverage {
+        Some(coverage) => Err(anyhow!(
+            "package version {} cannot be promoted: it failed the code coverage check, covering {}%",
+            report.version,
+            coverage.apex_code_coverage_percentage
+        )),
+        None => Err(anyhow!(
+            "package version {} cannot be promoted: no code coverage was computed for it.  Set code_coverage = true in [package] in app.toml and build it again.",
+            report.version
+        )),
+    }
+}
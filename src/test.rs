@@ -1,10 +1,30 @@
-use crate::util::config::{SFPackageAppConfig, SFPackageBuildpackConfig};
-use crate::{push_source, reset_environment, sfdx_create_org, sfdx_test_apex, BuildLogger, Logger};
+use crate::util::cache::has_layers;
+use crate::util::config::{
+    package_test_config, PackageConfig, PackageTestMode, SFPackageAppConfig,
+    SFPackageBuildpackConfig, TestLevel,
+};
+use crate::util::history::{TestHistory, TestRunRecord};
+use crate::util::meta::{PackageVersionMeta, SFPackageAppMeta};
+use crate::util::pool::ci_scratch_org;
+use crate::util::project::SfdxProject;
+use crate::util::report::{write_report, TestReport};
+use crate::util::scratch_org::ScratchOrg;
+use crate::util::setup::run_setup;
+use crate::util::version::version_key;
+use crate::{
+    install_dependencies, push_source, resolve_install_plan, resolve_tests, test_apex_with_retries,
+    ApexTestRunResult, BuildLogger, Logger, ProcessSfdxClient, SfdxClient,
+};
 use anyhow::anyhow;
 use libcnb::Error::BuildpackError;
 use libcnb::{
     get_lifecycle_mode, GenericPlatform, LifecycleMode, TestContext, TestOutcome, TestResults,
 };
+use std::path::Path;
+
+/// Tests are slower than in the run before when their runtime grows by more than this, unless
+/// `test_slowdown_percent` is configured.
+const DEFAULT_SLOWDOWN_PERCENT: f64 = 50.0;
 
 /// # Execute Tests Command
 /// A full test command differs from unit tests run during the build. Test should involve more
@@ -13,47 +33,55 @@ pub fn test(
     context: TestContext<GenericPlatform, SFPackageBuildpackConfig>,
 ) -> libcnb::Result<TestOutcome, anyhow::Error> {
     let mut logger = BuildLogger::new(true, true);
+    let client = ProcessSfdxClient::new(&context.layers_dir, &context.app_dir, &logger);
 
     let mode = get_lifecycle_mode().unwrap_or(LifecycleMode::Dev);
     match mode {
-        LifecycleMode::Dev => dev_test(context, &mut logger),
-        LifecycleMode::CI => ci_test(context, &mut logger),
-        LifecycleMode::Package => package_test(context, &mut logger),
+        LifecycleMode::Dev => dev_test(context, &client, &mut logger),
+        LifecycleMode::CI => ci_test(context, &client, &mut logger),
+        LifecycleMode::Package => package_test(context, &client, &mut logger),
         _ => Ok(TestOutcome::Pass(TestResults::new())),
     }
 }
 
 /// # Dev Mode Test
 /// Execute tests in an existing scratch org, formatted for interactive developer consumption.
-fn dev_test(
+pub fn dev_test<C: SfdxClient>(
     context: TestContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
     logger: &mut BuildLogger,
 ) -> libcnb::Result<TestOutcome, anyhow::Error> {
     let config = SFPackageAppConfig::from_dir(&context.app_dir).dev;
-    match sfdx_test_apex(
-        &context.layers_dir,
-        &context.app_dir,
+    let tests = match resolve_tests(&context.app_dir, &config.tests, TestLevel::RunLocalTests)
+        .map_err(BuildpackError)?
+    {
+        Some(tests) => tests,
+        None => return no_affected_tests(logger),
+    };
+    match test_apex_with_retries(
+        client,
+        logger,
         &config.org_alias,
-        config.test_results_path,
-        config.test_results_format,
+        &tests,
         config.op_wait_seconds,
+        &config.flaky,
     ) {
         Ok(result) => {
+            write_report(
+                &TestReport::new(&result),
+                &context.app_dir,
+                &config.test_results_path,
+                &config.test_results_format,
+                logger,
+            )?;
+            compare_with_history(
+                logger,
+                &context.layers_dir,
+                &result,
+                config.test_slowdown_percent,
+            )?;
             let outcome = result.into();
-            match &outcome {
-                TestOutcome::Pass(results) => {
-                    logger.info("Test run succeeded")?;
-                    logger.info(format!("{} tests passed", results.passed.len()))?;
-                    logger.info(format!("{} tests failed", results.failed.len()))?;
-                    logger.info(format!("{} tests ignored", results.ignored.len()))?;
-                }
-                TestOutcome::Fail(results) => {
-                    logger.info("Test run completed with failures")?;
-                    logger.info(format!("{} tests passed", results.passed.len()))?;
-                    logger.info(format!("{} tests failed", results.failed.len()))?;
-                    logger.info(format!("{} tests ignored", results.ignored.len()))?;
-                }
-            }
+            log_outcome(logger, &outcome)?;
             Ok(outcome)
         }
         Err(e) => libcnb::Result::Err(BuildpackError(e)),
@@ -61,98 +89,332 @@ fn dev_test(
 }
 
 /// # CI Mode Test
-/// Execute tests for a CI container, creating and cleaning up scratch org.
-fn ci_test(
+/// Execute tests for a CI container, creating or checking out of the pool a scratch org, and
+/// cleaning it up.
+pub fn ci_test<C: SfdxClient>(
     context: TestContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
     logger: &mut BuildLogger,
 ) -> libcnb::Result<TestOutcome, anyhow::Error> {
     let app_dir = &context.app_dir;
-    let layers_dir = &context.layers_dir;
     let config = SFPackageAppConfig::from_dir(app_dir).ci;
+    let tests = match resolve_tests(app_dir, &config.tests, TestLevel::RunLocalTests)
+        .map_err(BuildpackError)?
+    {
+        Some(tests) => tests,
+        None => return no_affected_tests(logger),
+    };
 
-    let mut abort = false;
-    match sfdx_create_org(
-        layers_dir,
+    // Deleted when dropped, should the run fail before it is deleted below.
+    let org = match ci_scratch_org(client, logger, &context.layers_dir, &config) {
+        Ok((org, output)) => {
+            if let Some(output) = output {
+                logger.output("Preparing artifacts", output)?;
+            }
+            org
+        }
+        Err(e) => return Err(BuildpackError(logger.fail("preparing artifacts", e))),
+    };
+    let org_alias = org.alias().to_string();
+    if let Err(e) = install_dependencies(
+        client,
         app_dir,
         &config.hub_user,
-        &config.org_def_path,
-        config.org_duration_days,
-        &config.org_alias,
+        &org_alias,
+        config.op_wait_seconds,
+        logger,
     ) {
-        Ok(output) => {
-            logger.output("Preparing artifacts", output)?;
-        }
-        Err(e) => {
-            logger.error("preparing artifacts", e)?;
-            abort = true;
-        }
+        return Err(BuildpackError(logger.fail("installing dependencies", e)));
     }
 
-    let result = match abort {
-        true => Err(BuildpackError(anyhow!(
-            "no tests were executed.  Environment setup failed."
-        ))),
-        false => {
-            logger.header("---> Preparing artifacts")?;
-            match push_source(
-                layers_dir,
+    logger.header("---> Preparing artifacts")?;
+    let result = match push_source(client, logger, &org_alias, config.op_wait_seconds) {
+        Ok(output) => {
+            logger.output("---> Preparing artifacts", output)?;
+            run_setup(client, logger, &config.hub_user, &org_alias, &config.setup)
+                .map_err(BuildpackError)?;
+
+            match test_apex_with_retries(
+                client,
                 logger,
-                app_dir,
-                &config.org_alias,
+                &org_alias,
+                &tests,
                 config.op_wait_seconds,
+                &config.flaky,
             ) {
-                Ok(output) => {
-                    logger.output("---> Preparing artifacts", output)?;
-
-                    match sfdx_test_apex(
-                        layers_dir,
+                Ok(result) => {
+                    write_report(
+                        &TestReport::new(&result),
                         app_dir,
-                        &config.org_alias,
-                        config.test_results_path,
-                        config.test_results_format,
-                        config.op_wait_seconds,
-                    ) {
-                        Ok(result) => {
-                            let outcome = result.into();
-                            match &outcome {
-                                TestOutcome::Pass(results) => {
-                                    logger.info("Test run succeeded")?;
-                                    logger
-                                        .info(format!("{} tests passed", results.passed.len()))?;
-                                    logger
-                                        .info(format!("{} tests failed", results.failed.len()))?;
-                                    logger
-                                        .info(format!("{} tests ignored", results.ignored.len()))?;
-                                }
-                                TestOutcome::Fail(results) => {
-                                    logger.info("Test run completed with failures")?;
-                                    logger
-                                        .info(format!("{} tests passed", results.passed.len()))?;
-                                    logger
-                                        .info(format!("{} tests failed", results.failed.len()))?;
-                                    logger
-                                        .info(format!("{} tests ignored", results.ignored.len()))?;
-                                }
-                            }
-                            Ok(outcome)
-                        }
-                        Err(e) => libcnb::Result::Err(BuildpackError(e)),
-                    }
+                        &config.test_results_path,
+                        &config.test_results_format,
+                        logger,
+                    )?;
+                    compare_with_history(
+                        logger,
+                        &context.layers_dir,
+                        &result,
+                        config.test_slowdown_percent,
+                    )?;
+                    let coverage = result.check_coverage(&org_alias, &config.coverage);
+                    let outcome = result.into();
+                    log_outcome(logger, &outcome)?;
+                    check_coverage(logger, outcome, coverage)
                 }
-                Err(e) => Err(BuildpackError(anyhow!(e))),
+                Err(e) => Err(BuildpackError(e)),
             }
         }
+        Err(e) => Err(BuildpackError(e)),
     };
 
-    reset_environment(layers_dir, app_dir, &config.hub_user, &config.org_alias)?;
+    logger.header("---> Resetting environment")?;
+    org.delete().map_err(BuildpackError)?;
     result
 }
 
 /// # Package Mode Test
-/// TODO Should involve installation of a built package artifact and suitable tests to verify it.
-fn package_test(
-    _context: TestContext<GenericPlatform, SFPackageBuildpackConfig>,
-    _logger: &mut BuildLogger,
+/// Install the most recently built versions of the project's packages into a new, non-namespaced
+/// scratch org and run the tests they ship with against the installed packages.  Dependencies are
+/// installed first.  In upgrade mode the last released versions are installed before the new
+/// ones, so that the new versions are tested as upgrades.  The org is deleted afterwards.
+pub fn package_test<C: SfdxClient>(
+    context: TestContext<GenericPlatform, SFPackageBuildpackConfig>,
+    client: &C,
+    logger: &mut BuildLogger,
 ) -> libcnb::Result<TestOutcome, anyhow::Error> {
+    let app_dir = &context.app_dir;
+    let project = SfdxProject::from_dir(app_dir).map_err(BuildpackError)?;
+    let app_config = SFPackageAppConfig::from_dir(app_dir);
+    let configs = app_config
+        .package_configs(&project)
+        .map_err(BuildpackError)?;
+    let config = package_test_config(&configs).map_err(BuildpackError)?;
+
+    let tests = match resolve_tests(app_dir, &config.tests, TestLevel::RunAllTestsInOrg)
+        .map_err(BuildpackError)?
+    {
+        Some(tests) => tests,
+        None => return no_affected_tests(logger),
+    };
+
+    let app_meta = SFPackageAppMeta::from_dir(app_dir);
+    let mut package_versions = vec![];
+    for package in configs.iter() {
+        match app_meta
+            .package(&package.name)
+            .and_then(|p| app_meta.latest_package_version(&p.id))
+        {
+            Some(package_version) => package_versions.push((package, package_version)),
+            None => {
+                return Err(BuildpackError(anyhow!(
+                    "no version of package {} found in app-meta.toml.  Run a package build first.",
+                    package.name
+                )))
+            }
+        }
+    }
+
+    logger.info("---> resolving dependencies")?;
+    let names: Vec<String> = configs.iter().map(|c| c.name.clone()).collect();
+    let mut installs = vec![];
+    for install in resolve_install_plan(client, app_dir, &config.hub_user, &names, true)
+        .map_err(BuildpackError)?
+    {
+        if !names.contains(&install.package) {
+            let installation_key = app_config.installation_key(&install);
+            installs.push(("dependency", install.version_id, installation_key));
+        }
+    }
+    if let PackageTestMode::Upgrade = config.test_mode {
+        for (package, package_version) in package_versions.iter() {
+            logger.info(format!(
+                "---> resolving last released version of {}",
+                package.name
+            ))?;
+            let ancestor_id =
+                resolve_ancestor_version(client, &config.hub_user, &app_meta, package_version)
+                    .map_err(BuildpackError)?;
+            installs.push((
+                "released version",
+                ancestor_id,
+                package.installation_key.clone(),
+            ));
+        }
+    }
+    for (package, package_version) in package_versions.iter() {
+        installs.push((
+            "new version",
+            package_version.id.clone(),
+            package.installation_key.clone(),
+        ));
+    }
+
+    logger.header("---> Creating environment")?;
+    logger.info("---> creating non-namespaced scratch org")?;
+    let org = match ScratchOrg::create(
+        client,
+        &config.hub_user,
+        &config.org_def_path,
+        config.org_duration_days,
+        &config.org_alias,
+        true,
+    ) {
+        Ok((org, _)) => org,
+        Err(e) => {
+            return Err(BuildpackError(
+                e.context("no tests were executed.  Environment setup failed."),
+            ))
+        }
+    };
+    let org_alias = org.alias().to_string();
+
+    logger.header("---> Installing packages")?;
+    let result = match install_packages(client, &config, &org_alias, &installs, logger) {
+        Ok(()) => {
+            logger.header("---> Running tests")?;
+            match client.test_apex(&org_alias, &tests, config.op_wait_seconds) {
+                Ok(result) => {
+                    let mut report = TestReport::new(&result);
+                    for (package, package_version) in package_versions.iter() {
+                        report = report
+                            .property(format!("{}.version", package.name), &package_version.number)
+                            .property(format!("{}.versionId", package.name), &package_version.id);
+                    }
+                    write_report(
+                        &report,
+                        app_dir,
+                        &config.test_results_path,
+                        &config.test_results_format,
+                        logger,
+                    )?;
+                    compare_with_history(
+                        logger,
+                        &context.layers_dir,
+                        &result,
+                        config.test_slowdown_percent,
+                    )?;
+                    let coverage = result.check_coverage(&org_alias, &config.coverage);
+                    let outcome = result.into();
+                    log_outcome(logger, &outcome)?;
+                    check_coverage(logger, outcome, coverage)
+                }
+                Err(e) => Err(BuildpackError(e)),
+            }
+        }
+        Err(e) => Err(BuildpackError(e)),
+    };
+
+    logger.header("---> Resetting environment")?;
+    org.delete().map_err(BuildpackError)?;
+    result
+}
+
+/// Install each (description, subscriber package version id, installation key) in order into the
+/// org with the given alias.
+fn install_packages<C: SfdxClient>(
+    client: &C,
+    config: &PackageConfig,
+    org_alias: &str,
+    installs: &[(&str, String, String)],
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    for (description, id, installation_key) in installs {
+        logger.info(format!("---> installing {} {}", description, id))?;
+        let output =
+            client.install_package(org_alias, id, installation_key, config.op_wait_seconds)?;
+        logger.output(format!("installing {}", id), output)?;
+    }
+    Ok(())
+}
+
+/// Find the version to upgrade from: the last version published by this buildpack, or failing
+/// that, the highest released version known to the Dev Hub.
+fn resolve_ancestor_version<C: SfdxClient>(
+    client: &C,
+    hub_user: &str,
+    app_meta: &SFPackageAppMeta,
+    package_version: &PackageVersionMeta,
+) -> Result<String, anyhow::Error> {
+    if let Some(published) = app_meta.latest_published_package_version(&package_version.package_id)
+    {
+        return Ok(published.id.clone());
+    }
+
+    let versions = client.list_package_versions(hub_user, &package_version.package_id, true)?;
+    versions
+        .into_iter()
+        .filter(|v| v.subscriber_package_version_id != package_version.id)
+        .max_by_key(|v| version_key(&v.version))
+        .map(|v| v.subscriber_package_version_id)
+        .ok_or_else(|| {
+            anyhow!(
+                "no released version of package {} found to upgrade from",
+                package_version.package_id
+            )
+        })
+}
+
+/// Record a test run in the test history, and report what changed since the run before.  There
+/// is no history without a layers directory to keep it in.
+fn compare_with_history(
+    logger: &mut BuildLogger,
+    layers_dir: &Path,
+    result: &ApexTestRunResult,
+    slowdown_percent: Option<f64>,
+) -> anyhow::Result<()> {
+    if !has_layers(layers_dir) {
+        return Ok(());
+    }
+    match TestHistory::new(layers_dir).record(result) {
+        Ok(Some(previous)) => {
+            let diff = TestRunRecord::from(result).diff(
+                &previous,
+                slowdown_percent.unwrap_or(DEFAULT_SLOWDOWN_PERCENT),
+            );
+            logger.info(format!(
+                "---> changes since test run {}\n{}",
+                previous.test_run_id, diff
+            ))
+        }
+        Ok(None) => logger.info("---> no earlier test run to compare with"),
+        Err(e) => logger.warning("Test history", format!("failed to record test run: {}", e)),
+    }
+}
+
+/// The outcome of a test run selecting the tests affected by changes, when none are.
+fn no_affected_tests(logger: &mut BuildLogger) -> libcnb::Result<TestOutcome, anyhow::Error> {
+    logger.info("---> no apex tests affected by changes")?;
     Ok(TestOutcome::Pass(TestResults::new()))
 }
+
+/// Fail a passing test run that did not reach its coverage thresholds.  Test failures are
+/// reported first.
+fn check_coverage(
+    logger: &mut BuildLogger,
+    outcome: TestOutcome,
+    coverage: Result<(), crate::BuildpackError>,
+) -> libcnb::Result<TestOutcome, anyhow::Error> {
+    match (&outcome, coverage) {
+        (TestOutcome::Pass(_), Err(e)) => {
+            Err(BuildpackError(logger.fail("Checking coverage", e.into())))
+        }
+        _ => Ok(outcome),
+    }
+}
+
+fn log_outcome(logger: &mut BuildLogger, outcome: &TestOutcome) -> anyhow::Result<()> {
+    let results = match outcome {
+        TestOutcome::Pass(results) => {
+            logger.info("Test run succeeded")?;
+            results
+        }
+        TestOutcome::Fail(results) => {
+            logger.info("Test run completed with failures")?;
+            results
+        }
+    };
+    logger.info(format!("{} tests passed", results.passed.len()))?;
+    logger.info(format!("{} tests failed", results.failed.len()))?;
+    logger.info(format!("{} tests ignored", results.ignored.len()))?;
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use libcnb::{read_file_to_string, write_toml_file};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// How long to wait for a build holding the lock of a layer.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A lock held for longer than this was left by a build that died, and is taken over.
+const STALE_LOCK_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// Whether `layers_dir` names a layers directory.  `cli` commands run without `--layers` have
+/// none, and keep nothing between runs rather than writing their layers into the current
+/// directory.
+pub(crate) fn has_layers(layers_dir: &Path) -> bool {
+    !layers_dir.as_os_str().is_empty()
+}
+
+/// # Cache Layer
+/// A layer the buildpack keeps files in from one build to the next, such as the `test-history`
+/// and `org-pool` layers.  It is cached, but neither exported to the image nor made available to
+/// later buildpacks.
+pub(crate) struct CacheLayer {
+    dir: PathBuf,
+}
+
+impl CacheLayer {
+    pub fn new(layers_dir: &Path, name: &str) -> Self {
+        CacheLayer {
+            dir: layers_dir.join(name),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Read a toml file of the layer, None when there is none yet.  A file that cannot be read
+    /// is an error rather than none, so that what it holds is not overwritten.
+    pub fn read<T: DeserializeOwned>(&self, file_name: &str) -> Result<Option<T>, anyhow::Error> {
+        let file = self.dir.join(file_name);
+        if !file.exists() {
+            return Ok(None);
+        }
+        let text = read_file_to_string(&file)?;
+        let value = toml::from_str(&text)
+            .with_context(|| format!("failed to read {}", file.to_string_lossy()))?;
+        Ok(Some(value))
+    }
+
+    /// Write a toml file of the layer, creating the layer if needed.
+    pub fn write<T: Serialize>(&self, file_name: &str, value: &T) -> Result<(), anyhow::Error> {
+        self.create()?;
+        write_toml_file(value, self.dir.join(file_name))?;
+        Ok(())
+    }
+
+    /// Create the layer directory, and mark it as cached so that it is restored for the next
+    /// build.
+    pub fn create(&self) -> Result<(), anyhow::Error> {
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.with_extension("toml"), "[types]\ncache = true\n")?;
+        Ok(())
+    }
+
+    /// Take the lock of the layer, waiting while another build or `cli` command holds it, so that
+    /// files read and written back while it is held are not changed in between.  The lock is
+    /// released when dropped.
+    pub fn lock(&self) -> Result<CacheLayerLock, anyhow::Error> {
+        self.create()?;
+        let path = self.dir.join(".lock");
+        let started = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(CacheLayerLock { path }),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    let age = fs::metadata(&path)
+                        .and_then(|m| m.modified())
+                        .ok()
+                        .and_then(|modified| modified.elapsed().ok());
+                    if age.map(|age| age > STALE_LOCK_AGE).unwrap_or(false) {
+                        let _ = fs::remove_file(&path);
+                    } else if started.elapsed() > LOCK_TIMEOUT {
+                        return Err(anyhow!(
+                            "timed out waiting for the lock {}.  Remove it if no other build is running.",
+                            path.to_string_lossy()
+                        ));
+                    } else {
+                        thread::sleep(Duration::from_millis(250));
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Remove the layer and all it holds.
+    pub fn remove(&self) -> Result<(), anyhow::Error> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
+
+/// The lock of a cache layer, see `CacheLayer::lock`.
+pub(crate) struct CacheLayerLock {
+    path: PathBuf,
+}
+
+impl Drop for CacheLayerLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_releases_its_lock_when_dropped() {
+        let layers_dir = tempdir().unwrap();
+        let layer = CacheLayer::new(layers_dir.path(), "pool");
+        let lock_file = layers_dir.path().join("pool/.lock");
+
+        let lock = layer.lock().unwrap();
+        assert!(lock_file.is_file());
+        drop(lock);
+        assert!(!lock_file.exists());
+        drop(layer.lock().unwrap());
+    }
+
+    #[test]
+    fn it_fails_on_unreadable_files() {
+        let layers_dir = tempdir().unwrap();
+        let layer = CacheLayer::new(layers_dir.path(), "pool");
+        assert_eq!(layer.read::<toml::Value>("pool.toml").unwrap(), None);
+
+        layer.create().unwrap();
+        fs::write(layer.dir().join("pool.toml"), "orgs = [").unwrap();
+        assert!(layer.read::<toml::Value>("pool.toml").is_err());
+    }
+}
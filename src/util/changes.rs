@@ -0,0 +1,141 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::anyhow;
+
+use crate::util::config::read_package_directories;
+
+/// The Apex test classes affected by the files changed in `app_dir` since `git_ref`, including
+/// files not yet committed.
+pub(crate) fn affected_test_classes(
+    app_dir: &Path,
+    git_ref: &str,
+) -> Result<Vec<String>, anyhow::Error> {
+    let mut changed = git_lines(app_dir, &["diff", "--name-only", "--relative", git_ref])?;
+    changed.extend(git_lines(
+        app_dir,
+        &["ls-files", "--others", "--exclude-standard"],
+    )?);
+    let changed: Vec<PathBuf> = changed.iter().map(PathBuf::from).collect();
+    Ok(tests_affected_by(app_dir, &changed))
+}
+
+/// The test classes that changed, or that refer to a component that changed.  Components are
+/// named by their file name up to the first `.`, so that a change to `Greeter.cls-meta.xml` is a
+/// change to `Greeter`.  Apex is case insensitive, and so is the match.
+pub(crate) fn tests_affected_by(app_dir: &Path, changed: &[PathBuf]) -> Vec<String> {
+    let components: HashSet<String> = changed
+        .iter()
+        .filter_map(|path| path.file_name())
+        .filter_map(|name| {
+            name.to_string_lossy()
+                .split('.')
+                .next()
+                .map(str::to_lowercase)
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    let mut test_classes = vec![];
+    if let Ok(dirs) = read_package_directories(&app_dir.to_path_buf(), true, true) {
+        for dir in dirs.iter() {
+            find_test_classes(&app_dir.join(dir), &mut test_classes);
+        }
+    }
+    test_classes.sort();
+    test_classes.dedup_by(|a, b| a.0 == b.0);
+
+    test_classes
+        .into_iter()
+        .filter(|(name, source)| {
+            components.contains(&name.to_lowercase())
+                || identifiers(source).any(|id| components.contains(&id))
+        })
+        .map(|(name, _)| name)
+        .collect()
+}
+
+fn git_lines(app_dir: &Path, args: &[&str]) -> Result<Vec<String>, anyhow::Error> {
+    let output = Command::new("git")
+        .current_dir(app_dir)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Collect the name and source of each `@IsTest` class under `dir`.
+fn find_test_classes(dir: &Path, test_classes: &mut Vec<(String, String)>) {
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                find_test_classes(&path, test_classes);
+            } else if path.extension().map(|e| e == "cls").unwrap_or(false) {
+                if let (Some(name), Ok(source)) = (path.file_stem(), fs::read_to_string(&path)) {
+                    if source.to_lowercase().contains("@istest") {
+                        test_classes.push((name.to_string_lossy().to_string(), source));
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn identifiers(source: &str) -> impl Iterator<Item = String> + '_ {
+    source
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|id| !id.is_empty())
+        .map(str::to_lowercase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn affected(changed: &[&str]) -> Vec<String> {
+        let app_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sf-package");
+        let changed: Vec<PathBuf> = changed.iter().map(PathBuf::from).collect();
+        tests_affected_by(&app_dir, &changed)
+    }
+
+    #[test]
+    fn it_finds_tests_referring_to_changed_classes() {
+        assert_eq!(
+            affected(&["force-app/main/default/classes/Test.cls"]),
+            vec!["TestTests"]
+        );
+        assert_eq!(
+            affected(&["force-app/main/default/classes/SomeCallable.cls-meta.xml"]),
+            vec!["SomeCallableTest"]
+        );
+    }
+
+    #[test]
+    fn it_finds_changed_tests() {
+        assert_eq!(
+            affected(&[
+                "force-app/main/default/classes/TestTests.cls",
+                "force-app/main/default/classes/SomeCallableTest.cls"
+            ]),
+            vec!["SomeCallableTest", "TestTests"]
+        );
+    }
+
+    #[test]
+    fn it_finds_no_tests_for_other_changes() {
+        assert!(affected(&["README.md", "config/project-scratch-def.json"]).is_empty());
+    }
+}
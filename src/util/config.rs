@@ -1,46 +1,39 @@
-use json::JsonValue;
+use crate::client::CliBackend;
+use crate::util::dependencies::{DependencyGraph, PlannedInstall};
+use crate::util::project::SfdxProject;
+use crate::util::version::VersionBump;
+use anyhow::anyhow;
 use libcnb::read_file_to_string;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use toml::value::Table;
 
 pub fn read_package_directories(
     app_dir: &PathBuf,
     existing: bool,
     shallow: bool,
-) -> Option<Vec<PathBuf>> {
-    let project_file_json = read_project_file_json(app_dir);
+) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let project = SfdxProject::from_dir(app_dir)?;
 
     let mut paths: HashMap<&OsStr, PathBuf> = HashMap::new();
-    match &project_file_json["packageDirectories"] {
-        json::JsonValue::Array(v) => {
-            for e in v {
-                match e {
-                    json::JsonValue::Object(obj) => match &obj["path"] {
-                        json::JsonValue::Short(j) => {
-                            let absolute_path = app_dir.join(j.to_string());
-                            if !existing || absolute_path.exists() {
-                                let path = Path::new(j.as_str());
-                                if shallow {
-                                    let root = path.iter().next().unwrap();
-                                    paths.insert(root, path.to_path_buf().clone());
-                                } else {
-                                    paths.insert(path.as_os_str(), path.to_path_buf().clone());
-                                }
-                            }
-                        }
-                        _ => println!("{:?}", e),
-                    },
-                    _ => println!("{:?}", e),
+    for dir in project.package_directories.iter() {
+        let absolute_path = app_dir.join(&dir.path);
+        if !existing || absolute_path.exists() {
+            let path = Path::new(dir.path.as_str());
+            if shallow {
+                if let Some(root) = path.iter().next() {
+                    paths.insert(root, path.to_path_buf());
                 }
+            } else {
+                paths.insert(path.as_os_str(), path.to_path_buf());
             }
-            Some(paths.into_iter().map(|(_key, p)| p).collect())
         }
-        _ => None,
     }
+    Ok(paths.into_iter().map(|(_key, p)| p).collect())
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -49,15 +42,66 @@ pub struct SFPackageBuildpackConfig {
 }
 
 /// Struct containing the url and sha256 checksum for a downloadable sfdx-runtime-java-runtime.
-/// This is used in both `buildpack.toml` and the `layer.toml` but with different keys.
-#[derive(Debug, Deserialize, Serialize)]
+/// This is used in both `buildpack.toml` and the `layer.toml` but with different keys.  The
+/// `[runtime]` table of `app.toml` overrides the fields it sets.  `url` may also be a path to a
+/// local tarball, relative to the app.  A runtime with a `sha256` is pinned: it is only
+/// installed when its tarball matches, and a cached install is reused without going online.
+/// `version` is the version the pinned runtime must report once installed; it needs a `sha256`
+/// to pin it.  `cli` selects the `CliBackend`, which is otherwise detected from the installed
+/// CLI.  `plugins` are CLI plugins installed into the layer with the runtime, each pinned as
+/// `name@version`.  A plugin that is not signed is only installed when its name is listed in
+/// `unsigned_plugins`.
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct SFDXRuntimeConfig {
+    #[serde(default)]
     pub url: String,
+    #[serde(default)]
     pub manifest: String,
+    #[serde(default)]
     pub sha256: String,
+    #[serde(default)]
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cli: Option<CliBackend>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub plugins: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub unsigned_plugins: Vec<String>,
 }
 
 impl SFDXRuntimeConfig {
+    pub fn is_pinned(&self) -> bool {
+        !self.sha256.is_empty()
+    }
+
+    /// This runtime with the fields set in `overrides` replaced.
+    pub fn with_overrides(&self, overrides: &SFDXRuntimeConfig) -> Self {
+        let pick = |value: &String, default: &String| {
+            if value.is_empty() {
+                default.clone()
+            } else {
+                value.clone()
+            }
+        };
+        SFDXRuntimeConfig {
+            url: pick(&overrides.url, &self.url),
+            manifest: pick(&overrides.manifest, &self.manifest),
+            sha256: pick(&overrides.sha256, &self.sha256),
+            version: pick(&overrides.version, &self.version),
+            cli: overrides.cli.or(self.cli),
+            plugins: if overrides.plugins.is_empty() {
+                self.plugins.clone()
+            } else {
+                overrides.plugins.clone()
+            },
+            unsigned_plugins: if overrides.unsigned_plugins.is_empty() {
+                self.unsigned_plugins.clone()
+            } else {
+                overrides.unsigned_plugins.clone()
+            },
+        }
+    }
+
     /// Build a `Runtime` from the `layer.toml`'s `metadata` keys.
     pub fn from_runtime_layer(metadata: &Table) -> Self {
         let empty_string = toml::Value::String("".to_string());
@@ -80,11 +124,39 @@ impl SFDXRuntimeConfig {
             .as_str()
             .unwrap_or("")
             .to_string();
+        let version = metadata
+            .get("runtime_version")
+            .unwrap_or(&empty_string)
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        let cli = metadata
+            .get("runtime_cli")
+            .and_then(|value| value.as_str())
+            .and_then(|value| value.parse().ok());
+        let strings = |key: &str| -> Vec<String> {
+            metadata
+                .get(key)
+                .and_then(|value| value.as_array())
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|value| value.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let plugins = strings("runtime_plugins");
+        let unsigned_plugins = strings("runtime_unsigned_plugins");
 
         SFDXRuntimeConfig {
             url,
             manifest,
             sha256,
+            version,
+            cli,
+            plugins,
+            unsigned_plugins,
         }
     }
 }
@@ -92,9 +164,16 @@ impl SFDXRuntimeConfig {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct SFPackageAppConfig {
     pub default: DefaultConfig,
+    #[serde(default)]
     pub package: PackageConfig,
+    #[serde(default)]
+    pub packages: Vec<PackageConfig>,
     pub dev: DevConfig,
     pub ci: CIConfig,
+    #[serde(default)]
+    pub runtime: SFDXRuntimeConfig,
+    #[serde(default)]
+    pub dependencies: Vec<DependencyConfig>,
 }
 
 impl Default for SFPackageAppConfig {
@@ -104,8 +183,11 @@ impl Default for SFPackageAppConfig {
         SFPackageAppConfig {
             default: default_config,
             package: PackageConfig::default(),
+            packages: vec![],
             dev: DevConfig::default(),
             ci: CIConfig::default(),
+            runtime: SFDXRuntimeConfig::default(),
+            dependencies: vec![],
         }
     }
 }
@@ -116,6 +198,9 @@ impl SFPackageAppConfig {
         if let Ok(file_text) = read_file_to_string(file.as_path()) {
             let mut config: SFPackageAppConfig = toml::from_str(&file_text).unwrap();
             config.package.set_defaults(&config.default);
+            for package in config.packages.iter_mut() {
+                package.set_defaults(&config.default);
+            }
             config.dev.set_defaults(&config.default);
             config.ci.set_defaults(&config.default);
             config
@@ -123,6 +208,72 @@ impl SFPackageAppConfig {
             SFPackageAppConfig::default()
         }
     }
+
+    /// The installation key of a dependency to install, empty when none is configured.
+    pub fn installation_key(&self, install: &PlannedInstall) -> String {
+        self.dependencies
+            .iter()
+            .find(|d| d.package == install.package || d.package == install.version_id)
+            .map(|d| d.installation_key.clone())
+            .unwrap_or_default()
+    }
+
+    /// The packages to build in Package mode, in dependency order.  These are the `[[packages]]`
+    /// entries when given, otherwise the `[package]` section, otherwise every non-default package
+    /// directory in `sfdx-project.json` that names a package, configured like `[package]`.
+    pub fn package_configs(
+        &self,
+        project: &SfdxProject,
+    ) -> Result<Vec<PackageConfig>, anyhow::Error> {
+        let mut configs: Vec<PackageConfig> = if !self.packages.is_empty() {
+            self.packages.clone()
+        } else if !self.package.name.is_empty() {
+            vec![self.package.clone()]
+        } else {
+            project
+                .package_directories
+                .iter()
+                .filter(|d| d.default != Some(true))
+                .filter_map(|d| {
+                    d.package.as_ref().map(|name| {
+                        let mut config = self.package.clone();
+                        config.name = name.clone();
+                        config.root = d.path.clone();
+                        config
+                    })
+                })
+                .collect()
+        };
+        // Version names and numbers not set in app.toml come from sfdx-project.json.
+        for config in configs.iter_mut() {
+            if let Some(dir) = project.package_directory(&config.name) {
+                if config.version_name.is_empty() {
+                    config.version_name = dir.version_name.clone().unwrap_or_default();
+                }
+                if config.version_number.is_empty() {
+                    config.version_number = dir.version_number.clone().unwrap_or_default();
+                }
+            }
+        }
+
+        let names: Vec<String> = configs.iter().map(|c| c.name.clone()).collect();
+        let order = DependencyGraph::from_project(project).build_order(&names)?;
+        Ok(order
+            .iter()
+            .filter_map(|name| configs.iter().find(|c| &c.name == name).cloned())
+            .collect())
+    }
+}
+
+/// A package the project depends on, configured in a `[[dependencies]]` entry of `app.toml`.
+/// `package` is its name or alias in `sfdx-project.json`, or the id of the version installed.
+/// Versions that have an installation key are installed with `installation_key`.
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
+pub struct DependencyConfig {
+    #[serde(default)]
+    pub package: String,
+    #[serde(default)]
+    pub installation_key: String,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -157,7 +308,7 @@ impl Default for DefaultConfig {
     }
 }
 
-#[derive(Deserialize, Debug, Serialize, Default)]
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
 pub struct PackageConfig {
     #[serde(default)]
     pub name: String,
@@ -188,11 +339,31 @@ pub struct PackageConfig {
     #[serde(default)]
     pub installation_key: String,
     #[serde(default)]
+    pub code_coverage: bool,
+    #[serde(default)]
     pub version_name: String,
     #[serde(default)]
     pub version_number: String,
     #[serde(default)]
+    pub bump: VersionBump,
+    #[serde(default)]
     pub op_wait_seconds: i32,
+    #[serde(default)]
+    pub org_alias: String,
+    #[serde(default)]
+    pub org_duration_days: i32,
+    #[serde(default)]
+    pub test_results_path: Option<String>,
+    #[serde(default)]
+    pub test_results_format: TestResultsFormat,
+    #[serde(default)]
+    pub test_slowdown_percent: Option<f64>,
+    #[serde(flatten)]
+    pub coverage: CoverageThresholds,
+    #[serde(default)]
+    pub tests: TestSelection,
+    #[serde(default)]
+    pub test_mode: PackageTestMode,
 }
 
 impl PackageConfig {
@@ -224,7 +395,65 @@ impl PackageConfig {
         if self.root.is_empty() {
             self.root = "force-app".to_string();
         }
+        if self.org_alias.is_empty() {
+            self.org_alias = "package".to_string();
+        }
+        if self.org_duration_days <= 0 {
+            self.org_duration_days = 1;
+        }
+    }
+}
+
+/// The config of the Package mode test, which installs all the packages into one scratch org and
+/// runs one test run.  The packages must agree on the settings of that org and run.
+pub fn package_test_config(configs: &[PackageConfig]) -> Result<PackageConfig, anyhow::Error> {
+    let first = match configs.first() {
+        Some(first) => first,
+        None => {
+            return Err(anyhow!(
+                "no packages to test.  Configure [package] or [[packages]] in app.toml."
+            ))
+        }
+    };
+    for config in configs.iter().skip(1) {
+        let differences = [
+            ("hub_user", config.hub_user != first.hub_user),
+            ("org_def_path", config.org_def_path != first.org_def_path),
+            ("org_alias", config.org_alias != first.org_alias),
+            (
+                "org_duration_days",
+                config.org_duration_days != first.org_duration_days,
+            ),
+            (
+                "op_wait_seconds",
+                config.op_wait_seconds != first.op_wait_seconds,
+            ),
+            (
+                "test_results_path",
+                config.test_results_path != first.test_results_path,
+            ),
+            (
+                "test_results_format",
+                config.test_results_format != first.test_results_format,
+            ),
+            (
+                "test_slowdown_percent",
+                config.test_slowdown_percent != first.test_slowdown_percent,
+            ),
+            ("coverage thresholds", config.coverage != first.coverage),
+            ("tests", config.tests != first.tests),
+            ("test_mode", config.test_mode != first.test_mode),
+        ];
+        if let Some((setting, _)) = differences.iter().find(|(_, differs)| *differs) {
+            return Err(anyhow!(
+                "packages {} and {} configure {} differently.  They are tested together in one scratch org, so set it the same for each.",
+                first.name,
+                config.name,
+                setting
+            ));
+        }
     }
+    Ok(first.clone())
 }
 
 #[derive(Deserialize, Debug, Serialize, Default)]
@@ -253,6 +482,14 @@ pub struct DevConfig {
     pub test_results_path: Option<String>,
     #[serde(default)]
     pub test_results_format: TestResultsFormat,
+    #[serde(default)]
+    pub test_slowdown_percent: Option<f64>,
+    #[serde(default)]
+    pub tests: TestSelection,
+    #[serde(flatten)]
+    pub flaky: FlakyTests,
+    #[serde(default)]
+    pub setup: Vec<SetupStep>,
 }
 
 impl DevConfig {
@@ -308,6 +545,18 @@ pub struct CIConfig {
     pub test_results_path: Option<String>,
     #[serde(default)]
     pub test_results_format: TestResultsFormat,
+    #[serde(default)]
+    pub test_slowdown_percent: Option<f64>,
+    #[serde(flatten)]
+    pub coverage: CoverageThresholds,
+    #[serde(default)]
+    pub tests: TestSelection,
+    #[serde(flatten)]
+    pub flaky: FlakyTests,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    #[serde(default)]
+    pub setup: Vec<SetupStep>,
 }
 
 impl CIConfig {
@@ -342,10 +591,50 @@ impl CIConfig {
         if self.test_results_path.is_none() {
             self.test_results_path = Some("test-results".to_string());
         }
+        self.pool.set_defaults(&self.org_alias, &self.org_def_path);
+    }
+}
+
+/// Scratch orgs created ahead of the CI builds that use them, see `OrgPool`.  The pool is used
+/// when `size` is above 0.  Its orgs are created from `org_def_path`, by default that of `[ci]`,
+/// and tagged with `tag`, by default the CI org alias.  Orgs with less than `min_hours_left`
+/// before they expire are recycled rather than checked out.
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
+pub struct PoolConfig {
+    #[serde(default)]
+    pub size: u32,
+    #[serde(default)]
+    pub tag: String,
+    #[serde(default)]
+    pub org_def_path: String,
+    #[serde(default)]
+    pub org_duration_days: i32,
+    #[serde(default)]
+    pub min_hours_left: i64,
+}
+
+impl PoolConfig {
+    fn set_defaults(&mut self, org_alias: &str, org_def_path: &str) {
+        if self.tag.is_empty() {
+            self.tag = org_alias.to_string();
+        }
+        if self.org_def_path.is_empty() {
+            self.org_def_path = org_def_path.to_string();
+        }
+        if self.org_duration_days <= 0 {
+            self.org_duration_days = 7;
+        }
+        if self.min_hours_left <= 0 {
+            self.min_hours_left = 2;
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.size > 0
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum TestResultsFormat {
     Human,
@@ -375,16 +664,208 @@ impl std::fmt::Display for TestResultsFormat {
     }
 }
 
-fn read_project_file_json(app_dir: &PathBuf) -> JsonValue {
-    let project_file = app_dir.join("sfdx-project.json");
-    let project_file_text = read_file_to_string(project_file.as_path()).unwrap();
-    let project_file_json = json::parse(&project_file_text).unwrap();
-    project_file_json
+/// The Apex code coverage, in percent, a passing test run must reach: `min_coverage` over all
+/// the lines the run covers, and `min_class_coverage` for each class.
+#[derive(Deserialize, Debug, Serialize, Default, Clone, PartialEq)]
+pub struct CoverageThresholds {
+    #[serde(default)]
+    pub min_coverage: Option<f64>,
+    #[serde(default)]
+    pub min_class_coverage: Option<f64>,
+}
+
+/// Apex tests that fail for reasons other than the code under test.  Failed tests are rerun up
+/// to `test_retries` times.  Failures of `quarantined_tests`, named as `Class.method` or by a
+/// whole `Class`, are ignored.
+#[derive(Deserialize, Debug, Serialize, Default, Clone)]
+pub struct FlakyTests {
+    #[serde(default)]
+    pub test_retries: u32,
+    #[serde(default)]
+    pub quarantined_tests: Vec<String>,
+}
+
+impl FlakyTests {
+    pub fn is_quarantined(&self, full_name: &str) -> bool {
+        self.quarantined_tests
+            .iter()
+            .any(|name| full_name == name || full_name.starts_with(&format!("{}.", name)))
+    }
+}
+
+/// A step preparing a scratch org once source is pushed to it, one of the `[[dev.setup]]` or
+/// `[[ci.setup]]` entries, run in order.  A failed step fails the build unless its `on_failure`
+/// is `continue`.
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
+pub struct SetupStep {
+    #[serde(flatten)]
+    pub action: SetupAction,
+    #[serde(default)]
+    pub on_failure: OnFailure,
+}
+
+/// What a setup step does, by the key naming it: `permset` assigns a permission set to the org's
+/// user, `data_plan` imports a `force:data:tree:import` plan, `apex` runs a file of anonymous
+/// Apex and `user` creates a user from a definition file.  Paths are relative to the app.
+#[derive(Deserialize, Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupAction {
+    Permset(String),
+    DataPlan(String),
+    Apex(String),
+    User(String),
+}
+
+impl std::fmt::Display for SetupAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetupAction::Permset(name) => write!(f, "assigning permission set {}", name),
+            SetupAction::DataPlan(path) => write!(f, "importing data plan {}", path),
+            SetupAction::Apex(path) => write!(f, "running apex {}", path),
+            SetupAction::User(path) => write!(f, "creating user from {}", path),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    Fail,
+    Continue,
+}
+
+impl Default for OnFailure {
+    fn default() -> Self {
+        OnFailure::Fail
+    }
+}
+
+/// How a built package version is verified in Package mode test.  `install` installs the new
+/// version into an empty org, `upgrade` first installs the last released version and upgrades it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageTestMode {
+    Install,
+    Upgrade,
+}
+
+impl Default for PackageTestMode {
+    fn default() -> Self {
+        PackageTestMode::Install
+    }
+}
+
+/// Scope of Apex tests to run, passed to `force:apex:test:run` as the test level.  The variants
+/// are named as the test levels of the CLI, which is also how they are given in `app.toml`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum TestLevel {
+    RunLocalTests,
+    RunAllTestsInOrg,
+    RunSpecifiedTests,
+}
+
+impl Default for TestLevel {
+    fn default() -> Self {
+        TestLevel::RunLocalTests
+    }
+}
+
+impl std::fmt::Display for TestLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                TestLevel::RunLocalTests => "RunLocalTests",
+                TestLevel::RunAllTestsInOrg => "RunAllTestsInOrg",
+                TestLevel::RunSpecifiedTests => "RunSpecifiedTests",
+            }
+        )
+    }
+}
+
+impl FromStr for TestLevel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "RunLocalTests" => Ok(TestLevel::RunLocalTests),
+            "RunAllTestsInOrg" => Ok(TestLevel::RunAllTestsInOrg),
+            "RunSpecifiedTests" => Ok(TestLevel::RunSpecifiedTests),
+            _ => Err(anyhow!("unknown test level {}", s)),
+        }
+    }
+}
+
+/// The Apex tests to run, from a `tests` table such as `[ci.tests]`.  Naming `classes`, `suites`
+/// or `methods` (as `Class.method`) runs just those tests, suites only without classes or methods.
+/// `changed_since` adds the test classes affected by the files changed since a git ref.  Each may
+/// be overridden by the environment variables `SF_PACKAGE_TEST_LEVEL`, `SF_PACKAGE_TEST_CLASSES`,
+/// `SF_PACKAGE_TEST_SUITES`, `SF_PACKAGE_TEST_METHODS` and `SF_PACKAGE_TEST_CHANGED_SINCE`, lists
+/// being comma separated.
+#[derive(Deserialize, Debug, Serialize, Default, Clone, PartialEq)]
+pub struct TestSelection {
+    #[serde(default)]
+    pub level: Option<TestLevel>,
+    #[serde(default)]
+    pub classes: Vec<String>,
+    #[serde(default)]
+    pub suites: Vec<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub changed_since: Option<String>,
+}
+
+impl TestSelection {
+    /// The selection with any overrides set in the environment.
+    pub fn with_env(mut self) -> Result<Self, anyhow::Error> {
+        if let Ok(level) = env::var("SF_PACKAGE_TEST_LEVEL") {
+            self.level = Some(level.parse()?);
+        }
+        for (var, list) in [
+            ("SF_PACKAGE_TEST_CLASSES", &mut self.classes),
+            ("SF_PACKAGE_TEST_SUITES", &mut self.suites),
+            ("SF_PACKAGE_TEST_METHODS", &mut self.methods),
+        ] {
+            if let Ok(value) = env::var(var) {
+                *list = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect();
+            }
+        }
+        if let Ok(git_ref) = env::var("SF_PACKAGE_TEST_CHANGED_SINCE") {
+            self.changed_since = Some(git_ref);
+        }
+        Ok(self)
+    }
+
+    pub fn is_specified(&self) -> bool {
+        !self.classes.is_empty() || !self.suites.is_empty() || !self.methods.is_empty()
+    }
+
+    /// The level to run at: as configured, otherwise the specified tests if any are named,
+    /// otherwise `default`.
+    pub fn level_or(&self, default: TestLevel) -> TestLevel {
+        match self.level {
+            Some(level) => level,
+            None if self.is_specified() => TestLevel::RunSpecifiedTests,
+            None => default,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::client::CliBackend;
     use crate::util::config;
+    use crate::util::dependencies::PlannedInstall;
+    use crate::util::project::SfdxProject;
+    use crate::util::version::VersionBump;
     use libcnb::write_file;
     use std::fs;
     use std::path::PathBuf;
@@ -455,7 +936,7 @@ mod tests {
         let app_dir = setup();
         let dirs = config::read_package_directories(&app_dir, true, true);
         match dirs {
-            Some(dirs) => {
+            Ok(dirs) => {
                 assert_eq!(dirs.len(), 1);
                 assert!(dirs[0].to_string_lossy().ends_with("force-app"));
             }
@@ -472,7 +953,7 @@ mod tests {
 
         let opt = config::read_package_directories(&app_dir, true, true);
         match opt {
-            Some(mut dirs) => {
+            Ok(mut dirs) => {
                 dirs.sort();
                 assert_eq!(dirs.len(), 2);
                 assert!(dirs[0].to_string_lossy().ends_with("force-app"));
@@ -497,25 +978,245 @@ mod tests {
         let dirs = config::read_package_directories(&app_dir, false, false).unwrap();
         assert_eq!(dirs.len(), 7);
     }
+
+    #[test]
+    fn it_should_keep_long_paths() {
+        let app_dir = setup();
+        let project_file_content = r#"
+{
+  "packageDirectories": [
+        { "path": "force-app/main/default/some/much/longer/package/path" }
+    ]
+}
+"#;
+        write_file(
+            project_file_content.as_bytes(),
+            &app_dir.join("sfdx-project.json"),
+        );
+
+        let dirs = config::read_package_directories(&app_dir, false, false).unwrap();
+        assert_eq!(dirs.len(), 1);
+    }
+
+    #[test]
+    fn it_should_order_packages_by_dependency() {
+        let app_dir = setup();
+        let project_file_content = r#"
+{
+  "packageDirectories": [
+        {
+            "path": "force-app",
+            "package": "Test App",
+            "default": true,
+            "dependencies": [ { "package": "Test App Deux", "versionNumber": "1.0.0.LATEST" } ]
+        },
+        {
+            "path": "force-app-deux",
+            "package": "Test App Deux",
+            "versionNumber": "2.1.0.NEXT",
+            "default": false
+        },
+        { "path": "force-app-trois", "package": "Test App Trois", "default": false }
+    ]
+}
+"#;
+        write_file(
+            project_file_content.as_bytes(),
+            &app_dir.join("sfdx-project.json"),
+        );
+        let app_file_content = r#"
+[default]
+hub_user = "hub"
+
+[[packages]]
+name = "Test App"
+
+[[packages]]
+name = "Test App Deux"
+bump = "minor"
+
+[dev]
+
+[ci]
+"#;
+        write_file(app_file_content.as_bytes(), &app_dir.join("app.toml"));
+
+        let project = SfdxProject::from_dir(&app_dir).unwrap();
+        let configs = config::SFPackageAppConfig::from_dir(&app_dir)
+            .package_configs(&project)
+            .unwrap();
+        let names: Vec<&str> = configs.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Test App Deux", "Test App"]);
+        assert_eq!(configs[1].hub_user, "hub");
+        assert_eq!(configs[0].version_number, "2.1.0.NEXT");
+        assert_eq!(configs[0].bump, VersionBump::Minor);
+    }
+
+    #[test]
+    fn it_should_require_packages_to_agree_on_test_settings() {
+        let package = |config: &str| -> config::PackageConfig { toml::from_str(config).unwrap() };
+        let configs = vec![
+            package("name = \"A\"\ntest_mode = \"upgrade\"\nmin_coverage = 75"),
+            package("name = \"B\"\ntest_mode = \"upgrade\"\nmin_coverage = 75"),
+        ];
+        let config = config::package_test_config(&configs).unwrap();
+        assert_eq!(config.test_mode, config::PackageTestMode::Upgrade);
+        assert_eq!(config.coverage.min_coverage, Some(75.0));
+
+        let configs = vec![
+            package("name = \"A\"\ntest_mode = \"upgrade\""),
+            package("name = \"B\""),
+        ];
+        let error = config::package_test_config(&configs).unwrap_err();
+        assert!(error.to_string().contains("test_mode"));
+        assert!(config::package_test_config(&[]).is_err());
+    }
+
+    #[test]
+    fn it_should_fail_without_project_file() {
+        let app_dir = tempdir().unwrap().into_path();
+        assert!(config::read_package_directories(&app_dir, false, false).is_err());
+    }
+
+    #[test]
+    fn it_should_read_coverage_thresholds() {
+        let ci: config::CIConfig = toml::from_str(
+            r#"
+org_alias = "ci"
+min_coverage = 75
+min_class_coverage = 62.5
+"#,
+        )
+        .unwrap();
+        assert_eq!(ci.org_alias, "ci");
+        assert_eq!(ci.coverage.min_coverage, Some(75.0));
+        assert_eq!(ci.coverage.min_class_coverage, Some(62.5));
+
+        let ci: config::CIConfig = toml::from_str(r#"org_alias = "ci""#).unwrap();
+        assert_eq!(ci.coverage.min_coverage, None);
+    }
+
+    #[test]
+    fn it_should_pin_runtime() {
+        let buildpack_runtime = config::SFDXRuntimeConfig {
+            url: "https://example.com/sfdx-linux-x64.tar.xz".to_string(),
+            manifest: "https://example.com/sfdx-linux-x64-buildmanifest".to_string(),
+            ..Default::default()
+        };
+        assert!(!buildpack_runtime.is_pinned());
+
+        let app: config::SFPackageAppConfig = toml::from_str(
+            r#"
+[default]
+[dev]
+[ci]
+[runtime]
+url = "vendor/sfdx-v7.132.0-linux-x64.tar.xz"
+sha256 = "0fd2a8a84cc2ad2e3f3d9d81ff1d2b8c1d5ba7cde1c1a2dfdbd5c0f6a3a5e8f1"
+version = "7.132.0"
+cli = "sf"
+plugins = ["@salesforce/sfdx-scanner@2.12.0", "sfpowerkit@4.2.13"]
+unsigned_plugins = ["sfpowerkit"]
+"#,
+        )
+        .unwrap();
+        let runtime = buildpack_runtime.with_overrides(&app.runtime);
+        assert!(runtime.is_pinned());
+        assert_eq!(runtime.url, "vendor/sfdx-v7.132.0-linux-x64.tar.xz");
+        assert_eq!(runtime.manifest, buildpack_runtime.manifest);
+        assert_eq!(runtime.version, "7.132.0");
+        assert_eq!(runtime.cli, Some(CliBackend::Sf));
+        assert_eq!(
+            runtime.plugins,
+            vec!["@salesforce/sfdx-scanner@2.12.0", "sfpowerkit@4.2.13"]
+        );
+        assert_eq!(runtime.unsigned_plugins, vec!["sfpowerkit"]);
+    }
+
+    #[test]
+    fn it_should_read_dependency_installation_keys() {
+        let app: config::SFPackageAppConfig = toml::from_str(
+            r#"
+[default]
+[dev]
+[ci]
+
+[[dependencies]]
+package = "Base Package"
+installation_key = "s3cret"
+
+[[dependencies]]
+package = "04t3t000002zQrEAAU"
+installation_key = "t0psecret"
+"#,
+        )
+        .unwrap();
+        let install = |package: &str, version_id: &str| PlannedInstall {
+            package: package.to_string(),
+            version_id: version_id.to_string(),
+        };
+        assert_eq!(
+            app.installation_key(&install("Base Package", "04t3t000002zQqpAAE")),
+            "s3cret"
+        );
+        assert_eq!(
+            app.installation_key(&install("Other Package", "04t3t000002zQrEAAU")),
+            "t0psecret"
+        );
+        assert_eq!(
+            app.installation_key(&install("Other Package", "04t3t000002zQrFAAU")),
+            ""
+        );
+    }
+
+    #[test]
+    fn it_should_read_setup_steps() {
+        let ci: config::CIConfig = toml::from_str(
+            r#"
+org_alias = "ci"
+
+[[setup]]
+permset = "Admin"
+
+[[setup]]
+apex = "scripts/apex/setup.apex"
+on_failure = "continue"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            ci.setup,
+            vec![
+                config::SetupStep {
+                    action: config::SetupAction::Permset("Admin".to_string()),
+                    on_failure: config::OnFailure::Fail,
+                },
+                config::SetupStep {
+                    action: config::SetupAction::Apex("scripts/apex/setup.apex".to_string()),
+                    on_failure: config::OnFailure::Continue,
+                },
+            ]
+        );
+    }
 }
 
 #[test]
-fn test_append_env_path() {
-    let bin = PathBuf::from("./sfdx/test_append_env_path/bin");
+fn test_prepend_env_path() {
+    let bin = PathBuf::from("./sfdx/test_prepend_env_path/bin");
     let bin2 = bin.clone();
 
     let mut path = env::var_os("PATH").unwrap_or(OsString::from(""));
     let mut paths = env::split_paths(&path).collect::<Vec<_>>();
     assert!(!paths.contains(&bin));
 
-    append_local_env_path(bin);
+    prepend_local_env_path(bin);
 
     path = env::var_os("PATH").unwrap();
     paths = env::split_paths(&path).collect::<Vec<_>>();
-    assert!(paths.contains(&bin2));
+    assert_eq!(paths.first(), Some(&bin2));
     let len1 = path.len();
 
-    append_local_env_path(bin2);
+    prepend_local_env_path(bin2);
     path = env::var_os("PATH").unwrap();
     let len2 = path.len();
     assert_eq!(
@@ -524,11 +1225,13 @@ fn test_append_env_path() {
     );
 }
 
-pub fn append_local_env_path(bin: PathBuf) {
+/// Put a directory at the front of `PATH`, so that executables installed into a layer take
+/// precedence over any found elsewhere.
+pub fn prepend_local_env_path(bin: PathBuf) {
     let path = env::var_os("PATH").unwrap_or(OsString::from(""));
     let mut paths = env::split_paths(&path).collect::<Vec<_>>();
     if !paths.contains(&bin) {
-        paths.push(bin);
+        paths.insert(0, bin);
         let new_path = env::join_paths(paths).unwrap();
         env::set_var("PATH", &new_path);
     }
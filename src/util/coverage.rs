@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::util::config::read_package_directories;
+use crate::{ApexClassCoverage, ApexCodeCoverage};
+
+/// # Coverage Report
+/// The code coverage of an Apex test run in the formats read by code review tools: Cobertura XML
+/// and LCOV.  Classes and triggers are reported by their source file, relative to the app, when
+/// it can be found in the project's package directories.
+pub struct CoverageReport<'a> {
+    coverage: &'a ApexCodeCoverage,
+    sources: HashMap<String, String>,
+}
+
+impl<'a> CoverageReport<'a> {
+    pub fn new(coverage: &'a ApexCodeCoverage, app_dir: &Path) -> Self {
+        let mut sources = HashMap::new();
+        if let Ok(dirs) = read_package_directories(&app_dir.to_path_buf(), true, true) {
+            for dir in dirs.iter() {
+                find_sources(app_dir, dir, &mut sources);
+            }
+        }
+        CoverageReport { coverage, sources }
+    }
+
+    /// Write `coverage-cobertura.xml` and `lcov.info` into `dir`, returning the files written.
+    pub fn write(&self, dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+        fs::create_dir_all(dir)?;
+        let cobertura = dir.join("coverage-cobertura.xml");
+        fs::write(&cobertura, self.cobertura())?;
+        let lcov = dir.join("lcov.info");
+        fs::write(&lcov, self.lcov())?;
+        Ok(vec![cobertura, lcov])
+    }
+
+    fn source(&self, class: &ApexClassCoverage) -> String {
+        self.sources
+            .get(&class.name)
+            .cloned()
+            .unwrap_or_else(|| class.name.clone())
+    }
+
+    pub fn cobertura(&self) -> String {
+        let classes = &self.coverage.coverage;
+        let valid: u32 = classes.iter().map(|c| c.total_lines).sum();
+        let covered: u32 = classes.iter().map(|c| c.covered_lines()).sum();
+        let mut xml = String::from("<?xml version=\"1.0\" ?>\n");
+        xml.push_str(
+            "<!DOCTYPE coverage SYSTEM \"http://cobertura.sourceforge.net/xml/coverage-04.dtd\">\n",
+        );
+        let _ = writeln!(
+            xml,
+            "<coverage line-rate=\"{}\" branch-rate=\"0\" lines-covered=\"{}\" lines-valid=\"{}\" branches-covered=\"0\" branches-valid=\"0\" complexity=\"0\" version=\"0.1\" timestamp=\"{}\">",
+            rate(covered, valid),
+            covered,
+            valid,
+            chrono::Utc::now().timestamp_millis()
+        );
+        xml.push_str("  <sources>\n    <source>.</source>\n  </sources>\n");
+        xml.push_str("  <packages>\n");
+        let _ = writeln!(
+            xml,
+            "    <package name=\"apex\" line-rate=\"{}\" branch-rate=\"0\" complexity=\"0\">",
+            rate(covered, valid)
+        );
+        xml.push_str("      <classes>\n");
+        for class in classes.iter() {
+            let _ = writeln!(
+                xml,
+                "        <class name=\"{}\" filename=\"{}\" line-rate=\"{}\" branch-rate=\"0\" complexity=\"0\">",
+                escape(&class.name),
+                escape(&self.source(class)),
+                rate(class.covered_lines(), class.total_lines)
+            );
+            xml.push_str("          <methods/>\n          <lines>\n");
+            for (line, hits) in class.lines.iter() {
+                let _ = writeln!(
+                    xml,
+                    "            <line number=\"{}\" hits=\"{}\" branch=\"false\"/>",
+                    line, hits
+                );
+            }
+            xml.push_str("          </lines>\n        </class>\n");
+        }
+        xml.push_str("      </classes>\n    </package>\n  </packages>\n</coverage>\n");
+        xml
+    }
+
+    pub fn lcov(&self) -> String {
+        let mut lcov = String::new();
+        for class in self.coverage.coverage.iter() {
+            lcov.push_str("TN:\n");
+            let _ = writeln!(lcov, "SF:{}", self.source(class));
+            for (line, hits) in class.lines.iter() {
+                let _ = writeln!(lcov, "DA:{},{}", line, hits);
+            }
+            let _ = writeln!(lcov, "LF:{}", class.total_lines);
+            let _ = writeln!(lcov, "LH:{}", class.covered_lines());
+            lcov.push_str("end_of_record\n");
+        }
+        lcov
+    }
+}
+
+/// Map the name of each Apex class and trigger under `dir` to its path relative to `app_dir`.
+fn find_sources(app_dir: &Path, dir: &Path, sources: &mut HashMap<String, String>) {
+    if let Ok(entries) = fs::read_dir(app_dir.join(dir)) {
+        for entry in entries.flatten() {
+            let path = dir.join(entry.file_name());
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                find_sources(app_dir, &path, sources);
+            } else if let (Some(name), Some(extension)) = (path.file_stem(), path.extension()) {
+                if extension == "cls" || extension == "trigger" {
+                    sources.insert(
+                        name.to_string_lossy().to_string(),
+                        path.to_string_lossy().to_string(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn rate(covered: u32, valid: u32) -> String {
+    if valid == 0 {
+        "1".to_string()
+    } else {
+        format!("{:.4}", f64::from(covered) / f64::from(valid))
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coverage() -> ApexCodeCoverage {
+        serde_json::from_str(
+            r#"{
+  "coverage": [
+    {"id": "01p0t00000FKeSsAAL", "name": "Test", "totalLines": 4, "lines": {"3": 1, "4": 1, "7": 2, "8": 0}, "totalCovered": 3, "coveredPercent": 75}
+  ]
+}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn it_writes_cobertura() {
+        let coverage = coverage();
+        let app_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sf-package");
+        let xml = CoverageReport::new(&coverage, &app_dir).cobertura();
+        assert!(xml.contains(r#"lines-covered="3" lines-valid="4""#));
+        assert!(xml.contains(
+            r#"<class name="Test" filename="force-app/main/default/classes/Test.cls" line-rate="0.7500""#
+        ));
+        assert!(xml.contains(r#"<line number="7" hits="2" branch="false"/>"#));
+    }
+
+    #[test]
+    fn it_writes_lcov() {
+        let coverage = coverage();
+        let lcov = CoverageReport::new(&coverage, Path::new("/nonexistent")).lcov();
+        assert_eq!(
+            lcov,
+            "TN:\nSF:Test\nDA:3,1\nDA:4,1\nDA:7,2\nDA:8,0\nLF:4\nLH:3\nend_of_record\n"
+        );
+    }
+}
@@ -0,0 +1,443 @@
+use anyhow::anyhow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::util::project::SfdxProject;
+use crate::util::version::{version_key, version_matches};
+
+/// A dependency as declared in the `dependencies` array of a `packageDirectories` entry.
+#[derive(Debug, Clone)]
+pub struct PackageDependency {
+    pub package: String,
+    pub version_number: Option<String>,
+}
+
+/// A package version to install into an org, in install order.
+#[derive(Debug, PartialEq)]
+pub struct PlannedInstall {
+    pub package: String,
+    pub version_id: String,
+}
+
+/// The packages declared in `sfdx-project.json`, their dependencies and `packageAliases`.
+#[derive(Debug, Default)]
+pub struct DependencyGraph {
+    packages: Vec<(String, Vec<PackageDependency>)>,
+    aliases: HashMap<String, String>,
+}
+
+impl DependencyGraph {
+    pub fn from_dir(app_dir: &PathBuf) -> Result<Self, anyhow::Error> {
+        Ok(DependencyGraph::from_project(&SfdxProject::from_dir(
+            app_dir,
+        )?))
+    }
+
+    pub fn from_project(project: &SfdxProject) -> Self {
+        let mut graph = DependencyGraph::default();
+        for dir in project.package_directories.iter() {
+            if let Some(name) = &dir.package {
+                let dependencies = dir
+                    .dependencies
+                    .iter()
+                    .map(|d| PackageDependency {
+                        package: d.package.clone(),
+                        version_number: d.version_number.clone(),
+                    })
+                    .collect();
+                graph.packages.push((name.clone(), dependencies));
+            }
+        }
+        for (alias, id) in project.package_aliases.iter() {
+            graph.aliases.insert(alias.clone(), id.clone());
+        }
+        graph
+    }
+
+    /// Names of all packages declared in `packageDirectories`, in declaration order.
+    pub fn package_names(&self) -> Vec<String> {
+        self.packages.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn dependencies_of(&self, name: &str) -> Option<&Vec<PackageDependency>> {
+        self.packages
+            .iter()
+            .find(|(package, _)| package == name)
+            .map(|(_, dependencies)| dependencies)
+    }
+
+    /// Order the named packages so that each one follows the packages declared in this project
+    /// that it depends on, directly or through other packages of this project.
+    pub fn build_order(&self, names: &[String]) -> Result<Vec<String>, anyhow::Error> {
+        let mut order = vec![];
+        let mut visiting = vec![];
+        for name in names {
+            self.order(name, names, &mut visiting, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn order(
+        &self,
+        name: &str,
+        names: &[String],
+        visiting: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<(), anyhow::Error> {
+        if order.iter().any(|o| o == name) {
+            return Ok(());
+        }
+        if visiting.iter().any(|v| v == name) {
+            visiting.push(name.to_string());
+            return Err(anyhow!(
+                "circular package dependency: {}",
+                visiting.join(" -> ")
+            ));
+        }
+        visiting.push(name.to_string());
+        if let Some(dependencies) = self.dependencies_of(name) {
+            for dependency in dependencies {
+                let (package, _) = split_package_version(&dependency.package);
+                if self.dependencies_of(package).is_some() {
+                    self.order(package, names, visiting, order)?;
+                }
+            }
+        }
+        visiting.pop();
+        if names.iter().any(|n| n == name) {
+            order.push(name.to_string());
+        }
+        Ok(())
+    }
+
+    /// Work out which package versions must be installed, and in which order, before the `roots`
+    /// packages can be deployed.  Dependencies on packages declared in this project are followed
+    /// transitively; they are only installed themselves when `include_local` is set, since Dev and
+    /// CI builds push their source instead.  Versions that cannot be resolved through
+    /// `packageAliases` are looked up with `lookup`, given the package id and version number, which
+    /// returns the matching version's id and version.  A package depended on at several versions
+    /// is installed once, at the highest of them.
+    pub fn install_plan<F>(
+        &self,
+        roots: &[String],
+        include_local: bool,
+        mut lookup: F,
+    ) -> Result<Vec<PlannedInstall>, anyhow::Error>
+    where
+        F: FnMut(&str, &str) -> Result<Option<(String, String)>, anyhow::Error>,
+    {
+        let mut plan = vec![];
+        let mut visiting = vec![];
+        for root in roots {
+            self.visit(root, include_local, &mut lookup, &mut visiting, &mut plan)?;
+        }
+        Ok(plan.into_iter().map(|(install, _)| install).collect())
+    }
+
+    fn visit<F>(
+        &self,
+        name: &str,
+        include_local: bool,
+        lookup: &mut F,
+        visiting: &mut Vec<String>,
+        plan: &mut Vec<(PlannedInstall, Option<String>)>,
+    ) -> Result<(), anyhow::Error>
+    where
+        F: FnMut(&str, &str) -> Result<Option<(String, String)>, anyhow::Error>,
+    {
+        let dependencies = match self.dependencies_of(name) {
+            Some(dependencies) => dependencies,
+            None => return Ok(()),
+        };
+        if visiting.iter().any(|v| v == name) {
+            visiting.push(name.to_string());
+            return Err(anyhow!(
+                "circular package dependency: {}",
+                visiting.join(" -> ")
+            ));
+        }
+        visiting.push(name.to_string());
+
+        for dependency in dependencies {
+            let (package, _) = split_package_version(&dependency.package);
+            let local = self.dependencies_of(package).is_some();
+            if local {
+                self.visit(package, include_local, lookup, visiting, plan)?;
+                if !include_local {
+                    continue;
+                }
+            }
+            let (version_id, version) = self.resolve(dependency, lookup)?;
+            match plan.iter_mut().find(|(p, _)| p.package == package) {
+                // Keep the place of the first, since its dependencies are installed before it.
+                Some((planned, planned_version)) => {
+                    if is_higher(&version, planned_version) {
+                        planned.version_id = version_id;
+                        *planned_version = version;
+                    }
+                }
+                None => plan.push((
+                    PlannedInstall {
+                        package: package.to_string(),
+                        version_id,
+                    },
+                    version,
+                )),
+            }
+        }
+
+        visiting.pop();
+        Ok(())
+    }
+
+    /// Resolve a dependency to a subscriber package version id (04t), and its version when known.
+    fn resolve<F>(
+        &self,
+        dependency: &PackageDependency,
+        lookup: &mut F,
+    ) -> Result<(String, Option<String>), anyhow::Error>
+    where
+        F: FnMut(&str, &str) -> Result<Option<(String, String)>, anyhow::Error>,
+    {
+        if let Some(id) = self.aliases.get(&dependency.package) {
+            if id.starts_with("04t") {
+                let version = split_package_version(&dependency.package)
+                    .1
+                    .map(|v| v.replace('-', "."));
+                return Ok((id.clone(), version));
+            }
+        }
+
+        let (package, package_version) = split_package_version(&dependency.package);
+        let id = self
+            .aliases
+            .get(package)
+            .map(String::as_str)
+            .unwrap_or(package);
+        if id.starts_with("04t") {
+            return Ok((id.to_string(), None));
+        }
+
+        let version_number = match package_version.or(dependency.version_number.as_deref()) {
+            Some(version_number) => version_number.replace('-', "."),
+            None => {
+                return Err(anyhow!(
+                    "dependency {} has no versionNumber and no package version alias",
+                    dependency.package
+                ))
+            }
+        };
+
+        if let Some((version_id, version)) = self.resolve_alias(package, &version_number) {
+            return Ok((version_id, Some(version)));
+        }
+        if !id.starts_with("0Ho") {
+            return Err(anyhow!(
+                "dependency {} is not a package alias or package id",
+                dependency.package
+            ));
+        }
+        match lookup(id, &version_number)? {
+            Some((version_id, version)) => Ok((version_id, Some(version))),
+            None => Err(anyhow!(
+                "no version {} of dependency {} found",
+                version_number,
+                dependency.package
+            )),
+        }
+    }
+
+    /// Find a `Package@major.minor.patch-build` alias matching the version number, taking the
+    /// highest matching version for `LATEST`, with that version.
+    fn resolve_alias(&self, package: &str, version_number: &str) -> Option<(String, String)> {
+        let prefix = format!("{}@", package);
+        self.aliases
+            .iter()
+            .filter(|(alias, id)| alias.starts_with(&prefix) && id.starts_with("04t"))
+            .filter_map(|(alias, id)| {
+                let version = alias[prefix.len()..].replace('-', ".");
+                if version_matches(version_number, &version) {
+                    Some((version_key(&version), id, version))
+                } else {
+                    None
+                }
+            })
+            .max_by_key(|(key, _, _)| key.clone())
+            .map(|(_, id, version)| (id.clone(), version))
+    }
+}
+
+/// Whether a resolved version is higher than the one planned.  A version given only as a
+/// subscriber package version id is not compared, and the one planned first is kept.
+fn is_higher(version: &Option<String>, planned: &Option<String>) -> bool {
+    match (version, planned) {
+        (Some(version), Some(planned)) => version_key(version) > version_key(planned),
+        _ => false,
+    }
+}
+
+/// Split `Package@1.2.0-1` or `Package@1.2.0.LATEST` into the package and its version.
+fn split_package_version(package: &str) -> (&str, Option<&str>) {
+    match package.rfind('@') {
+        Some(i) => (&package[..i], Some(&package[i + 1..])),
+        None => (package, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph() -> DependencyGraph {
+        let project: SfdxProject = serde_json::from_str(
+            r#"
+{
+  "packageDirectories": [
+    {
+      "path": "core",
+      "package": "Core",
+      "versionNumber": "1.0.0.NEXT",
+      "dependencies": [
+        { "package": "Vendor", "versionNumber": "2.1.0.LATEST" },
+        { "package": "Logging@3.0.0-2" }
+      ]
+    },
+    {
+      "path": "app",
+      "package": "App",
+      "versionNumber": "1.0.0.NEXT",
+      "dependencies": [
+        { "package": "Remote", "versionNumber": "1.4.0.LATEST" },
+        { "package": "Core", "versionNumber": "1.0.0.LATEST" },
+        { "package": "Vendor", "versionNumber": "2.1.0.LATEST" }
+      ]
+    }
+  ],
+  "packageAliases": {
+    "Vendor": "0Ho000000000001AAA",
+    "Vendor@2.1.0-1": "04t000000000001AAA",
+    "Vendor@2.1.0-3": "04t000000000003AAA",
+    "Vendor@2.0.0-9": "04t000000000009AAA",
+    "Logging@3.0.0-2": "04t000000000032AAA",
+    "Remote": "0Ho000000000002AAA",
+    "Core": "0Ho000000000003AAA",
+    "Core@1.0.0-4": "04t000000000104AAA"
+  }
+}
+"#,
+        )
+        .unwrap();
+        DependencyGraph::from_project(&project)
+    }
+
+    fn remote_lookup(
+        id: &str,
+        version_number: &str,
+    ) -> Result<Option<(String, String)>, anyhow::Error> {
+        assert_eq!(id, "0Ho000000000002AAA");
+        assert_eq!(version_number, "1.4.0.LATEST");
+        Ok(Some((
+            "04t000000000140AAA".to_string(),
+            "1.4.0.2".to_string(),
+        )))
+    }
+
+    #[test]
+    fn it_orders_transitive_dependencies_first() {
+        let plan = graph()
+            .install_plan(&["App".to_string()], false, remote_lookup)
+            .unwrap();
+        let ids: Vec<&str> = plan.iter().map(|p| p.version_id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "04t000000000140AAA",
+                "04t000000000003AAA",
+                "04t000000000032AAA"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_installs_local_packages_when_asked() {
+        let plan = graph()
+            .install_plan(&["App".to_string()], true, remote_lookup)
+            .unwrap();
+        let packages: Vec<&str> = plan.iter().map(|p| p.package.as_str()).collect();
+        assert_eq!(packages, vec!["Remote", "Vendor", "Logging", "Core"]);
+        assert_eq!(plan[3].version_id, "04t000000000104AAA");
+    }
+
+    #[test]
+    fn it_installs_the_highest_version_of_a_package_once() {
+        let project: SfdxProject = serde_json::from_str(
+            r#"
+{
+  "packageDirectories": [
+    {
+      "path": "core",
+      "package": "Core",
+      "dependencies": [ { "package": "Vendor@2.0.0-9" } ]
+    },
+    {
+      "path": "app",
+      "package": "App",
+      "dependencies": [
+        { "package": "Core", "versionNumber": "1.0.0.LATEST" },
+        { "package": "Vendor", "versionNumber": "2.1.0.LATEST" }
+      ]
+    }
+  ],
+  "packageAliases": {
+    "Vendor": "0Ho000000000001AAA",
+    "Vendor@2.1.0-3": "04t000000000003AAA",
+    "Vendor@2.0.0-9": "04t000000000009AAA"
+  }
+}
+"#,
+        )
+        .unwrap();
+        let plan = DependencyGraph::from_project(&project)
+            .install_plan(&["App".to_string()], false, |_, _| Ok(None))
+            .unwrap();
+        assert_eq!(
+            plan,
+            vec![PlannedInstall {
+                package: "Vendor".to_string(),
+                version_id: "04t000000000003AAA".to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn it_orders_local_packages_for_building() {
+        let order = graph()
+            .build_order(&["App".to_string(), "Core".to_string()])
+            .unwrap();
+        assert_eq!(order, vec!["Core".to_string(), "App".to_string()]);
+    }
+
+    #[test]
+    fn it_detects_cycles() {
+        let project: SfdxProject = serde_json::from_str(
+            r#"
+{
+  "packageDirectories": [
+    { "path": "a", "package": "A", "dependencies": [ { "package": "B" } ] },
+    { "path": "b", "package": "B", "dependencies": [ { "package": "A" } ] }
+  ]
+}
+"#,
+        )
+        .unwrap();
+        let graph = DependencyGraph::from_project(&project);
+        let result = graph.install_plan(&["A".to_string()], false, |_, _| Ok(None));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn it_fails_on_unresolvable_versions() {
+        let result = graph().install_plan(&["App".to_string()], false, |_, _| Ok(None));
+        assert!(result.is_err());
+    }
+}
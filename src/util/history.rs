@@ -0,0 +1,262 @@
+use std::fmt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::cache::CacheLayer;
+use crate::{ApexTestOutcome, ApexTestRunResult};
+
+const HISTORY_FILE: &str = "history.toml";
+
+/// Runs kept in the history, oldest first dropped.
+const MAX_RUNS: usize = 20;
+
+/// Tests are only slower when they take this much longer, so that the noise in the runtime of
+/// fast tests is not reported.
+const MIN_SLOWDOWN_MS: i32 = 100;
+
+/// # Test History
+/// The results of earlier test runs, kept in the `test-history` cache layer so that each run can
+/// be compared with the one before.
+pub struct TestHistory {
+    layer: CacheLayer,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct TestHistoryFile {
+    #[serde(default)]
+    runs: Vec<TestRunRecord>,
+}
+
+/// The outcome and runtime of each test of a run.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TestRunRecord {
+    pub test_run_id: String,
+    pub started: String,
+    pub tests: Vec<TestRecord>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TestRecord {
+    pub name: String,
+    pub outcome: ApexTestOutcome,
+    pub run_time: i32,
+}
+
+impl TestHistory {
+    pub fn new(layers_dir: &Path) -> Self {
+        TestHistory {
+            layer: CacheLayer::new(layers_dir, "test-history"),
+        }
+    }
+
+    /// Record a run, returning the run recorded before it.
+    pub fn record(
+        &self,
+        result: &ApexTestRunResult,
+    ) -> Result<Option<TestRunRecord>, anyhow::Error> {
+        let mut history: TestHistoryFile = self.layer.read(HISTORY_FILE)?.unwrap_or_default();
+        let previous = history.runs.last().cloned();
+        history.runs.push(TestRunRecord::from(result));
+        if history.runs.len() > MAX_RUNS {
+            history.runs.drain(..history.runs.len() - MAX_RUNS);
+        }
+
+        self.layer.write(HISTORY_FILE, &history)?;
+        Ok(previous)
+    }
+}
+
+impl From<&ApexTestRunResult> for TestRunRecord {
+    fn from(result: &ApexTestRunResult) -> Self {
+        TestRunRecord {
+            test_run_id: result.summary.test_run_id.clone(),
+            started: result.summary.test_start_time.clone(),
+            tests: result
+                .tests
+                .iter()
+                .map(|test| TestRecord {
+                    name: test.full_name.clone(),
+                    outcome: test.outcome.clone(),
+                    run_time: test.run_time,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TestRunRecord {
+    fn test(&self, name: &str) -> Option<&TestRecord> {
+        self.tests.iter().find(|t| t.name == name)
+    }
+
+    /// What changed since the `previous` run.  Tests are slower when their runtime grew by more
+    /// than `slowdown_percent`.
+    pub fn diff(&self, previous: &TestRunRecord, slowdown_percent: f64) -> TestRunDiff {
+        let mut diff = TestRunDiff::default();
+        for test in self.tests.iter() {
+            match previous.test(&test.name) {
+                None => diff.added.push(test.name.clone()),
+                Some(before) => {
+                    let failed = |t: &TestRecord| matches!(t.outcome, ApexTestOutcome::Fail);
+                    let passed = |t: &TestRecord| matches!(t.outcome, ApexTestOutcome::Pass);
+                    if failed(test) && !failed(before) {
+                        diff.newly_failing.push(test.name.clone());
+                    } else if passed(test) && failed(before) {
+                        diff.newly_passing.push(test.name.clone());
+                    }
+                    let limit = f64::from(before.run_time) * (1.0 + slowdown_percent / 100.0);
+                    if f64::from(test.run_time) > limit
+                        && test.run_time - before.run_time >= MIN_SLOWDOWN_MS
+                    {
+                        diff.slower
+                            .push((test.name.clone(), before.run_time, test.run_time));
+                    }
+                }
+            }
+        }
+        for test in previous.tests.iter() {
+            if self.test(&test.name).is_none() {
+                diff.removed.push(test.name.clone());
+            }
+        }
+        diff
+    }
+}
+
+/// The tests whose results changed between two runs.
+#[derive(Debug, Default, PartialEq)]
+pub struct TestRunDiff {
+    pub newly_failing: Vec<String>,
+    pub newly_passing: Vec<String>,
+    /// Tests that slowed down, with their runtimes before and after, in ms.
+    pub slower: Vec<(String, i32, i32)>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+impl TestRunDiff {
+    pub fn is_empty(&self) -> bool {
+        self == &TestRunDiff::default()
+    }
+}
+
+impl fmt::Display for TestRunDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes since the previous run");
+        }
+        let sections = [
+            ("newly failing", &self.newly_failing),
+            ("newly passing", &self.newly_passing),
+            ("added", &self.added),
+            ("removed", &self.removed),
+        ];
+        let mut lines = vec![];
+        for (title, names) in sections.iter() {
+            if !names.is_empty() {
+                lines.push(format!("{}:", title));
+                lines.extend(names.iter().map(|name| format!("  {}", name)));
+            }
+        }
+        if !self.slower.is_empty() {
+            lines.push("slower:".to_string());
+            lines.extend(
+                self.slower.iter().map(|(name, before, after)| {
+                    format!("  {} {} ms -> {} ms", name, before, after)
+                }),
+            );
+        }
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn run(id: &str, tests: &[(&str, ApexTestOutcome, i32)]) -> TestRunRecord {
+        TestRunRecord {
+            test_run_id: id.to_string(),
+            started: "Thu Jan 06 2022 2:44:54 PM".to_string(),
+            tests: tests
+                .iter()
+                .map(|(name, outcome, run_time)| TestRecord {
+                    name: name.to_string(),
+                    outcome: outcome.clone(),
+                    run_time: *run_time,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn it_diffs_runs() {
+        let before = run(
+            "1",
+            &[
+                ("T.fails", ApexTestOutcome::Pass, 10),
+                ("T.fixed", ApexTestOutcome::Fail, 10),
+                ("T.slows", ApexTestOutcome::Pass, 200),
+                ("T.jitters", ApexTestOutcome::Pass, 10),
+                ("T.removed", ApexTestOutcome::Pass, 10),
+            ],
+        );
+        let after = run(
+            "2",
+            &[
+                ("T.fails", ApexTestOutcome::Fail, 10),
+                ("T.fixed", ApexTestOutcome::Pass, 10),
+                ("T.slows", ApexTestOutcome::Pass, 450),
+                ("T.jitters", ApexTestOutcome::Pass, 40),
+                ("T.added", ApexTestOutcome::Pass, 10),
+            ],
+        );
+
+        assert_eq!(
+            after.diff(&before, 50.0),
+            TestRunDiff {
+                newly_failing: vec!["T.fails".to_string()],
+                newly_passing: vec!["T.fixed".to_string()],
+                slower: vec![("T.slows".to_string(), 200, 450)],
+                added: vec!["T.added".to_string()],
+                removed: vec!["T.removed".to_string()],
+            }
+        );
+        assert!(after.diff(&after, 50.0).is_empty());
+    }
+
+    #[test]
+    fn it_keeps_runs_in_the_layer() {
+        let layers_dir = tempdir().unwrap();
+        let history = TestHistory::new(layers_dir.path());
+
+        let result: ApexTestRunResult = serde_json::from_str(
+            r#"{
+  "summary": {"outcome": "Passed", "testsRan": 1, "passing": 1, "failing": 0, "skipped": 0,
+    "passRate": "100%", "failRate": "0%", "testStartTime": "Thu Jan 06 2022 2:44:54 PM",
+    "testExecutionTime": "24 ms", "testTotalTime": "24 ms", "commandTime": "244 ms",
+    "hostname": "https://example.my.salesforce.com", "orgId": "00D0t000000MeWZEA0",
+    "username": "test@example.com", "testRunId": "7070t00001vpgqx", "userId": "0050t000009C5sDAAS",
+    "testRunCoverage": "100%", "orgWideCoverage": "100%"},
+  "tests": [{"Id": "07M0t00000FfffwEAB", "QueueItemId": "7090t0000022UUlAAM", "StackTrace": null,
+    "Message": null, "AsyncApexJobId": "7070t00001vpgqxAAA", "MethodName": "testBehavior",
+    "Outcome": "Pass", "ApexClass": {"Id": "01p0t00000FKeStAAL", "Name": "TestTests", "NamespacePrefix": null},
+    "RunTime": 11, "FullName": "TestTests.testBehavior"}]
+}"#,
+        )
+        .unwrap();
+
+        assert_eq!(history.record(&result).unwrap(), None);
+        let previous = history.record(&result).unwrap().unwrap();
+        assert_eq!(previous.tests[0].name, "TestTests.testBehavior");
+        assert_eq!(previous.tests[0].run_time, 11);
+        assert!(layers_dir.path().join("test-history.toml").is_file());
+
+        let file = layers_dir.path().join("test-history/history.toml");
+        std::fs::write(&file, "runs = 3").unwrap();
+        assert!(history.record(&result).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "runs = 3");
+    }
+}
@@ -16,6 +16,13 @@ pub trait Logger {
     fn debug(&mut self, msg: impl Display) -> anyhow::Result<()>;
     /// Display output from an executed process
     fn output(&mut self, msg: impl Display, output: Output) -> anyhow::Result<()>;
+
+    /// Display an error and hand it back, so that it is propagated as it was rather than as
+    /// the header `error` fails with
+    fn fail(&mut self, header: impl Display, error: anyhow::Error) -> anyhow::Error {
+        let _ = self.error(header, &error);
+        error
+    }
 }
 
 /// A logger that uses generics for the implementation of stderr/stdout.
@@ -41,6 +48,23 @@ impl BuildLogger {
     }
 }
 
+/// A logger with the same settings, writing to the same streams, for code that outlives the
+/// borrow of the caller's logger.
+impl Clone for BuildLogger {
+    fn clone(&self) -> Self {
+        BuildLogger::new(self.debug, self.prefix)
+    }
+}
+
+impl<T: Write + WriteColor> std::fmt::Debug for GenericLogger<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GenericLogger")
+            .field("debug", &self.debug)
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
 impl<T: Write + WriteColor> Logger for GenericLogger<T> {
     fn header(&mut self, msg: impl Display) -> anyhow::Result<()> {
         if self.prefix {
@@ -24,15 +24,26 @@ impl FromStr for PackageVersionStatus {
 
 #[derive(Debug, Deserialize, Serialize, Default)]
 pub struct SFPackageAppMeta {
+    #[serde(default)]
     package_versions: Vec<PackageVersionMeta>,
-    package: PackageMeta,
+    #[serde(default)]
+    packages: Vec<PackageMeta>,
+    /// Single package recorded by earlier versions of the buildpack, moved into `packages`.
+    #[serde(default, skip_serializing)]
+    package: Option<PackageMeta>,
 }
 
 impl SFPackageAppMeta {
     pub fn from_dir(app_dir: &PathBuf) -> Self {
         let file = app_dir.join("app-meta.toml");
         if let Ok(file_text) = read_file_to_string(file.as_path()) {
-            toml::from_str(&file_text).unwrap()
+            let mut app_meta: SFPackageAppMeta = toml::from_str(&file_text).unwrap();
+            if let Some(package) = app_meta.package.take() {
+                if app_meta.package(&package.name).is_none() {
+                    app_meta.packages.push(package);
+                }
+            }
+            app_meta
         } else {
             SFPackageAppMeta::default()
         }
@@ -42,23 +53,54 @@ impl SFPackageAppMeta {
         let file = app_dir.join("app-meta.toml");
         write_toml_file(self, file)
     }
+
+    /// The recorded package with the given name.
+    pub fn package(&self, name: &str) -> Option<&PackageMeta> {
+        self.packages.iter().find(|p| p.name == name)
+    }
+
+    /// All recorded versions of the given package, oldest first.
+    pub fn package_versions(&self, package_id: &str) -> Vec<&PackageVersionMeta> {
+        self.package_versions
+            .iter()
+            .filter(|v| v.package_id == package_id)
+            .collect()
+    }
+
+    /// The most recently recorded version of the given package, if any has been built.
+    pub fn latest_package_version(&self, package_id: &str) -> Option<&PackageVersionMeta> {
+        self.package_versions
+            .iter()
+            .rev()
+            .find(|v| v.package_id == package_id)
+    }
+
+    /// The most recently published version of the given package, if any.
+    pub fn latest_published_package_version(
+        &self,
+        package_id: &str,
+    ) -> Option<&PackageVersionMeta> {
+        self.package_versions.iter().rev().find(|v| {
+            v.package_id == package_id && matches!(v.status, PackageVersionStatus::Published)
+        })
+    }
 }
 
 #[derive(Deserialize, Debug, Serialize, Default)]
 pub struct PackageMeta {
-    id: String,
-    name: String,
-    hub_user: String,
-    hub_instance_url: String,
+    pub id: String,
+    pub name: String,
+    pub hub_user: String,
+    pub hub_instance_url: String,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
 pub struct PackageVersionMeta {
-    id: String,
-    name: String,
-    number: String,
-    package_id: String,
-    status: PackageVersionStatus,
+    pub id: String,
+    pub name: String,
+    pub number: String,
+    pub package_id: String,
+    pub status: PackageVersionStatus,
 }
 
 pub fn write_package_meta(
@@ -69,12 +111,16 @@ pub fn write_package_meta(
     hub_instance_url: &String,
 ) -> Result<(), anyhow::Error> {
     let mut app_meta = SFPackageAppMeta::from_dir(app_dir);
-    app_meta.package = PackageMeta {
+    let package = PackageMeta {
         id: id.to_string(),
         name: name.to_string(),
         hub_user: hub_user.to_string(),
         hub_instance_url: hub_instance_url.to_string(),
     };
+    match app_meta.packages.iter_mut().find(|p| &p.name == name) {
+        Some(existing) => *existing = package,
+        None => app_meta.packages.push(package),
+    }
     match app_meta.to_dir(app_dir) {
         Ok(()) => Ok(()),
         Err(e) => Err(anyhow::Error::new(e)),
@@ -101,3 +147,16 @@ pub fn write_package_version_meta(
         Err(e) => Err(anyhow::Error::new(e)),
     }
 }
+
+/// Mark a recorded package version as promoted to released.
+pub fn publish_package_version(app_dir: &PathBuf, id: &str) -> Result<(), anyhow::Error> {
+    let mut app_meta = SFPackageAppMeta::from_dir(app_dir);
+    match app_meta.package_versions.iter_mut().find(|v| v.id == id) {
+        Some(package_version) => package_version.status = PackageVersionStatus::Published,
+        None => return Err(anyhow!("package version {} not found in app-meta.toml", id)),
+    }
+    match app_meta.to_dir(app_dir) {
+        Ok(()) => Ok(()),
+        Err(e) => Err(anyhow::Error::new(e)),
+    }
+}
@@ -1,5 +1,17 @@
+pub(crate) mod cache;
+pub(crate) mod changes;
 pub mod config;
+pub mod coverage;
+pub(crate) mod dependencies;
 pub mod enc_file;
+pub mod history;
 pub mod logger;
 
 pub(crate) mod meta;
+pub mod pool;
+pub mod project;
+pub mod report;
+pub(crate) mod scratch_org;
+pub(crate) mod setup;
+pub mod state;
+pub mod version;
@@ -0,0 +1,352 @@
+use std::path::Path;
+use std::process::Output;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::util::cache::CacheLayer;
+use crate::util::config::{CIConfig, PoolConfig};
+use crate::util::logger::{BuildLogger, Logger};
+use crate::util::scratch_org::ScratchOrg;
+use crate::{OrgStatus, SfdxClient};
+
+const POOL_FILE: &str = "pool.toml";
+
+/// # Scratch Org Pool
+/// Scratch orgs created ahead of the CI builds that use them, kept in the `org-pool` cache layer.
+/// Each org is tagged with the pool it belongs to and the definition it was created from, and
+/// is only checked out by builds asking for both.  A checked out org leaves the pool for good,
+/// to be deleted by the build, so the pool must be refilled with `fill`.  Orgs are addressed by
+/// username, as their aliases are only known to the CLI home directory that created them.  The
+/// pool is changed under the lock of its layer, as builds and `cli pool` may share it.
+pub struct OrgPool<'a, C: SfdxClient> {
+    client: &'a C,
+    hub_user: String,
+    config: PoolConfig,
+    layer: CacheLayer,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct OrgPoolFile {
+    #[serde(default)]
+    orgs: Vec<PooledOrg>,
+}
+
+/// A scratch org waiting in the pool, by the alias it was created with and its username.  Times
+/// are RFC 3339.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PooledOrg {
+    pub alias: String,
+    pub username: String,
+    pub tag: String,
+    pub org_def_path: String,
+    pub created: String,
+    pub expires: String,
+}
+
+impl PooledOrg {
+    /// Whether the org expires within `hours`.  Orgs whose expiry cannot be read are treated as
+    /// expired.
+    fn expires_within(&self, hours: i64) -> bool {
+        match DateTime::parse_from_rfc3339(&self.expires) {
+            Ok(expires) => expires.with_timezone(&Utc) <= Utc::now() + Duration::hours(hours),
+            Err(_) => true,
+        }
+    }
+}
+
+impl<'a, C: SfdxClient> OrgPool<'a, C> {
+    pub fn new(client: &'a C, hub_user: &str, config: &PoolConfig, layers_dir: &Path) -> Self {
+        OrgPool {
+            client,
+            hub_user: hub_user.to_string(),
+            config: config.clone(),
+            layer: CacheLayer::new(layers_dir, "org-pool"),
+        }
+    }
+
+    fn read(&self) -> Result<OrgPoolFile, anyhow::Error> {
+        Ok(self.layer.read(POOL_FILE)?.unwrap_or_default())
+    }
+
+    fn write(&self, pool: &OrgPoolFile) -> Result<(), anyhow::Error> {
+        self.layer.write(POOL_FILE, pool)
+    }
+
+    fn is_member(&self, org: &PooledOrg) -> bool {
+        org.tag == self.config.tag && org.org_def_path == self.config.org_def_path
+    }
+
+    fn is_active(&self, org: &PooledOrg) -> bool {
+        matches!(
+            self.client.check_org(&org.username),
+            Some(OrgStatus::Active)
+        )
+    }
+
+    /// Every org in the pool, whatever its tag.
+    pub fn orgs(&self) -> Result<Vec<PooledOrg>, anyhow::Error> {
+        Ok(self.read()?.orgs)
+    }
+
+    /// Take the oldest active org of this pool out of it, to be used and deleted by the caller.
+    /// Orgs that are no longer active are dropped from the pool on the way, while those about to
+    /// expire are left to `recycle`.  None if the pool has no org to give.
+    pub fn checkout(&self) -> Result<Option<PooledOrg>, anyhow::Error> {
+        let _lock = self.layer.lock()?;
+        let mut pool = self.read()?;
+        let mut checked_out = None;
+        let mut kept = vec![];
+        for org in pool.orgs.drain(..) {
+            if checked_out.is_some()
+                || !self.is_member(&org)
+                || org.expires_within(self.config.min_hours_left)
+            {
+                kept.push(org);
+            } else if self.is_active(&org) {
+                checked_out = Some(org);
+            }
+        }
+        pool.orgs = kept;
+        self.write(&pool)?;
+        Ok(checked_out)
+    }
+
+    /// Delete the orgs of this pool that are about to expire, and drop those that are no longer
+    /// active.  Returns the number of orgs removed.
+    pub fn recycle(&self, logger: &mut BuildLogger) -> Result<usize, anyhow::Error> {
+        let _lock = self.layer.lock()?;
+        let mut pool = self.read()?;
+        let mut kept = vec![];
+        let mut removed = 0;
+        for org in pool.orgs.drain(..) {
+            if !self.is_member(&org) {
+                kept.push(org);
+            } else if !self.is_active(&org) {
+                logger.info(format!("---> dropping inactive scratch org {}", org.alias))?;
+                removed += 1;
+            } else if org.expires_within(self.config.min_hours_left) {
+                logger.info(format!("---> deleting expiring scratch org {}", org.alias))?;
+                if let Err(e) = self.client.delete_org(&self.hub_user, &org.username) {
+                    logger.warning("---> Recycling scratch orgs", e)?;
+                }
+                removed += 1;
+            } else {
+                kept.push(org);
+            }
+        }
+        pool.orgs = kept;
+        self.write(&pool)?;
+        Ok(removed)
+    }
+
+    /// Recycle the pool, then create orgs until it holds `size` of them.  Each org is recorded
+    /// as soon as it is created, so that those created before a failure are kept.  Returns the
+    /// number of orgs created.
+    pub fn fill(&self, logger: &mut BuildLogger) -> Result<usize, anyhow::Error> {
+        self.recycle(logger)?;
+        let available = self
+            .read()?
+            .orgs
+            .iter()
+            .filter(|o| self.is_member(o))
+            .count();
+        let missing = (self.config.size as usize).saturating_sub(available);
+        for n in 0..missing {
+            let created = Utc::now();
+            let alias = format!(
+                "{}-pool-{}-{}",
+                self.config.tag,
+                created.format("%Y%m%d%H%M%S"),
+                n
+            );
+            logger.info(format!("---> creating scratch org {}", alias))?;
+            // Deleted when dropped, should the org not be recorded in the pool.
+            let (org, output) = ScratchOrg::create(
+                self.client,
+                &self.hub_user,
+                &self.config.org_def_path,
+                self.config.org_duration_days,
+                &alias,
+                false,
+            )?;
+            logger.output("---> Filling scratch org pool", output)?;
+            let username = match self.client.display_org(&alias) {
+                Some(info) => info.username,
+                None => {
+                    return Err(anyhow!(
+                        "failed to read the username of scratch org {}",
+                        alias
+                    ))
+                }
+            };
+            let _lock = self.layer.lock()?;
+            let mut pool = self.read()?;
+            pool.orgs.push(PooledOrg {
+                alias,
+                username,
+                tag: self.config.tag.clone(),
+                org_def_path: self.config.org_def_path.clone(),
+                created: created.to_rfc3339(),
+                expires: (created + Duration::days(self.config.org_duration_days.into()))
+                    .to_rfc3339(),
+            });
+            self.write(&pool)?;
+            org.keep();
+        }
+        Ok(missing)
+    }
+}
+
+/// The scratch org for a CI build or test run: checked out of the pool when one is configured
+/// and has an org to give, otherwise created, with the output of `force:org:create`.  Either way
+/// the org is deleted when dropped.
+pub(crate) fn ci_scratch_org<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    layers_dir: &Path,
+    config: &CIConfig,
+) -> Result<(ScratchOrg<C>, Option<Output>), anyhow::Error> {
+    if config.pool.is_enabled() {
+        let pool = OrgPool::new(client, &config.hub_user, &config.pool, layers_dir);
+        if let Some(org) = pool.checkout()? {
+            logger.info(format!(
+                "---> checked out scratch org {} from the pool",
+                org.alias
+            ))?;
+            return Ok((
+                ScratchOrg::adopt(client, &config.hub_user, &org.username),
+                None,
+            ));
+        }
+        logger.info("---> scratch org pool is empty")?;
+    }
+    logger.info("---> creating scratch org")?;
+    let (org, output) = ScratchOrg::create(
+        client,
+        &config.hub_user,
+        &config.org_def_path,
+        config.org_duration_days,
+        &config.org_alias,
+        false,
+    )?;
+    Ok((org, Some(output)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ScriptedSfdxClient;
+    use tempfile::tempdir;
+
+    const ACTIVE: &str = r#"{"status": 0, "result": {"id": "00D0t000000MeWZEA0", "accessToken": "token", "instanceUrl": "https://example.my.salesforce.com", "username": "test@example.com", "clientId": "PlatformCLI", "status": "Active"}}"#;
+
+    fn config(size: u32) -> PoolConfig {
+        PoolConfig {
+            size,
+            tag: "ci".to_string(),
+            org_def_path: "config/project-scratch-def.json".to_string(),
+            org_duration_days: 7,
+            min_hours_left: 2,
+        }
+    }
+
+    #[test]
+    fn it_fills_the_pool() {
+        let layers_dir = tempdir().unwrap();
+        let client = ScriptedSfdxClient::new();
+        client.respond("force:org:display", ACTIVE);
+        client.respond("force:org:display", ACTIVE);
+        let pool = OrgPool::new(&client, "hub", &config(2), layers_dir.path());
+
+        assert_eq!(pool.fill(&mut BuildLogger::new(false, false)).unwrap(), 2);
+
+        let orgs = pool.orgs().unwrap();
+        assert_eq!(orgs.len(), 2);
+        assert!(orgs.iter().all(|o| o.username == "test@example.com"));
+        assert!(orgs.iter().all(|o| o.alias.starts_with("ci-pool-")));
+        assert!(!orgs[0].expires_within(24 * 6));
+        assert!(layers_dir.path().join("org-pool.toml").is_file());
+        assert_eq!(
+            client.commands(),
+            vec![
+                "force:org:create",
+                "force:org:display",
+                "force:org:create",
+                "force:org:display"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_checks_out_active_orgs() {
+        let layers_dir = tempdir().unwrap();
+        let client = ScriptedSfdxClient::new();
+        let pool = OrgPool::new(&client, "hub", &config(3), layers_dir.path());
+        let org = |alias: &str, tag: &str, hours: i64| PooledOrg {
+            alias: alias.to_string(),
+            username: format!("{}@example.com", alias),
+            tag: tag.to_string(),
+            org_def_path: "config/project-scratch-def.json".to_string(),
+            created: Utc::now().to_rfc3339(),
+            expires: (Utc::now() + Duration::hours(hours)).to_rfc3339(),
+        };
+        pool.write(&OrgPoolFile {
+            orgs: vec![
+                org("expiring", "ci", 1),
+                org("other", "qa", 48),
+                org("gone", "ci", 48),
+                org("active", "ci", 48),
+                org("spare", "ci", 48),
+            ],
+        })
+        .unwrap();
+        client.respond(
+            "force:org:display",
+            r#"{"status": 1, "name": "NamedOrgNotFoundError", "message": "No org found"}"#,
+        );
+        client.respond("force:org:display", ACTIVE);
+
+        assert_eq!(pool.checkout().unwrap().unwrap().alias, "active");
+        let checked: Vec<String> = client
+            .calls()
+            .into_iter()
+            .map(|call| call.args[0].clone())
+            .collect();
+        assert_eq!(checked, vec!["gone@example.com", "active@example.com"]);
+        let left: Vec<String> = pool.orgs().unwrap().into_iter().map(|o| o.alias).collect();
+        assert_eq!(left, vec!["expiring", "other", "spare"]);
+
+        client.respond("force:org:display", ACTIVE);
+        client.respond("force:org:display", ACTIVE);
+        assert_eq!(
+            pool.recycle(&mut BuildLogger::new(false, false)).unwrap(),
+            1
+        );
+        let left: Vec<String> = pool.orgs().unwrap().into_iter().map(|o| o.alias).collect();
+        assert_eq!(left, vec!["other", "spare"]);
+        assert_eq!(client.commands().last().unwrap(), "force:org:display");
+        let delete = client
+            .calls()
+            .into_iter()
+            .find(|call| call.command == "force:org:delete")
+            .unwrap();
+        assert_eq!(delete.args, vec!["hub", "expiring@example.com"]);
+    }
+
+    #[test]
+    fn it_keeps_an_unreadable_pool() {
+        let layers_dir = tempdir().unwrap();
+        let client = ScriptedSfdxClient::new();
+        let pool = OrgPool::new(&client, "hub", &config(1), layers_dir.path());
+        let file = layers_dir.path().join("org-pool/pool.toml");
+        std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+        std::fs::write(&file, "orgs = [").unwrap();
+
+        assert!(pool.checkout().is_err());
+        assert!(pool.fill(&mut BuildLogger::new(false, false)).is_err());
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "orgs = [");
+        assert!(client.commands().is_empty());
+    }
+}
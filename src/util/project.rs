@@ -0,0 +1,140 @@
+use anyhow::Context;
+use indexmap::IndexMap;
+use libcnb::read_file_to_string;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::PathBuf;
+
+/// Typed model of `sfdx-project.json`.  Properties without a typed field are kept in `other`, so
+/// that reading and writing the file back does not lose anything.
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SfdxProject {
+    #[serde(default)]
+    pub package_directories: Vec<PackageDirectory>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sfdc_login_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_api_version: Option<String>,
+    #[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+    pub package_aliases: IndexMap<String, String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageDirectory {
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub definition_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<PackageDirectoryDependency>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ancestor_version: Option<String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PackageDirectoryDependency {
+    pub package: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_number: Option<String>,
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl SfdxProject {
+    pub fn from_dir(app_dir: &PathBuf) -> Result<Self, anyhow::Error> {
+        let file = app_dir.join("sfdx-project.json");
+        let file_text = read_file_to_string(file.as_path())
+            .with_context(|| format!("failed to read {}", file.to_string_lossy()))?;
+        serde_json::from_str(&file_text)
+            .with_context(|| format!("failed to parse {}", file.to_string_lossy()))
+    }
+
+    pub fn to_dir(&self, app_dir: &PathBuf) -> Result<(), anyhow::Error> {
+        let file = app_dir.join("sfdx-project.json");
+        let mut buf = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"    ");
+        let mut ser = serde_json::Serializer::with_formatter(&mut buf, formatter);
+        self.serialize(&mut ser)?;
+        buf.push(b'\n');
+        fs::write(&file, buf).with_context(|| format!("failed to write {}", file.to_string_lossy()))
+    }
+
+    /// The package directory declaring the named package.
+    pub fn package_directory(&self, package: &str) -> Option<&PackageDirectory> {
+        self.package_directories
+            .iter()
+            .find(|d| d.package.as_deref() == Some(package))
+    }
+
+    pub fn package_directory_mut(&mut self, package: &str) -> Option<&mut PackageDirectory> {
+        self.package_directories
+            .iter_mut()
+            .find(|d| d.package.as_deref() == Some(package))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn it_round_trips_the_project_file() {
+        let fixture = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sf-package");
+        let project = SfdxProject::from_dir(&fixture).unwrap();
+        assert_eq!(project.package_directories.len(), 4);
+        assert_eq!(project.source_api_version.as_deref(), Some("52.0"));
+
+        let app_dir = tempdir().unwrap().into_path();
+        project.to_dir(&app_dir).unwrap();
+        let written = SfdxProject::from_dir(&app_dir).unwrap();
+
+        let aliases: Vec<&String> = project.package_aliases.keys().collect();
+        let written_aliases: Vec<&String> = written.package_aliases.keys().collect();
+        assert_eq!(aliases, written_aliases);
+        assert_eq!(
+            serde_json::to_value(&project).unwrap(),
+            serde_json::to_value(&written).unwrap()
+        );
+    }
+
+    #[test]
+    fn it_keeps_unknown_properties() {
+        let project: SfdxProject = serde_json::from_str(
+            r#"{
+                "packageDirectories": [ { "path": "force-app", "unpackagedMetadata": { "path": "x" } } ],
+                "plugins": { "custom": true }
+            }"#,
+        )
+        .unwrap();
+        let value = serde_json::to_value(&project).unwrap();
+        assert_eq!(value["plugins"]["custom"], Value::Bool(true));
+        assert_eq!(
+            value["packageDirectories"][0]["unpackagedMetadata"]["path"],
+            Value::String("x".to_string())
+        );
+    }
+
+    #[test]
+    fn it_reports_missing_files() {
+        let app_dir = tempdir().unwrap().into_path();
+        assert!(SfdxProject::from_dir(&app_dir).is_err());
+    }
+}
@@ -0,0 +1,418 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use indexmap::IndexMap;
+use serde_json::json;
+
+use crate::util::config::TestResultsFormat;
+use crate::util::coverage::CoverageReport;
+use crate::{ApexTestOutcome, ApexTestResult, ApexTestRunResult, BuildLogger, Logger};
+
+/// # Test Report
+/// A report of an Apex test run written by the buildpack rather than by the sfdx reporter, so
+/// that it reads the same whichever version of sfdx ran the tests.  Tests are grouped by class,
+/// and `properties` carry context such as the org the tests ran in.
+pub struct TestReport<'a> {
+    result: &'a ApexTestRunResult,
+    properties: IndexMap<String, String>,
+}
+
+impl<'a> TestReport<'a> {
+    pub fn new(result: &'a ApexTestRunResult) -> Self {
+        let mut properties = IndexMap::new();
+        properties.insert("orgId".to_string(), result.summary.org_id.clone());
+        properties.insert("username".to_string(), result.summary.username.clone());
+        properties.insert("testRunId".to_string(), result.summary.test_run_id.clone());
+        TestReport { result, properties }
+    }
+
+    pub fn property(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.insert(name.into(), value.into());
+        self
+    }
+
+    /// The tests of each class, in the order the classes were first reported.
+    fn classes(&self) -> IndexMap<String, Vec<&'a ApexTestResult>> {
+        let mut classes: IndexMap<String, Vec<&ApexTestResult>> = IndexMap::new();
+        for test in self.result.tests.iter() {
+            classes.entry(class_name(test)).or_default().push(test);
+        }
+        classes
+    }
+
+    pub fn render(&self, format: &TestResultsFormat) -> String {
+        match format {
+            TestResultsFormat::Human => self.human(),
+            TestResultsFormat::TAP => self.tap(),
+            TestResultsFormat::JUnit => self.junit(),
+            TestResultsFormat::JSON => self.json(),
+        }
+    }
+
+    /// Write the report into `dir`, returning the file written.
+    pub fn write(&self, dir: &Path, format: &TestResultsFormat) -> Result<PathBuf, anyhow::Error> {
+        fs::create_dir_all(dir)?;
+        let file = dir.join(file_name(format));
+        fs::write(&file, self.render(format))?;
+        Ok(file)
+    }
+
+    pub fn junit(&self) -> String {
+        let summary = &self.result.summary;
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        let _ = writeln!(
+            xml,
+            "<testsuites name=\"force.apex\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{}\">",
+            summary.tests_ran,
+            summary.failing,
+            summary.skipped,
+            seconds(self.result.tests.iter().map(|t| t.run_time).sum())
+        );
+        for (class, tests) in self.classes() {
+            let _ = writeln!(
+                xml,
+                "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{}\" timestamp=\"{}\">",
+                escape(&class),
+                tests.len(),
+                count(&tests, |o| matches!(o, ApexTestOutcome::Fail)),
+                count(&tests, |o| matches!(o, ApexTestOutcome::Ignore)),
+                seconds(tests.iter().map(|t| t.run_time).sum()),
+                escape(&summary.test_start_time)
+            );
+            xml.push_str("    <properties>\n");
+            for (name, value) in self.properties.iter() {
+                let _ = writeln!(
+                    xml,
+                    "      <property name=\"{}\" value=\"{}\"/>",
+                    escape(name),
+                    escape(value)
+                );
+            }
+            xml.push_str("    </properties>\n");
+            for test in tests {
+                let _ = write!(
+                    xml,
+                    "    <testcase name=\"{}\" classname=\"{}\" time=\"{}\"",
+                    escape(&test.method_name),
+                    escape(&class),
+                    seconds(test.run_time)
+                );
+                match test.outcome {
+                    ApexTestOutcome::Pass => xml.push_str("/>\n"),
+                    ApexTestOutcome::Ignore => {
+                        xml.push_str(">\n      <skipped/>\n    </testcase>\n")
+                    }
+                    ApexTestOutcome::Fail => {
+                        let _ = writeln!(
+                            xml,
+                            ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>",
+                            escape(test.message.as_deref().unwrap_or("")),
+                            escape(test.stack_trace.as_deref().unwrap_or(""))
+                        );
+                    }
+                }
+            }
+            xml.push_str("  </testsuite>\n");
+        }
+        xml.push_str("</testsuites>\n");
+        xml
+    }
+
+    /// TAP version 13, with failure details and runtimes in YAML blocks.
+    pub fn tap(&self) -> String {
+        let mut tap = String::from("TAP version 13\n");
+        let _ = writeln!(tap, "1..{}", self.result.tests.len());
+        for (name, value) in self.properties.iter() {
+            let _ = writeln!(tap, "# {}: {}", name, value);
+        }
+        let mut n = 0;
+        for (class, tests) in self.classes() {
+            let _ = writeln!(tap, "# {}", class);
+            for test in tests {
+                n += 1;
+                let name = format!("{}.{}", class, test.method_name);
+                match test.outcome {
+                    ApexTestOutcome::Pass => {
+                        let _ = writeln!(tap, "ok {} - {}", n, name);
+                    }
+                    ApexTestOutcome::Ignore => {
+                        let _ = writeln!(tap, "ok {} - {} # SKIP", n, name);
+                    }
+                    ApexTestOutcome::Fail => {
+                        let _ = writeln!(tap, "not ok {} - {}", n, name);
+                    }
+                }
+                tap.push_str("  ---\n");
+                if let ApexTestOutcome::Fail = test.outcome {
+                    yaml_block(&mut tap, "message", test.message.as_deref());
+                    yaml_block(&mut tap, "stack", test.stack_trace.as_deref());
+                }
+                let _ = writeln!(tap, "  duration_ms: {}", test.run_time);
+                tap.push_str("  ...\n");
+            }
+        }
+        tap
+    }
+
+    pub fn json(&self) -> String {
+        let summary = &self.result.summary;
+        let classes: Vec<serde_json::Value> = self
+            .classes()
+            .into_iter()
+            .map(|(class, tests)| {
+                let tests: Vec<serde_json::Value> = tests
+                    .iter()
+                    .map(|test| {
+                        json!({
+                            "name": test.method_name,
+                            "fullName": test.full_name,
+                            "outcome": outcome(test),
+                            "runTime": test.run_time,
+                            "message": test.message,
+                            "stackTrace": test.stack_trace,
+                        })
+                    })
+                    .collect();
+                json!({ "name": class, "tests": tests })
+            })
+            .collect();
+        let report = json!({
+            "summary": {
+                "outcome": if summary.failing > 0 { "Failed" } else { "Passed" },
+                "testsRan": summary.tests_ran,
+                "passing": summary.passing,
+                "failing": summary.failing,
+                "skipped": summary.skipped,
+                "testStartTime": summary.test_start_time,
+                "testTotalTime": summary.test_total_time,
+                "coveragePercent": self.result.coverage_percent(),
+                "flaky": self.result.flaky,
+                "quarantined": self.result.quarantined,
+            },
+            "properties": self.properties,
+            "classes": classes,
+        });
+        let mut json = serde_json::to_string_pretty(&report).unwrap_or_default();
+        json.push('\n');
+        json
+    }
+
+    pub fn human(&self) -> String {
+        let summary = &self.result.summary;
+        let mut text = String::new();
+        for (class, tests) in self.classes() {
+            let _ = writeln!(text, "{}", class);
+            for test in tests {
+                let _ = writeln!(
+                    text,
+                    "  {:<4} {} ({} ms)",
+                    outcome(test).to_uppercase(),
+                    test.method_name,
+                    test.run_time
+                );
+                if let Some(message) = &test.message {
+                    let _ = writeln!(text, "       {}", message);
+                }
+            }
+        }
+        let _ = writeln!(
+            text,
+            "\n{} tests ran, {} passed, {} failed, {} skipped",
+            summary.tests_ran, summary.passing, summary.failing, summary.skipped
+        );
+        if let Some(percent) = self.result.coverage_percent() {
+            let _ = writeln!(text, "{:.1}% of lines covered", percent);
+        }
+        for name in self.result.flaky.iter() {
+            let _ = writeln!(text, "{} passed only when rerun", name);
+        }
+        for name in self.result.quarantined.iter() {
+            let _ = writeln!(text, "{} is quarantined, its failure was ignored", name);
+        }
+        for (name, value) in self.properties.iter() {
+            let _ = writeln!(text, "{}: {}", name, value);
+        }
+        text
+    }
+}
+
+/// Write the report of a test run into the app's `results_path`, if one is configured, along
+/// with Cobertura and LCOV reports of its code coverage.
+pub(crate) fn write_report(
+    report: &TestReport,
+    app_dir: &Path,
+    results_path: &Option<String>,
+    format: &TestResultsFormat,
+    logger: &mut BuildLogger,
+) -> Result<(), anyhow::Error> {
+    if let Some(path) = results_path {
+        let dir = app_dir.join(path);
+        let file = report.write(&dir, format)?;
+        logger.info(format!("---> wrote test report {}", file.to_string_lossy()))?;
+        if let Some(coverage) = &report.result.coverage {
+            for file in CoverageReport::new(coverage, app_dir).write(&dir)? {
+                logger.info(format!(
+                    "---> wrote coverage report {}",
+                    file.to_string_lossy()
+                ))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn file_name(format: &TestResultsFormat) -> &'static str {
+    match format {
+        TestResultsFormat::Human => "test-result.txt",
+        TestResultsFormat::TAP => "test-result.tap",
+        TestResultsFormat::JUnit => "test-result-junit.xml",
+        TestResultsFormat::JSON => "test-result.json",
+    }
+}
+
+fn class_name(test: &ApexTestResult) -> String {
+    match &test.apex_class.namespace_prefix {
+        Some(prefix) => format!("{}.{}", prefix, test.apex_class.name),
+        None => test.apex_class.name.clone(),
+    }
+}
+
+fn outcome(test: &ApexTestResult) -> &'static str {
+    match test.outcome {
+        ApexTestOutcome::Pass => "Pass",
+        ApexTestOutcome::Fail => "Fail",
+        ApexTestOutcome::Ignore => "Skip",
+    }
+}
+
+fn count(tests: &[&ApexTestResult], outcome: impl Fn(&ApexTestOutcome) -> bool) -> usize {
+    tests.iter().filter(|t| outcome(&t.outcome)).count()
+}
+
+fn seconds(millis: i32) -> String {
+    format!("{:.3}", f64::from(millis) / 1000.0)
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn yaml_block(tap: &mut String, key: &str, value: Option<&str>) {
+    if let Some(value) = value {
+        let _ = writeln!(tap, "  {}: |-", key);
+        for line in value.lines() {
+            let _ = writeln!(tap, "    {}", line);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ApexTestClass, ApexTestSummary, ApexTestSummaryOutcome};
+
+    fn test(method_name: &str, outcome: ApexTestOutcome, message: Option<&str>) -> ApexTestResult {
+        ApexTestResult {
+            id: "07M0t00000FfffwEAB".to_string(),
+            queue_item_id: "7090t0000022UUlAAM".to_string(),
+            stack_trace: message.map(|_| format!("Class.TestTests.{}: line 12", method_name)),
+            message: message.map(String::from),
+            async_apex_job_id: "7070t00001vpgqxAAA".to_string(),
+            method_name: method_name.to_string(),
+            outcome,
+            apex_class: ApexTestClass {
+                id: "01p0t00000FKeStAAL".to_string(),
+                name: "TestTests".to_string(),
+                namespace_prefix: None,
+            },
+            run_time: 11,
+            full_name: format!("TestTests.{}", method_name),
+        }
+    }
+
+    fn run() -> ApexTestRunResult {
+        ApexTestRunResult {
+            summary: ApexTestSummary {
+                outcome: ApexTestSummaryOutcome::Failed,
+                tests_ran: 3,
+                passing: 1,
+                failing: 1,
+                skipped: 1,
+                pass_rate: "33%".to_string(),
+                fail_rate: "33%".to_string(),
+                test_start_time: "Thu Jan 06 2022 2:44:54 PM".to_string(),
+                test_execution_time: "33 ms".to_string(),
+                test_total_time: "33 ms".to_string(),
+                command_time: "244 ms".to_string(),
+                hostname: "https://velocity-energy-3793-dev-ed.cs77.my.salesforce.com".to_string(),
+                org_id: "00D0t000000MeWZEA0".to_string(),
+                username: "test-ahmet6briymu@example.com".to_string(),
+                test_run_id: "7070t00001vpgqx".to_string(),
+                user_id: "0050t000009C5sDAAS".to_string(),
+                test_run_coverage: "100%".to_string(),
+                org_wide_coverage: "100%".to_string(),
+            },
+            tests: vec![
+                test("testBehavior", ApexTestOutcome::Pass, None),
+                test(
+                    "testGreeting",
+                    ApexTestOutcome::Fail,
+                    Some("System.AssertException: Expected: <Hello>"),
+                ),
+                test("testLater", ApexTestOutcome::Ignore, None),
+            ],
+            coverage: None,
+            flaky: vec![],
+            quarantined: vec![],
+        }
+    }
+
+    #[test]
+    fn it_writes_junit() {
+        let run = run();
+        let xml = TestReport::new(&run)
+            .property("packageVersion", "1.0.0.6")
+            .junit();
+        assert!(xml.contains(
+            r#"<testsuite name="TestTests" tests="3" failures="1" skipped="1" time="0.033""#
+        ));
+        assert!(xml.contains(r#"<property name="orgId" value="00D0t000000MeWZEA0"/>"#));
+        assert!(xml.contains(r#"<property name="packageVersion" value="1.0.0.6"/>"#));
+        assert!(
+            xml.contains(r#"<testcase name="testBehavior" classname="TestTests" time="0.011"/>"#)
+        );
+        assert!(xml.contains(
+            r#"<failure message="System.AssertException: Expected: &lt;Hello&gt;">Class.TestTests.testGreeting: line 12</failure>"#
+        ));
+        assert!(xml.contains("<skipped/>"));
+    }
+
+    #[test]
+    fn it_writes_tap() {
+        let run = run();
+        let tap = TestReport::new(&run).tap();
+        assert!(tap.starts_with("TAP version 13\n1..3\n"));
+        assert!(tap.contains("ok 1 - TestTests.testBehavior\n"));
+        assert!(tap.contains(
+            "not ok 2 - TestTests.testGreeting\n  ---\n  message: |-\n    System.AssertException: Expected: <Hello>\n"
+        ));
+        assert!(tap.contains("ok 3 - TestTests.testLater # SKIP\n"));
+        assert!(tap.contains("  duration_ms: 11\n"));
+    }
+
+    #[test]
+    fn it_writes_json() {
+        let run = run();
+        let json: serde_json::Value = serde_json::from_str(&TestReport::new(&run).json()).unwrap();
+        assert_eq!(json["summary"]["outcome"], "Failed");
+        assert_eq!(json["properties"]["testRunId"], "7070t00001vpgqx");
+        assert_eq!(json["classes"][0]["name"], "TestTests");
+        assert_eq!(json["classes"][0]["tests"][1]["outcome"], "Fail");
+        assert_eq!(json["classes"][0]["tests"][2]["outcome"], "Skip");
+    }
+}
@@ -0,0 +1,197 @@
+use lazy_static::lazy_static;
+use std::process::{self, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard, Once};
+
+use crate::client::SfdxClient;
+use crate::util::logger::{BuildLogger, Logger};
+
+/// A scratch org created by this process, with the means to delete it.
+struct LiveOrg {
+    id: usize,
+    alias: String,
+    delete: Box<dyn Fn() -> Result<Output, anyhow::Error> + Send>,
+}
+
+lazy_static! {
+    /// Scratch orgs still owned by a guard, deleted if the process is interrupted.
+    static ref LIVE_ORGS: Mutex<Vec<LiveOrg>> = Mutex::new(vec![]);
+}
+
+static HANDLER: Once = Once::new();
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Orgs must still be cleaned up after a panic while the registry was locked.
+fn live_orgs() -> MutexGuard<'static, Vec<LiveOrg>> {
+    LIVE_ORGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Delete every live scratch org on SIGINT or SIGTERM before exiting, as the guards of an
+/// interrupted process are never dropped.
+fn install_interrupt_handler(logger: &mut BuildLogger) {
+    HANDLER.call_once(|| {
+        // The handler runs on a thread of its own with no logger to hand, so it reports straight
+        // to stderr.
+        let result = ctrlc::set_handler(|| {
+            let orgs: Vec<LiveOrg> = live_orgs().drain(..).collect();
+            for org in orgs {
+                eprintln!("---> interrupted, deleting scratch org {}", org.alias);
+                if let Err(e) = (org.delete)() {
+                    eprintln!("{}", e);
+                }
+            }
+            process::exit(130);
+        });
+        if let Err(e) = result {
+            let _ = logger.warning("failed to install interrupt handler", e);
+        }
+    });
+}
+
+/// # Scratch Org Guard
+/// Owns a scratch org created for a single build or test run, and deletes it when dropped, which
+/// includes returning early with an error and unwinding from a panic.  The org is also deleted
+/// when the process receives SIGINT or SIGTERM.  Call `keep` to opt out, as Dev mode does, or
+/// `delete` to delete the org and see whether that succeeded.
+pub struct ScratchOrg<C: SfdxClient> {
+    client: C,
+    hub_user: String,
+    alias: String,
+    id: Option<usize>,
+    /// Reports deletion on drop, when the caller's logger is no longer to hand.
+    logger: BuildLogger,
+}
+
+impl<C: SfdxClient> ScratchOrg<C> {
+    /// Create a scratch org through the client and take ownership of it.
+    pub fn create(
+        client: &C,
+        hub_user: &str,
+        scratch_org_def_path: &str,
+        scratch_org_duration: i32,
+        scratch_org_alias: &str,
+        no_namespace: bool,
+    ) -> Result<(ScratchOrg<C>, Output), anyhow::Error> {
+        let mut logger = BuildLogger::new(true, true);
+        install_interrupt_handler(&mut logger);
+        let output = client.create_org(
+            hub_user,
+            scratch_org_def_path,
+            scratch_org_duration,
+            scratch_org_alias,
+            no_namespace,
+        )?;
+        Ok((
+            ScratchOrg::register(client.clone(), hub_user, scratch_org_alias, logger),
+            output,
+        ))
+    }
+
+    /// Take ownership of a scratch org created earlier, such as one checked out of a pool.
+    pub fn adopt(client: &C, hub_user: &str, scratch_org_alias: &str) -> ScratchOrg<C> {
+        let mut logger = BuildLogger::new(true, true);
+        install_interrupt_handler(&mut logger);
+        ScratchOrg::register(client.clone(), hub_user, scratch_org_alias, logger)
+    }
+
+    fn register(client: C, hub_user: &str, alias: &str, logger: BuildLogger) -> ScratchOrg<C> {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let (deleter, hub, name) = (client.clone(), hub_user.to_string(), alias.to_string());
+        live_orgs().push(LiveOrg {
+            id,
+            alias: alias.to_string(),
+            delete: Box::new(move || deleter.delete_org(&hub, &name)),
+        });
+        ScratchOrg {
+            client,
+            hub_user: hub_user.to_string(),
+            alias: alias.to_string(),
+            id: Some(id),
+            logger,
+        }
+    }
+
+    pub fn alias(&self) -> &str {
+        &self.alias
+    }
+
+    /// Release the org without deleting it.
+    pub fn keep(mut self) {
+        self.release();
+    }
+
+    /// Delete the org now, reporting failure to the caller rather than to stderr.
+    pub fn delete(mut self) -> Result<Output, anyhow::Error> {
+        self.release();
+        self.client.delete_org(&self.hub_user, &self.alias)
+    }
+
+    /// Unregister the org, returning whether it was still owned.
+    fn release(&mut self) -> bool {
+        match self.id.take() {
+            Some(id) => {
+                live_orgs().retain(|o| o.id != id);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<C: SfdxClient> Drop for ScratchOrg<C> {
+    fn drop(&mut self) {
+        if self.release() {
+            let _ = self
+                .logger
+                .info(format!("---> deleting scratch org {}", self.alias));
+            if let Err(e) = self.client.delete_org(&self.hub_user, &self.alias) {
+                let _ = self.logger.error(
+                    format!("---> Failed to delete scratch org {}", self.alias),
+                    e,
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ScriptedSfdxClient;
+
+    fn test_logger() -> BuildLogger {
+        BuildLogger::new(false, false)
+    }
+
+    fn is_live(alias: &str) -> bool {
+        live_orgs().iter().any(|o| o.alias == alias)
+    }
+
+    #[test]
+    fn it_deletes_dropped_orgs() {
+        let client = ScriptedSfdxClient::new();
+        {
+            let _org = ScratchOrg::register(client.clone(), "hub", "dropped-org", test_logger());
+            assert!(is_live("dropped-org"));
+        }
+        assert!(!is_live("dropped-org"));
+        assert_eq!(client.commands(), vec!["force:org:delete"]);
+    }
+
+    #[test]
+    fn it_keeps_released_orgs() {
+        let client = ScriptedSfdxClient::new();
+        let org = ScratchOrg::register(client.clone(), "hub", "kept-org", test_logger());
+        org.keep();
+        assert!(!is_live("kept-org"));
+        assert!(client.commands().is_empty());
+    }
+
+    #[test]
+    fn it_deletes_orgs_once() {
+        let client = ScriptedSfdxClient::new();
+        let org = ScratchOrg::register(client.clone(), "hub", "deleted-org", test_logger());
+        org.delete().unwrap();
+        assert_eq!(client.commands(), vec!["force:org:delete"]);
+    }
+}
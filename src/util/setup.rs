@@ -0,0 +1,113 @@
+use crate::util::config::{OnFailure, SetupAction, SetupStep};
+use crate::util::logger::{BuildLogger, Logger};
+use crate::SfdxClient;
+
+/// Run the setup steps against a scratch org, in order.  A step that fails with `on_failure` set
+/// to `continue` is logged as a warning and the steps after it still run, otherwise the first
+/// failure is returned.
+pub(crate) fn run_setup<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    hub_user: &str,
+    scratch_org_alias: &str,
+    steps: &[SetupStep],
+) -> Result<(), anyhow::Error> {
+    for (n, step) in steps.iter().enumerate() {
+        logger.info(format!("---> {}", step.action))?;
+        if let Err(e) = run_step(client, logger, hub_user, scratch_org_alias, &step.action) {
+            let header = format!("---> Setup step {} failed", n + 1);
+            match step.on_failure {
+                OnFailure::Continue => logger.warning(header, e)?,
+                OnFailure::Fail => return Err(logger.fail(header, e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn run_step<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    hub_user: &str,
+    scratch_org_alias: &str,
+    action: &SetupAction,
+) -> Result<(), anyhow::Error> {
+    let output = match action {
+        SetupAction::Permset(name) => client.assign_permset(scratch_org_alias, name)?,
+        SetupAction::DataPlan(path) => client.import_data(scratch_org_alias, path)?,
+        SetupAction::Apex(path) => {
+            let result = client.execute_apex(scratch_org_alias, path)?;
+            logger.debug(&result.logs)?;
+            return Ok(result.check(path)?);
+        }
+        SetupAction::User(path) => client.create_user(hub_user, scratch_org_alias, path)?,
+    };
+    logger.output(action, output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ScriptedSfdxClient;
+    use crate::{find_buildpack_error, BuildpackError};
+
+    fn steps(on_failure: OnFailure) -> Vec<SetupStep> {
+        vec![
+            SetupStep {
+                action: SetupAction::Apex("scripts/apex/setup.apex".to_string()),
+                on_failure,
+            },
+            SetupStep {
+                action: SetupAction::Permset("Admin".to_string()),
+                on_failure: OnFailure::Fail,
+            },
+        ]
+    }
+
+    const APEX_EXCEPTION: &str = r#"{"status": 0, "result": {"compiled": true, "success": false, "compileProblem": "", "exceptionMessage": "System.NullPointerException: Attempt to de-reference a null object", "exceptionStackTrace": "AnonymousBlock: line 1, column 1", "line": 1, "column": 1, "logs": ""}}"#;
+
+    #[test]
+    fn it_stops_at_failed_steps() {
+        let client = ScriptedSfdxClient::new();
+        client.respond("force:apex:execute", APEX_EXCEPTION);
+
+        let error = run_setup(
+            &client,
+            &mut BuildLogger::new(false, false),
+            "hub",
+            "ci",
+            &steps(OnFailure::Fail),
+        )
+        .unwrap_err();
+
+        match find_buildpack_error(&error) {
+            Some(BuildpackError::SfdxCommandFailed { name, message, .. }) => {
+                assert_eq!(name, "ApexException");
+                assert!(message.contains("NullPointerException"));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(client.commands(), vec!["force:apex:execute"]);
+    }
+
+    #[test]
+    fn it_continues_past_steps_allowed_to_fail() {
+        let client = ScriptedSfdxClient::new();
+        client.respond("force:apex:execute", APEX_EXCEPTION);
+
+        run_setup(
+            &client,
+            &mut BuildLogger::new(false, false),
+            "hub",
+            "ci",
+            &steps(OnFailure::Continue),
+        )
+        .unwrap();
+
+        assert_eq!(
+            client.commands(),
+            vec!["force:apex:execute", "force:user:permset:assign"]
+        );
+        assert_eq!(client.calls()[1].args, vec!["ci", "Admin"]);
+    }
+}
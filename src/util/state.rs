@@ -0,0 +1,190 @@
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+use serde::{Deserialize, Serialize};
+
+use crate::util::cache::CacheLayer;
+
+const STATE_LAYER: &str = "sfdx-state";
+const STATE_FILE: &str = "state.toml";
+
+/// The directories of the project in which sfdx and sf keep their source tracking.
+const PROJECT_STATE_DIRS: [&str; 2] = [".sfdx", ".sf"];
+
+/// # sfdx State
+/// What the CLI remembers between commands, kept in the `sfdx-state` cache layer so that later
+/// builds reuse it.  The CLI runs with the layer's `home/` as its home directory, where it keeps
+/// the auth files and aliases of the hub and scratch orgs.  The source tracking it keeps in the
+/// project's `.sfdx` and `.sf` directories is copied into the layer after the build, and back
+/// into projects that have none.
+///
+/// Source tracking is only kept in Dev mode, where the scratch org outlives the build.  CI and
+/// Package builds push to a new scratch org each time, which tracking kept for an org that has
+/// since been deleted would only confuse, so they restore and save the home directory alone.
+///
+/// The state belongs to one Dev Hub, the `hub_user` and `hub_instance_url` of the `[default]`
+/// table of `app.toml`.  It is discarded when either changes, or when `SFDX_STATE_RESET` is set.
+/// `cli` commands run without `--layers` keep no state, and the CLI uses the user's home.
+pub struct SfdxState {
+    layer: CacheLayer,
+    app_dir: PathBuf,
+}
+
+/// The hub the state belongs to.
+#[derive(Deserialize, Serialize, Debug, Default, PartialEq)]
+struct SfdxStateFile {
+    #[serde(default)]
+    hub_user: String,
+    #[serde(default)]
+    hub_instance_url: String,
+}
+
+impl SfdxState {
+    pub fn new(layers_dir: &Path, app_dir: &Path) -> Self {
+        SfdxState {
+            layer: CacheLayer::new(layers_dir, STATE_LAYER),
+            app_dir: app_dir.to_path_buf(),
+        }
+    }
+
+    /// The home directory of the CLI, which only exists once the state has been restored.  It is
+    /// absolute, as the CLI runs in the app directory rather than the current one.
+    pub fn home(layers_dir: &Path) -> PathBuf {
+        let home = layers_dir.join(STATE_LAYER).join("home");
+        match env::current_dir() {
+            Ok(dir) => dir.join(home),
+            Err(_) => home,
+        }
+    }
+
+    fn project_dir(&self) -> PathBuf {
+        self.layer.dir().join("project")
+    }
+
+    /// Restore the home directory of earlier builds for the hub, discarding any state kept for
+    /// another hub or, with `reset`, all of it.  Returns whether there was state to restore.
+    pub fn restore(
+        &self,
+        hub_user: &str,
+        hub_instance_url: &str,
+        reset: bool,
+    ) -> Result<bool, anyhow::Error> {
+        let key = SfdxStateFile {
+            hub_user: hub_user.to_string(),
+            hub_instance_url: hub_instance_url.to_string(),
+        };
+        // State that cannot be read is discarded, like that of another hub.
+        let kept: Option<SfdxStateFile> = self.layer.read(STATE_FILE).ok().flatten();
+        let restored = !reset && kept.as_ref() == Some(&key);
+        if !restored {
+            self.layer.remove()?;
+        }
+
+        fs::create_dir_all(self.layer.dir().join("home"))?;
+        self.layer.write(STATE_FILE, &key)?;
+        Ok(restored)
+    }
+
+    /// Copy the source tracking kept by `save` back into the project, if it has none of its own.
+    pub fn restore_tracking(&self) -> Result<(), anyhow::Error> {
+        for name in PROJECT_STATE_DIRS.iter() {
+            let kept = self.project_dir().join(name);
+            // Source tracking that came with the project is newer than that of the layer.
+            if kept.is_dir() && !self.app_dir.join(name).exists() {
+                copy_dir(&kept, &self.app_dir.join(name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep the source tracking of the project for the next build.
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        for name in PROJECT_STATE_DIRS.iter() {
+            let kept = self.project_dir().join(name);
+            if kept.exists() {
+                fs::remove_dir_all(&kept)?;
+            }
+            if self.app_dir.join(name).is_dir() {
+                copy_dir(&self.app_dir.join(name), &kept)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn copy_dir(from: &Path, to: &Path) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const HUB: &str = "hub@example.com";
+    const URL: &str = "https://example.my.salesforce.com";
+
+    #[test]
+    fn it_has_an_absolute_home() {
+        let home = SfdxState::home(Path::new("layers"));
+        assert!(home.is_absolute());
+        assert!(home.ends_with("layers/sfdx-state/home"));
+    }
+
+    #[test]
+    fn it_keeps_state_across_builds() {
+        let layers_dir = tempdir().unwrap();
+        let app_dir = tempdir().unwrap();
+        let state = SfdxState::new(layers_dir.path(), app_dir.path());
+
+        assert!(!state.restore(HUB, URL, false).unwrap());
+        assert!(SfdxState::home(layers_dir.path()).is_dir());
+        assert!(layers_dir.path().join("sfdx-state.toml").is_file());
+        fs::write(
+            SfdxState::home(layers_dir.path()).join("alias.json"),
+            r#"{"orgs": {"dev": "test@example.com"}}"#,
+        )
+        .unwrap();
+        fs::create_dir_all(app_dir.path().join(".sfdx/orgs")).unwrap();
+        fs::write(app_dir.path().join(".sfdx/orgs/maxRevision.json"), "{}").unwrap();
+        state.save().unwrap();
+
+        // The next build starts from a fresh checkout of the project.
+        let app_dir = tempdir().unwrap();
+        let state = SfdxState::new(layers_dir.path(), app_dir.path());
+        assert!(state.restore(HUB, URL, false).unwrap());
+        assert!(SfdxState::home(layers_dir.path())
+            .join("alias.json")
+            .is_file());
+        assert!(!app_dir.path().join(".sfdx").exists());
+        state.restore_tracking().unwrap();
+        assert!(app_dir.path().join(".sfdx/orgs/maxRevision.json").is_file());
+    }
+
+    #[test]
+    fn it_discards_state_of_other_hubs() {
+        let layers_dir = tempdir().unwrap();
+        let app_dir = tempdir().unwrap();
+        let state = SfdxState::new(layers_dir.path(), app_dir.path());
+        let alias_file = SfdxState::home(layers_dir.path()).join("alias.json");
+
+        state.restore(HUB, URL, false).unwrap();
+        fs::write(&alias_file, "{}").unwrap();
+        assert!(!state.restore("other@example.com", URL, false).unwrap());
+        assert!(!alias_file.exists());
+
+        fs::write(&alias_file, "{}").unwrap();
+        assert!(!state.restore("other@example.com", URL, true).unwrap());
+        assert!(!alias_file.exists());
+    }
+}
@@ -0,0 +1,268 @@
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// A package version number, `major.minor.patch.build`, where the build may be one of the
+/// `NEXT` or `LATEST` tokens understood by sfdx.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionNumber {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    pub build: BuildNumber,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BuildNumber {
+    Number(u32),
+    Next,
+    Latest,
+}
+
+/// Which part of the version number to raise once the configured version has been released.
+#[derive(Deserialize, Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VersionBump {
+    None,
+    Major,
+    Minor,
+    Patch,
+}
+
+impl Default for VersionBump {
+    fn default() -> Self {
+        VersionBump::None
+    }
+}
+
+impl VersionNumber {
+    /// The `major.minor.patch` part, which identifies a release of a package.
+    pub fn release(&self) -> (u32, u32, u32) {
+        (self.major, self.minor, self.patch)
+    }
+
+    /// The version number following this release under the given policy, building `NEXT`.
+    pub fn bump(&self, bump: VersionBump) -> VersionNumber {
+        let (major, minor, patch) = match bump {
+            VersionBump::None => self.release(),
+            VersionBump::Major => (self.major + 1, 0, 0),
+            VersionBump::Minor => (self.major, self.minor + 1, 0),
+            VersionBump::Patch => (self.major, self.minor, self.patch + 1),
+        };
+        VersionNumber {
+            major,
+            minor,
+            patch,
+            build: BuildNumber::Next,
+        }
+    }
+
+    /// The version number to create next, given the versions already built.  A release that has
+    /// been promoted cannot get more builds, so once the configured release is released the
+    /// version is bumped past the latest released version, or refused when there is no policy.
+    pub fn next(
+        &self,
+        bump: VersionBump,
+        built: &[VersionNumber],
+        released: &[VersionNumber],
+    ) -> Result<VersionNumber, anyhow::Error> {
+        if let BuildNumber::Latest = self.build {
+            return Err(anyhow!(
+                "version number {} cannot be built: LATEST is only valid for dependencies",
+                self
+            ));
+        }
+
+        let latest_released = released.iter().max();
+        if let Some(latest) = latest_released {
+            if latest.release() >= self.release() {
+                if let VersionBump::None = bump {
+                    return Err(anyhow!(
+                        "version {} is already released, but version number {} is configured.  \
+                        Raise versionNumber or set a bump policy.",
+                        latest,
+                        self
+                    ));
+                }
+                let mut next = latest.bump(bump);
+                if let BuildNumber::Number(_) = self.build {
+                    next.build = BuildNumber::Number(1);
+                }
+                return Ok(next);
+            }
+        }
+
+        if built.iter().any(|v| v == self) {
+            return Err(anyhow!("version {} has already been built", self));
+        }
+        Ok(self.clone())
+    }
+}
+
+impl FromStr for VersionNumber {
+    type Err = anyhow::Error;
+
+    fn from_str(version: &str) -> Result<VersionNumber, Self::Err> {
+        let segments: Vec<&str> = version.trim().split(|c| c == '.' || c == '-').collect();
+        if segments.len() != 4 {
+            return Err(anyhow!(
+                "invalid version number {}: expected major.minor.patch.build",
+                version
+            ));
+        }
+        let number = |segment: &str| {
+            segment.parse::<u32>().map_err(|_| {
+                anyhow!(
+                    "invalid version number {}: {} is not a number",
+                    version,
+                    segment
+                )
+            })
+        };
+        let build = match segments[3] {
+            "NEXT" => BuildNumber::Next,
+            "LATEST" => BuildNumber::Latest,
+            build => BuildNumber::Number(number(build)?),
+        };
+        Ok(VersionNumber {
+            major: number(segments[0])?,
+            minor: number(segments[1])?,
+            patch: number(segments[2])?,
+            build,
+        })
+    }
+}
+
+impl fmt::Display for VersionNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}.", self.major, self.minor, self.patch)?;
+        match self.build {
+            BuildNumber::Number(build) => write!(f, "{}", build),
+            BuildNumber::Next => write!(f, "NEXT"),
+            BuildNumber::Latest => write!(f, "LATEST"),
+        }
+    }
+}
+
+impl Ord for VersionNumber {
+    /// Orders by release, then build.  `NEXT` and `LATEST` sort after any built number.
+    fn cmp(&self, other: &Self) -> Ordering {
+        let build = |b: &BuildNumber| match b {
+            BuildNumber::Number(n) => (0, *n),
+            BuildNumber::Next => (1, 0),
+            BuildNumber::Latest => (2, 0),
+        };
+        self.release()
+            .cmp(&other.release())
+            .then_with(|| build(&self.build).cmp(&build(&other.build)))
+    }
+}
+
+impl PartialOrd for VersionNumber {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Whether a concrete `major.minor.patch.build` version satisfies a version number, where a
+/// missing or `LATEST` segment matches anything.
+pub(crate) fn version_matches(version_number: &str, version: &str) -> bool {
+    let wanted = version_number.split('.');
+    let mut actual = version.split('.');
+    for segment in wanted {
+        match actual.next() {
+            Some(_) if segment == "LATEST" => return true,
+            Some(a) if a == segment => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Sort key for a concrete version as reported by the Dev Hub, tolerating short versions.
+pub(crate) fn version_key(version: &str) -> Vec<u32> {
+    version.split('.').map(|n| n.parse().unwrap_or(0)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(version: &str) -> VersionNumber {
+        version.parse().unwrap()
+    }
+
+    #[test]
+    fn it_parses_version_numbers() {
+        assert_eq!(
+            v("1.2.3.NEXT"),
+            VersionNumber {
+                major: 1,
+                minor: 2,
+                patch: 3,
+                build: BuildNumber::Next
+            }
+        );
+        assert_eq!(v("1.2.3-4").build, BuildNumber::Number(4));
+        assert_eq!(v("1.2.3.LATEST").to_string(), "1.2.3.LATEST");
+        assert!("1.2.NEXT".parse::<VersionNumber>().is_err());
+        assert!("1.x.0.NEXT".parse::<VersionNumber>().is_err());
+    }
+
+    #[test]
+    fn it_orders_version_numbers() {
+        assert!(v("1.10.0.1") > v("1.9.0.7"));
+        assert!(v("1.0.0.NEXT") > v("1.0.0.12"));
+        assert_eq!(
+            [v("0.9.0.3"), v("1.0.0.2"), v("1.0.0.1")].iter().max(),
+            Some(&v("1.0.0.2"))
+        );
+    }
+
+    #[test]
+    fn it_keeps_unreleased_version_numbers() {
+        let next = v("1.1.0.NEXT")
+            .next(VersionBump::Minor, &[v("1.1.0.1")], &[v("1.0.0.3")])
+            .unwrap();
+        assert_eq!(next, v("1.1.0.NEXT"));
+    }
+
+    #[test]
+    fn it_bumps_released_version_numbers() {
+        let released = [v("1.0.0.1"), v("1.1.0.4")];
+        let current = v("1.1.0.NEXT");
+        assert_eq!(
+            current.next(VersionBump::Minor, &[], &released).unwrap(),
+            v("1.2.0.NEXT")
+        );
+        assert_eq!(
+            current.next(VersionBump::Major, &[], &released).unwrap(),
+            v("2.0.0.NEXT")
+        );
+        assert_eq!(
+            current.next(VersionBump::Patch, &[], &released).unwrap(),
+            v("1.1.1.NEXT")
+        );
+        assert!(current.next(VersionBump::None, &[], &released).is_err());
+    }
+
+    #[test]
+    fn it_refuses_versions_that_cannot_be_built() {
+        assert!(v("1.0.0.LATEST")
+            .next(VersionBump::Minor, &[], &[])
+            .is_err());
+        assert!(v("1.0.0.2")
+            .next(VersionBump::None, &[v("1.0.0.2")], &[])
+            .is_err());
+    }
+
+    #[test]
+    fn it_matches_dependency_versions() {
+        assert!(version_matches("2.1.0.LATEST", "2.1.0.3"));
+        assert!(version_matches("2.1", "2.1.0.3"));
+        assert!(!version_matches("2.1.0.LATEST", "2.0.0.9"));
+        assert!(version_key("1.10.0.1") > version_key("1.9.0.7"));
+    }
+}
@@ -0,0 +1,218 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+
+use crate::util::changes::tests_affected_by;
+use crate::util::config::{
+    read_package_directories, DevConfig, SFPackageAppConfig, TestLevel, TestSelection,
+};
+use crate::util::logger::{BuildLogger, Logger};
+use crate::util::report::TestReport;
+use crate::{push_source, test_apex_with_retries, OrgStatus, SfdxClient};
+
+/// # Dev Mode Watch
+/// Push source to the Dev mode scratch org whenever it changes in the project's package
+/// directories, until interrupted.  Changes arriving within `debounce` of each other are pushed
+/// together, and files excluded by `.forceignore` are not watched.  With `run_tests`, the Apex
+/// tests affected by each change are run after it is pushed.  A failed push or test run is
+/// reported and watching carries on.  The org must already exist, created by a Dev build.
+pub fn dev_watch<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    app_dir: &Path,
+    run_tests: bool,
+    debounce: Duration,
+) -> Result<(), anyhow::Error> {
+    let config = SFPackageAppConfig::from_dir(&app_dir.to_path_buf()).dev;
+    logger.header("---> Dev Watch")?;
+    if !matches!(client.check_org(&config.org_alias), Some(OrgStatus::Active)) {
+        return Err(anyhow!(
+            "scratch org {} is not active, run a Dev build to create it",
+            config.org_alias
+        ));
+    }
+
+    let force_ignore = ForceIgnore::new(app_dir);
+    let (tx, rx) = channel();
+    let mut watcher = watcher(tx, debounce)?;
+    for dir in read_package_directories(&app_dir.to_path_buf(), true, false)? {
+        watcher.watch(app_dir.join(&dir), RecursiveMode::Recursive)?;
+        logger.info(format!("---> watching {}", dir.to_string_lossy()))?;
+    }
+
+    loop {
+        let mut events = vec![rx.recv()?];
+        // Changes still arriving, such as a branch being checked out, join the same push.
+        loop {
+            match rx.recv_timeout(debounce) {
+                Ok(event) => events.push(event),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return Err(anyhow!("file watcher stopped")),
+            }
+        }
+
+        let mut changed = vec![];
+        for event in events {
+            match event {
+                DebouncedEvent::Create(path)
+                | DebouncedEvent::Write(path)
+                | DebouncedEvent::Remove(path) => changed.push(path),
+                DebouncedEvent::Rename(from, to) => changed.extend([from, to]),
+                DebouncedEvent::Error(e, _) => logger.warning("---> Watching source", e)?,
+                _ => {}
+            }
+        }
+        let changed = force_ignore.changed_sources(app_dir, changed);
+        if changed.is_empty() {
+            continue;
+        }
+        if let Err(e) = push_changes(client, logger, app_dir, &config, run_tests, &changed) {
+            logger.warning("---> Pushing changes", e)?;
+        }
+    }
+}
+
+/// Push the `changed` files, relative to the app, then run the Apex tests they affect when
+/// `run_tests`.
+pub(crate) fn push_changes<C: SfdxClient>(
+    client: &C,
+    logger: &mut BuildLogger,
+    app_dir: &Path,
+    config: &DevConfig,
+    run_tests: bool,
+    changed: &[PathBuf],
+) -> Result<(), anyhow::Error> {
+    for path in changed.iter() {
+        logger.info(format!("---> changed {}", path.to_string_lossy()))?;
+    }
+    let output = push_source(client, logger, &config.org_alias, config.op_wait_seconds)?;
+    logger.output("---> Pushing changes", output)?;
+
+    if run_tests {
+        let classes = tests_affected_by(app_dir, changed);
+        if classes.is_empty() {
+            return logger.info("---> no apex tests affected by changes");
+        }
+        let tests = TestSelection {
+            level: Some(TestLevel::RunSpecifiedTests),
+            classes,
+            ..Default::default()
+        };
+        logger.info("---> running apex tests")?;
+        let result = test_apex_with_retries(
+            client,
+            logger,
+            &config.org_alias,
+            &tests,
+            config.op_wait_seconds,
+            &config.flaky,
+        )?;
+        logger.info(TestReport::new(&result).human())?;
+        result.check(&config.org_alias)?;
+    }
+    Ok(())
+}
+
+/// The paths `.forceignore` keeps out of source push, in gitignore syntax.
+struct ForceIgnore(Gitignore);
+
+impl ForceIgnore {
+    fn new(app_dir: &Path) -> Self {
+        let mut builder = GitignoreBuilder::new(app_dir);
+        // A project without a .forceignore ignores nothing.
+        builder.add(app_dir.join(".forceignore"));
+        ForceIgnore(builder.build().unwrap_or_else(|_| Gitignore::empty()))
+    }
+
+    /// The changed paths, relative to the app, that are not ignored, each once.
+    fn changed_sources(&self, app_dir: &Path, paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        // Watchers may report canonical paths, for example through a symlinked temp dir.
+        let canonical_dir = app_dir
+            .canonicalize()
+            .unwrap_or_else(|_| app_dir.to_path_buf());
+        let mut changed: Vec<PathBuf> = paths
+            .iter()
+            .filter_map(|path| {
+                path.strip_prefix(app_dir)
+                    .or_else(|_| path.strip_prefix(&canonical_dir))
+                    .ok()
+            })
+            .filter(|path| {
+                !self
+                    .0
+                    .matched_path_or_any_parents(path, app_dir.join(path).is_dir())
+                    .is_ignore()
+            })
+            .map(Path::to_path_buf)
+            .collect();
+        changed.sort();
+        changed.dedup();
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ScriptedSfdxClient;
+
+    fn app_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sf-package")
+    }
+
+    #[test]
+    fn it_honors_forceignore() {
+        let app_dir = app_dir();
+        let classes = app_dir.join("force-app/main/default/classes");
+        let changed = ForceIgnore::new(&app_dir).changed_sources(
+            &app_dir,
+            vec![
+                classes.join("Test.cls"),
+                classes.join("Test.cls"),
+                app_dir.join("force-app/main/default/lwc/greeter/__tests__/greeter.test.js"),
+                app_dir.join("force-app/main/default/lwc/jsconfig.json"),
+                PathBuf::from("/elsewhere/Test.cls"),
+            ],
+        );
+        assert_eq!(
+            changed,
+            vec![PathBuf::from("force-app/main/default/classes/Test.cls")]
+        );
+    }
+
+    #[test]
+    fn it_pushes_changes_and_runs_affected_tests() {
+        let app_dir = app_dir();
+        let config = SFPackageAppConfig::from_dir(&app_dir).dev;
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file(
+                "force:apex:test:run",
+                app_dir.join("../sfdx/apex_test_run.json"),
+            )
+            .unwrap();
+
+        push_changes(
+            &client,
+            &mut BuildLogger::new(false, false),
+            &app_dir,
+            &config,
+            true,
+            &[PathBuf::from("force-app/main/default/classes/Test.cls")],
+        )
+        .unwrap();
+
+        assert_eq!(
+            client.commands(),
+            vec!["force:source:push", "force:apex:test:run"]
+        );
+        assert_eq!(
+            client.calls()[1].args,
+            vec!["dev", "-l", "RunSpecifiedTests", "-n", "TestTests"]
+        );
+    }
+}
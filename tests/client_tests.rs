@@ -0,0 +1,562 @@
+mod support;
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::support::{recorded, write_app_meta, TestSetup};
+    use libcnb::TestOutcome;
+    use sf_package_buildpack::{
+        ci_build, ci_test, dev_build, find_buildpack_error, package_build, package_publish,
+        package_test, BuildLogger, BuildpackError, ScriptedSfdxClient,
+    };
+
+    #[test]
+    fn test_dev_build_reuses_active_org() {
+        let setup = TestSetup::new();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:org:display", recorded("org_display_active.json"))
+            .unwrap();
+
+        dev_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        assert_eq!(
+            client.commands(),
+            vec!["auth", "force:org:display", "force:source:push"]
+        );
+    }
+
+    #[test]
+    fn test_ci_build() {
+        let setup = TestSetup::new();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        assert_eq!(
+            client.commands(),
+            vec![
+                "auth",
+                "force:org:create",
+                "force:source:push",
+                "force:apex:test:run",
+                "force:org:delete"
+            ]
+        );
+        let delete = client.calls().pop().unwrap();
+        assert_eq!(delete.args, vec!["mhoefer@mphhub.org", "ci"]);
+    }
+
+    #[test]
+    fn test_ci_build_checks_out_pooled_org() {
+        let setup = TestSetup::new();
+        let app_toml = setup.app_dir.join("app.toml");
+        let config = fs::read_to_string(&app_toml).unwrap() + "\n[ci.pool]\nsize = 1\n";
+        fs::write(&app_toml, config).unwrap();
+        let pool_file = setup.layers_dir.join("org-pool/pool.toml");
+        fs::create_dir_all(pool_file.parent().unwrap()).unwrap();
+        fs::write(
+            &pool_file,
+            r#"[[orgs]]
+alias = "ci-pool-1"
+username = "test-ahmet6briymu@example.com"
+tag = "ci"
+org_def_path = "configgy/project-scratch-def.json"
+created = "2022-01-06T14:00:00+00:00"
+expires = "2999-01-13T14:00:00+00:00"
+"#,
+        )
+        .unwrap();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:org:display", recorded("org_display_active.json"))
+            .unwrap()
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        assert_eq!(
+            client.commands(),
+            vec![
+                "auth",
+                "force:org:display",
+                "force:source:push",
+                "force:apex:test:run",
+                "force:org:delete"
+            ]
+        );
+        let delete = client.calls().pop().unwrap();
+        assert_eq!(
+            delete.args,
+            vec!["mhoefer@mphhub.org", "test-ahmet6briymu@example.com"]
+        );
+        assert!(!fs::read_to_string(&pool_file)
+            .unwrap()
+            .contains("ci-pool-1"));
+    }
+
+    #[test]
+    fn test_ci_build_runs_setup_steps() {
+        let setup = TestSetup::new();
+        let app_toml = setup.app_dir.join("app.toml");
+        let config = fs::read_to_string(&app_toml).unwrap()
+            + r#"
+[[ci.setup]]
+data_plan = "data/plan.json"
+on_failure = "continue"
+
+[[ci.setup]]
+permset = "Admin"
+"#;
+        fs::write(&app_toml, config).unwrap();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:data:tree:import", recorded("error.json"))
+            .unwrap()
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        assert_eq!(
+            client.commands(),
+            vec![
+                "auth",
+                "force:org:create",
+                "force:source:push",
+                "force:data:tree:import",
+                "force:user:permset:assign",
+                "force:apex:test:run",
+                "force:org:delete"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ci_build_writes_coverage_reports() {
+        let setup = TestSetup::new();
+        let app_dir = setup.app_dir.clone();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        let lcov = fs::read_to_string(app_dir.join("results/apex/lcov.info")).unwrap();
+        assert!(lcov.contains("SF:force-app/main/default/classes/Test.cls\n"));
+        assert!(lcov.contains("LH:3\n"));
+        let cobertura =
+            fs::read_to_string(app_dir.join("results/apex/coverage-cobertura.xml")).unwrap();
+        assert!(cobertura.contains(r#"lines-covered="3" lines-valid="4""#));
+    }
+
+    #[test]
+    fn test_ci_build_fails_below_min_class_coverage() {
+        let setup = TestSetup::new();
+        let app_toml = setup.app_dir.join("app.toml");
+        let config = fs::read_to_string(&app_toml).unwrap().replace(
+            "[ci]\n",
+            "[ci]\nmin_coverage = 70\nmin_class_coverage = 80\n",
+        );
+        fs::write(&app_toml, config).unwrap();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        let error = ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .unwrap_err();
+
+        match find_buildpack_error(&error) {
+            Some(BuildpackError::InsufficientCoverage { org, shortfalls }) => {
+                assert_eq!(org, "ci");
+                assert_eq!(
+                    shortfalls,
+                    &vec!["Test covers 75.0% of lines, below 80%".to_string()]
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(client.commands().last().unwrap(), "force:org:delete");
+    }
+
+    #[test]
+    fn test_ci_build_runs_specified_tests() {
+        let setup = TestSetup::new();
+        let app_toml = setup.app_dir.join("app.toml");
+        let mut config = fs::read_to_string(&app_toml).unwrap();
+        config.push_str("\n[ci.tests]\nclasses = [\"TestTests\"]\nmethods = [\"SomeCallableTest.testCallable\"]\n");
+        fs::write(&app_toml, config).unwrap();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        let test_run = client
+            .calls()
+            .into_iter()
+            .find(|call| call.command == "force:apex:test:run")
+            .unwrap();
+        assert_eq!(
+            test_run.args,
+            vec![
+                "ci",
+                "-l",
+                "RunSpecifiedTests",
+                "-t",
+                "TestTests,SomeCallableTest.testCallable"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ci_build_deletes_org_on_failure() {
+        let setup = TestSetup::new();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:source:push", recorded("error.json"))
+            .unwrap();
+
+        let result = ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            client.commands(),
+            vec![
+                "auth",
+                "force:org:create",
+                "force:source:push",
+                "force:org:delete"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ci_build_reports_push_conflicts() {
+        let setup = TestSetup::new();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:source:push", recorded("source_push_conflict.json"))
+            .unwrap();
+
+        let error = ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .unwrap_err();
+
+        match find_buildpack_error(&error) {
+            Some(BuildpackError::PushConflict { org, conflicts }) => {
+                assert_eq!(org, "ci");
+                assert_eq!(conflicts[0].full_name, "TestTests");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(client.commands().last().unwrap(), "force:org:delete");
+    }
+
+    #[test]
+    fn test_ci_build_fails_on_test_failures() {
+        let setup = TestSetup::new();
+        let app_dir = setup.app_dir.clone();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run_failed.json"))
+            .unwrap();
+
+        let error = ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .unwrap_err();
+
+        match find_buildpack_error(&error) {
+            Some(BuildpackError::TestFailures { org, failures }) => {
+                assert_eq!(org, "ci");
+                assert_eq!(
+                    failures,
+                    &vec!["TestTests.testGreeting: System.AssertException: Assertion Failed: Expected: Hello, Actual: Hi".to_string()]
+                );
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(client.commands().last().unwrap(), "force:org:delete");
+
+        let report =
+            std::fs::read_to_string(app_dir.join("results/apex/test-result-junit.xml")).unwrap();
+        assert!(report.contains(r#"<testcase name="testGreeting" classname="TestTests""#));
+        assert!(report.contains(
+            r#"<failure message="System.AssertException: Assertion Failed: Expected: Hello, Actual: Hi">"#
+        ));
+    }
+
+    fn configure_ci(setup: &TestSetup, settings: &str) {
+        let app_toml = setup.app_dir.join("app.toml");
+        let config = fs::read_to_string(&app_toml)
+            .unwrap()
+            .replace("[ci]\n", &format!("[ci]\n{}\n", settings));
+        fs::write(&app_toml, config).unwrap();
+    }
+
+    #[test]
+    fn test_ci_test_reruns_failed_tests() {
+        let setup = TestSetup::new();
+        configure_ci(&setup, "test_retries = 2");
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run_failed.json"))
+            .unwrap()
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run_rerun.json"))
+            .unwrap();
+
+        let outcome = ci_test(
+            setup.test_context(),
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Test failed");
+
+        match outcome {
+            TestOutcome::Pass(results) => assert_eq!(results.passed.len(), 2),
+            TestOutcome::Fail(_) => panic!("the rerun passed"),
+        }
+        let reruns: Vec<Vec<String>> = client
+            .calls()
+            .into_iter()
+            .filter(|call| call.command == "force:apex:test:run")
+            .map(|call| call.args)
+            .collect();
+        assert_eq!(
+            reruns[1],
+            vec![
+                "ci",
+                "-l",
+                "RunSpecifiedTests",
+                "-t",
+                "TestTests.testGreeting"
+            ]
+        );
+        assert_eq!(reruns.len(), 2);
+    }
+
+    #[test]
+    fn test_ci_test_ignores_quarantined_tests() {
+        let setup = TestSetup::new();
+        configure_ci(
+            &setup,
+            "test_retries = 1\nquarantined_tests = [\"TestTests.testGreeting\"]",
+        );
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run_failed.json"))
+            .unwrap();
+
+        let outcome = ci_test(
+            setup.test_context(),
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Test failed");
+
+        match outcome {
+            TestOutcome::Pass(results) => {
+                assert_eq!(results.passed.len(), 1);
+                assert_eq!(results.ignored.len(), 1);
+            }
+            TestOutcome::Fail(_) => panic!("quarantined failures should not fail the run"),
+        }
+        assert_eq!(
+            client
+                .commands()
+                .iter()
+                .filter(|c| *c == "force:apex:test:run")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_package_build() {
+        let setup = TestSetup::new();
+        let app_dir = setup.app_dir.clone();
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file(
+                "force:package:version:create",
+                recorded("package_version_create.json"),
+            )
+            .unwrap()
+            .respond_with_file(
+                "force:package:version:report",
+                recorded("package_version_report.json"),
+            )
+            .unwrap();
+
+        package_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Package build failed");
+
+        assert_eq!(
+            client.commands(),
+            vec![
+                "auth",
+                "force:package:version:create",
+                "force:package:version:report"
+            ]
+        );
+        let create = &client.calls()[1];
+        assert_eq!(create.args[1], "0Ho3t000000XZNrCAO");
+        assert_eq!(create.args[4], "1.0.0.NEXT");
+
+        let app_meta = fs::read_to_string(app_dir.join("app-meta.toml")).unwrap();
+        assert!(app_meta.contains("04t3t000002zQrEAAU"));
+        assert!(app_meta.contains("1.0.0.6"));
+    }
+
+    #[test]
+    fn test_package_test() {
+        let setup = TestSetup::new();
+        write_app_meta(&setup.app_dir, &[("04t3t000002zQrEAAU", "1.0.0.6", "Beta")]);
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        let outcome = package_test(
+            setup.test_context(),
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Package test failed");
+
+        assert!(matches!(outcome, TestOutcome::Pass(_)));
+        assert_eq!(
+            client.commands(),
+            vec![
+                "force:org:create",
+                "force:package:install",
+                "force:apex:test:run",
+                "force:org:delete"
+            ]
+        );
+        let create = &client.calls()[0];
+        assert!(create.args.contains(&String::from("-n")));
+        let install = &client.calls()[1];
+        assert_eq!(install.args[1], "04t3t000002zQrEAAU");
+    }
+
+    #[test]
+    fn test_package_test_upgrade_installs_released_version_first() {
+        let setup = TestSetup::new();
+        let app_toml = setup.app_dir.join("app.toml");
+        let config = fs::read_to_string(&app_toml)
+            .unwrap()
+            .replace("[package]\n", "[package]\ntest_mode = \"upgrade\"\n");
+        fs::write(&app_toml, config).unwrap();
+        write_app_meta(
+            &setup.app_dir,
+            &[
+                ("04t3t000002zQr9AAE", "1.0.0.5", "Published"),
+                ("04t3t000002zQrEAAU", "1.0.0.6", "Beta"),
+            ],
+        );
+        let client = ScriptedSfdxClient::new();
+        client
+            .respond_with_file("force:apex:test:run", recorded("apex_test_run.json"))
+            .unwrap();
+
+        package_test(
+            setup.test_context(),
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Package test failed");
+
+        let installs: Vec<String> = client
+            .calls()
+            .into_iter()
+            .filter(|call| call.command == "force:package:install")
+            .map(|call| call.args[1].clone())
+            .collect();
+        assert_eq!(installs, vec!["04t3t000002zQr9AAE", "04t3t000002zQrEAAU"]);
+    }
+
+    #[test]
+    fn test_package_publish_refuses_version_failing_coverage() {
+        let setup = TestSetup::new();
+        write_app_meta(&setup.app_dir, &[("04t3t000002zQrEAAU", "1.0.0.6", "Beta")]);
+        let report = fs::read_to_string(recorded("package_version_report.json"))
+            .unwrap()
+            .replace(
+                "\"HasPassedCodeCoverageCheck\": true",
+                "\"HasPassedCodeCoverageCheck\": false",
+            );
+        let client = ScriptedSfdxClient::new();
+        client.respond("force:package:version:report", report);
+
+        let result = package_publish(
+            setup.publish_context(),
+            &client,
+            &mut BuildLogger::new(true, true),
+        );
+
+        assert!(result.is_err());
+        assert_eq!(
+            client.commands(),
+            vec!["auth", "force:package:version:report"]
+        );
+        let app_meta = fs::read_to_string(setup.app_dir.join("app-meta.toml")).unwrap();
+        assert!(app_meta.contains("status = \"Beta\""));
+    }
+}
@@ -0,0 +1,156 @@
+mod support;
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    use crate::support::{recorded, TestSetup};
+    use lazy_static::lazy_static;
+    use sf_package_buildpack::{ci_build, package_build, BuildLogger, ProcessSfdxClient};
+
+    lazy_static! {
+        // Every build puts its own fake sfdx at the front of the shared PATH.
+        static ref PATH_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    /// Installs the fake sfdx into the sfdx layer of a build.
+    struct FakeSfdx {
+        state_dir: PathBuf,
+    }
+
+    impl FakeSfdx {
+        fn install(setup: &TestSetup) -> Self {
+            let sfdx_dir = setup.layers_dir.join("sfdx");
+            let bin_dir = sfdx_dir.join("bin");
+            let state_dir = sfdx_dir.join("fake-sfdx");
+            fs::create_dir_all(&bin_dir).unwrap();
+            fs::create_dir_all(state_dir.join("responses")).unwrap();
+            fs::copy(env!("CARGO_BIN_EXE_fake-sfdx"), bin_dir.join("sfdx")).unwrap();
+
+            let fake = FakeSfdx { state_dir };
+            fake.respond("force:org:display", "org_display_connected.json");
+            fake
+        }
+
+        fn respond(&self, command: &str, name: &str) {
+            fs::copy(
+                recorded(name),
+                self.state_dir
+                    .join("responses")
+                    .join(format!("{}.json", command)),
+            )
+            .unwrap();
+        }
+
+        /// The arguments of every call, leaving out the org lookups made while authenticating.
+        fn calls(&self) -> Vec<Vec<String>> {
+            fs::read_to_string(self.state_dir.join("calls.log"))
+                .unwrap_or_default()
+                .lines()
+                .map(|line| serde_json::from_str::<Vec<String>>(line).unwrap())
+                .filter(|args| args[0] != "force:org:display")
+                .collect()
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.calls()
+                .into_iter()
+                .map(|args| args[0].clone())
+                .collect()
+        }
+    }
+
+    fn client(setup: &TestSetup) -> ProcessSfdxClient {
+        ProcessSfdxClient::new(
+            &setup.layers_dir,
+            &setup.app_dir,
+            &BuildLogger::new(true, true),
+        )
+    }
+
+    #[test]
+    fn test_ci_build_lifecycle() {
+        let _lock = PATH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let setup = TestSetup::new();
+        let fake = FakeSfdx::install(&setup);
+        fake.respond("force:apex:test:run", "apex_test_run.json");
+
+        let client = client(&setup);
+        ci_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Build failed");
+
+        assert_eq!(
+            fake.commands(),
+            vec![
+                "force:org:create",
+                "force:source:push",
+                "force:apex:test:run",
+                "force:org:delete"
+            ]
+        );
+    }
+
+    fn package_version_create_args(app_toml: Option<(&str, &str)>) -> Vec<String> {
+        let _lock = PATH_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let setup = TestSetup::new();
+        if let Some((from, to)) = app_toml {
+            let file = setup.app_dir.join("app.toml");
+            let content = fs::read_to_string(&file).unwrap();
+            fs::write(&file, content.replace(from, to)).unwrap();
+        }
+        let fake = FakeSfdx::install(&setup);
+        fake.respond(
+            "force:package:version:create",
+            "package_version_create.json",
+        );
+        fake.respond(
+            "force:package:version:report",
+            "package_version_report.json",
+        );
+
+        let client = client(&setup);
+        package_build(
+            setup.build_context,
+            &client,
+            &mut BuildLogger::new(true, true),
+        )
+        .expect("Package build failed");
+
+        fake.calls()
+            .into_iter()
+            .find(|args| args[0] == "force:package:version:create")
+            .expect("no package version created")
+    }
+
+    #[test]
+    fn test_package_build_without_installation_key() {
+        let args = package_version_create_args(None);
+        assert!(args.contains(&"-x".to_string()));
+        assert!(!args.contains(&"-k".to_string()));
+        assert!(!args.contains(&"-c".to_string()));
+    }
+
+    #[test]
+    fn test_package_build_with_code_coverage() {
+        let args =
+            package_version_create_args(Some(("[package]\n", "[package]\ncode_coverage = true\n")));
+        assert!(args.contains(&"-c".to_string()));
+    }
+
+    #[test]
+    fn test_package_build_with_installation_key() {
+        let args = package_version_create_args(Some((
+            "[package]\n",
+            "[package]\ninstallation_key = \"s3cret\"\n",
+        )));
+        assert!(!args.contains(&"-x".to_string()));
+        let k = args.iter().position(|a| a == "-k").expect("no -k flag");
+        assert_eq!(args[k + 1], "s3cret");
+    }
+}
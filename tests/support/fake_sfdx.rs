@@ -0,0 +1,56 @@
+//! # Fake sfdx
+//! Poses as the `sfdx` CLI in end-to-end tests.  Installed as `<dir>/bin/sfdx`, it keeps its state
+//! in `<dir>/fake-sfdx`:
+//!
+//! - every invocation is appended to `calls.log`, one JSON array of arguments per line;
+//! - `responses/<command>.json`, e.g. `responses/force:org:create.json`, is printed as the answer
+//!   to the command, exiting with failure when its `status` is not 0;
+//! - commands without a response succeed with an empty result.
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::exit;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--version") {
+        println!("sfdx-cli/7.132.0 fake-sfdx");
+        return;
+    }
+
+    let state_dir = state_dir();
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(state_dir.join("calls.log"))
+        .expect("failed to open calls.log");
+    writeln!(log, "{}", serde_json::to_string(&args).unwrap()).expect("failed to log call");
+
+    let command = args.first().cloned().unwrap_or_default();
+    let response_file = state_dir
+        .join("responses")
+        .join(format!("{}.json", command));
+    match fs::read_to_string(&response_file) {
+        Ok(response) => {
+            println!("{}", response);
+            let value: serde_json::Value =
+                serde_json::from_str(&response).expect("invalid recorded response");
+            if value["status"].as_i64().unwrap_or(0) != 0 {
+                exit(1);
+            }
+        }
+        Err(_) => println!("{{\"status\": 0, \"result\": {{}}}}"),
+    }
+}
+
+fn state_dir() -> PathBuf {
+    let exe = env::current_exe().expect("failed to locate fake sfdx");
+    let dir = exe
+        .parent()
+        .and_then(|bin| bin.parent())
+        .expect("fake sfdx must be installed into a bin directory")
+        .join("fake-sfdx");
+    fs::create_dir_all(&dir).expect("failed to create fake sfdx state");
+    dir
+}
@@ -0,0 +1,122 @@
+// Each test binary uses only part of the shared support.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use libcnb::data::{buildpack_plan::BuildpackPlan, buildpack_plan::Entry};
+use libcnb::{BuildContext, GenericPlatform, Platform, PublishContext, TestContext};
+use sf_package_buildpack::SFPackageBuildpackConfig;
+use tempfile::{tempdir, TempDir};
+
+pub struct TestSetup {
+    // Hold reference to temp dirs so they're not cleaned off disk
+    _tmp_dir: TempDir,
+    pub app_dir: PathBuf,
+    pub layers_dir: PathBuf,
+    pub build_context: BuildContext<GenericPlatform, SFPackageBuildpackConfig>,
+}
+
+impl TestSetup {
+    /// A build of a copy of the sf-package fixture, so that builds may write to it.
+    pub fn new() -> Self {
+        let root_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let tmp_dir = tempdir().unwrap();
+        let app_dir = tmp_dir.path().join("app");
+        let layers_dir = tmp_dir.path().join("layers");
+        let bp_dir = tmp_dir.path().join("buildpack");
+        let platform_dir = tmp_dir.path().join("platform");
+        for path in [&layers_dir, &bp_dir, &platform_dir.join("env")].iter() {
+            fs::create_dir_all(path).unwrap();
+        }
+        copy_dir(&root_dir.join("tests/fixtures/sf-package"), &app_dir);
+
+        let build_context = BuildContext {
+            layers_dir: layers_dir.clone(),
+            app_dir: app_dir.clone(),
+            buildpack_dir: bp_dir,
+            stack_id: String::from("lol"),
+            platform: GenericPlatform::from_path(&platform_dir).unwrap(),
+            buildpack_plan: BuildpackPlan {
+                entries: Vec::<Entry>::new(),
+            },
+            buildpack_descriptor: toml::from_str(include_str!("../../buildpack.toml")).unwrap(),
+        };
+        TestSetup {
+            _tmp_dir: tmp_dir,
+            app_dir,
+            layers_dir,
+            build_context,
+        }
+    }
+
+    /// A test of the same app, for running after the build.
+    pub fn test_context(&self) -> TestContext<GenericPlatform, SFPackageBuildpackConfig> {
+        TestContext {
+            layers_dir: self.layers_dir.clone(),
+            app_dir: self.app_dir.clone(),
+            buildpack_dir: self.build_context.buildpack_dir.clone(),
+            stack_id: self.build_context.stack_id.clone(),
+            platform: GenericPlatform::from_path(self._tmp_dir.path().join("platform")).unwrap(),
+            buildpack_descriptor: toml::from_str(include_str!("../../buildpack.toml")).unwrap(),
+        }
+    }
+
+    /// A publish of the same app, for running after the build.
+    pub fn publish_context(&self) -> PublishContext<GenericPlatform, SFPackageBuildpackConfig> {
+        PublishContext {
+            app_dir: self.app_dir.clone(),
+            buildpack_dir: self.build_context.buildpack_dir.clone(),
+            stack_id: self.build_context.stack_id.clone(),
+            platform: GenericPlatform::from_path(self._tmp_dir.path().join("platform")).unwrap(),
+            buildpack_descriptor: toml::from_str(include_str!("../../buildpack.toml")).unwrap(),
+        }
+    }
+}
+
+/// Record versions of the fixture's package in app-meta.toml, as package builds would, each given
+/// as (subscriber package version id, version number, status).
+pub fn write_app_meta(app_dir: &Path, versions: &[(&str, &str, &str)]) {
+    let mut app_meta = String::from(
+        r#"[[packages]]
+id = "0Ho3t000000XZNrCAO"
+name = "sf-package-test"
+hub_user = "mhoefer@mphhub.org"
+hub_instance_url = "https://mphhub-dev-ed.my.salesforce.com"
+"#,
+    );
+    for (id, number, status) in versions {
+        app_meta.push_str(&format!(
+            r#"
+[[package_versions]]
+id = "{}"
+name = "Version One"
+number = "{}"
+package_id = "0Ho3t000000XZNrCAO"
+status = "{}"
+"#,
+            id, number, status
+        ));
+    }
+    fs::write(app_dir.join("app-meta.toml"), app_meta).unwrap();
+}
+
+pub fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let target = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &target);
+        } else {
+            fs::copy(entry.path(), target).unwrap();
+        }
+    }
+}
+
+/// A recorded sfdx `--json` response.
+pub fn recorded(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures/sfdx")
+        .join(name)
+}